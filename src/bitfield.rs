@@ -0,0 +1,273 @@
+//! Sets of sequence numbers held of a log
+
+use std::cmp;
+use std::fmt;
+use std::ops::Range;
+
+use crate::log::MAX_SEQUENCE_NUMBER;
+use crate::varint;
+
+#[derive(Debug, PartialEq)]
+pub enum BitfieldError {
+    Truncated,
+    TooLong,
+}
+
+impl fmt::Display for BitfieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitfieldError::Truncated => write!(f, "bitfield ends within a number"),
+            BitfieldError::TooLong => write!(f, "bitfield exceeds the maximum length"),
+        }
+    }
+}
+
+/// Set of sequence numbers (starting at 1) of the entries present in a log.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bitfield {
+    words: Vec<u64>,
+}
+
+impl Bitfield {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a bitfield holding all entries up to the given length.
+    pub fn with_length(length: u64) -> Self {
+        let mut bitfield = Self::new();
+        bitfield.set_range(1..length + 1, true);
+        bitfield
+    }
+
+    pub fn get(&self, sequence_number: u64) -> bool {
+        let (word, bit) = position(sequence_number);
+        self.words.get(word).is_some_and(|word| word & (1 << bit) != 0)
+    }
+
+    pub fn set(&mut self, sequence_number: u64, value: bool) {
+        let (word, bit) = position(sequence_number);
+
+        if value {
+            if word >= self.words.len() {
+                self.words.resize(word + 1, 0);
+            }
+
+            self.words[word] |= 1 << bit;
+        } else if let Some(word) = self.words.get_mut(word) {
+            *word &= !(1 << bit);
+            self.trim();
+        }
+    }
+
+    pub fn set_range(&mut self, range: Range<u64>, value: bool) {
+        let mut sequence_number = range.start;
+
+        while sequence_number < range.end {
+            let (word, bit) = position(sequence_number);
+
+            // Fill whole words at once
+            if bit == 0 && range.end - sequence_number >= 64 {
+                if value && word >= self.words.len() {
+                    self.words.resize(word + 1, 0);
+                }
+
+                if let Some(word) = self.words.get_mut(word) {
+                    *word = if value { u64::MAX } else { 0 };
+                }
+
+                sequence_number += 64;
+            } else {
+                self.set(sequence_number, value);
+                sequence_number += 1;
+            }
+        }
+
+        self.trim();
+    }
+
+    /// Adds all entries present in the other bitfield.
+    pub fn union(&mut self, other: &Bitfield) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    // Drops empty words at the end, equal sets compare equal then
+    fn trim(&mut self) {
+        while self.words.last() == Some(&0) {
+            self.words.pop();
+        }
+    }
+
+    /// Returns the number of present entries.
+    pub fn count(&self) -> u64 {
+        self.words.iter().map(|word| u64::from(word.count_ones())).sum()
+    }
+
+    /// Returns the highest present sequence number.
+    pub fn last(&self) -> Option<u64> {
+        self.words
+            .iter()
+            .rposition(|word| *word != 0)
+            .map(|index| index as u64 * 64 + 63 - u64::from(self.words[index].leading_zeros()))
+    }
+
+    /// Returns all runs of present entries in ascending order.
+    pub fn ranges(&self) -> Vec<Range<u64>> {
+        let last = match self.last() {
+            Some(last) => last,
+            None => return Vec::new(),
+        };
+
+        self.runs(1..last + 1, true)
+    }
+
+    /// Returns the runs of entries present within the given range.
+    pub fn present(&self, range: Range<u64>) -> Vec<Range<u64>> {
+        self.runs(range, true)
+    }
+
+    /// Returns the runs of entries missing within the given range.
+    pub fn missing(&self, range: Range<u64>) -> Vec<Range<u64>> {
+        self.runs(range, false)
+    }
+
+    fn runs(&self, range: Range<u64>, value: bool) -> Vec<Range<u64>> {
+        let mut runs = Vec::new();
+        let mut sequence_number = range.start;
+
+        while sequence_number < range.end {
+            let start = self.find(sequence_number..range.end, value);
+
+            if start == range.end {
+                break;
+            }
+
+            let end = self.find(start..range.end, !value);
+            runs.push(start..end);
+            sequence_number = end;
+        }
+
+        runs
+    }
+
+    // Returns the first sequence number within the range whose bit has the
+    // value, or the end of the range. Skips whole words at once
+    fn find(&self, range: Range<u64>, value: bool) -> u64 {
+        let mut sequence_number = range.start;
+
+        while sequence_number < range.end {
+            let (index, bit) = position(sequence_number);
+
+            let word = match self.words.get(index) {
+                Some(word) => *word,
+                // Nothing is present after the last word
+                None if value => break,
+                None => 0,
+            };
+
+            let matching = if value { word } else { !word } & (u64::MAX << bit);
+
+            if matching != 0 {
+                return cmp::min(index as u64 * 64 + u64::from(matching.trailing_zeros()), range.end);
+            }
+
+            sequence_number = (index as u64 + 1) * 64;
+        }
+
+        range.end
+    }
+
+    /// Encodes the bitfield as alternating lengths of missing and present
+    /// runs starting at sequence number 1, each as variable-length integer.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut position = 1;
+
+        for range in self.ranges() {
+            varint::write(&mut bytes, range.start - position);
+            varint::write(&mut bytes, range.end - range.start);
+            position = range.end;
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BitfieldError> {
+        let mut bitfield = Self::new();
+        let mut bytes = bytes;
+        let mut position: u64 = 1;
+
+        while !bytes.is_empty() {
+            let missing = varint::read(&mut bytes).ok_or(BitfieldError::Truncated)?;
+            let present = varint::read(&mut bytes).ok_or(BitfieldError::Truncated)?;
+
+            let start = position.checked_add(missing).ok_or(BitfieldError::TooLong)?;
+            let end = start.checked_add(present).ok_or(BitfieldError::TooLong)?;
+
+            if end > MAX_SEQUENCE_NUMBER {
+                return Err(BitfieldError::TooLong);
+            }
+
+            bitfield.set_range(start..end, true);
+            position = end;
+        }
+
+        Ok(bitfield)
+    }
+}
+
+fn position(sequence_number: u64) -> (usize, u64) {
+    ((sequence_number / 64) as usize, sequence_number % 64)
+}
+
+#[cfg(test)]
+mod bitfield {
+    use super::*;
+
+    #[test]
+    fn tracks_holes() {
+        let mut bitfield = Bitfield::with_length(3);
+        bitfield.set_range(100..103, true);
+        bitfield.set(2, false);
+
+        assert!(bitfield.get(1));
+        assert!(!bitfield.get(2));
+        assert!(!bitfield.get(5000));
+        assert_eq!(bitfield.count(), 5);
+        assert_eq!(bitfield.last(), Some(102));
+        assert_eq!(bitfield.ranges(), vec![1..2, 3..4, 100..103]);
+        assert_eq!(bitfield.missing(1..105), vec![2..3, 4..100, 103..105]);
+        assert_eq!(Bitfield::new().last(), None);
+
+        bitfield.union(&Bitfield::with_length(4));
+        assert_eq!(bitfield.ranges(), vec![1..5, 100..103]);
+
+        // Runs end within words and at the end of the range
+        assert_eq!(bitfield.missing(3..101), vec![5..100]);
+        assert_eq!(bitfield.missing(101..102), vec![]);
+        assert_eq!(Bitfield::new().missing(1..MAX_SEQUENCE_NUMBER), vec![1..MAX_SEQUENCE_NUMBER]);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut bitfield = Bitfield::with_length(1000);
+        bitfield.set_range(5000..5010, true);
+
+        let bytes = bitfield.to_bytes();
+
+        // Long runs compress to a few bytes
+        assert_eq!(bytes.len(), 6);
+        assert_eq!(Bitfield::from_bytes(&bytes), Ok(bitfield));
+
+        assert_eq!(Bitfield::from_bytes(&[0x80]), Err(BitfieldError::Truncated));
+        assert_eq!(
+            Bitfield::from_bytes(&[0, 0xff, 0xff, 0xff, 0x7f]),
+            Err(BitfieldError::TooLong));
+    }
+}
@@ -0,0 +1,424 @@
+//! Slash-commands typed into the prompt
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::export::{self, Archive};
+use crate::node::{self, Node};
+
+/// Prefix marking a line as command, doubling it sends the line as message.
+pub const COMMAND_PREFIX: char = '/';
+
+/// Results of a command the frontend needs to act on.
+#[derive(Debug, PartialEq)]
+pub enum Output {
+    /// Show a line of information to the user
+    Info(String),
+
+    /// Send a chat message
+    Message(String),
+
+    /// Remove all messages from the view
+    Clear,
+
+    /// Exit the program
+    Quit,
+}
+
+/// Everything a command handler can work with.
+pub struct Context<'a> {
+    pub node: &'a Node,
+    pub commands: &'a Commands,
+
+    /// Arguments as typed by the user, for commands taking free text
+    pub raw_args: &'a str,
+
+    output: Vec<Output>,
+}
+
+impl<'a> Context<'a> {
+    pub fn info<S: Into<String>>(&mut self, text: S) {
+        self.output.push(Output::Info(text.into()));
+    }
+
+    pub fn output(&mut self, output: Output) {
+        self.output.push(output);
+    }
+}
+
+pub type Handler = fn(&mut Context, &[&str]) -> Result<(), String>;
+
+/// Definition of a command which can be registered.
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    pub handler: Handler,
+}
+
+/// Registry of all known commands.
+#[derive(Default)]
+pub struct Commands {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl Commands {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns registry containing all built-in commands.
+    pub fn with_defaults() -> Self {
+        let mut commands = Self::new();
+
+        for command in builtin_commands() {
+            commands.register(command);
+        }
+
+        commands
+    }
+
+    /// Adds a command, replacing an earlier one with the same name.
+    pub fn register(&mut self, command: Command) {
+        self.commands.insert(command.name, command);
+    }
+
+    /// Returns the text to send when the line is no command.
+    pub fn as_message(line: &str) -> Option<&str> {
+        let mut chars = line.chars();
+
+        match (chars.next(), chars.next()) {
+            (Some(COMMAND_PREFIX), Some(COMMAND_PREFIX)) => Some(&line[COMMAND_PREFIX.len_utf8()..]),
+            (Some(COMMAND_PREFIX), _) => None,
+            _ => Some(line),
+        }
+    }
+
+    /// Parses and runs the command, errors are returned as info lines.
+    pub fn execute(&self, node: &Node, line: &str) -> Vec<Output> {
+        let line = line.strip_prefix(COMMAND_PREFIX).unwrap_or(line);
+
+        let (name, raw_args) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+
+        let command = match self.commands.get(name) {
+            Some(command) => command,
+            None => {
+                return vec![Output::Info(format!(
+                    "Unknown command {}{}, see {}help", COMMAND_PREFIX, name, COMMAND_PREFIX))];
+            }
+        };
+
+        let args: Vec<&str> = raw_args.split_whitespace().collect();
+
+        if args.len() < command.min_args || args.len() > command.max_args {
+            return vec![Output::Info(format!("Usage: {}", usage(command)))];
+        }
+
+        let mut context = Context {
+            node,
+            commands: self,
+            raw_args,
+            output: Vec::new(),
+        };
+
+        if let Err(err) = (command.handler)(&mut context, &args) {
+            context.info(format!("{}{}: {}", COMMAND_PREFIX, command.name, err));
+        }
+
+        context.output
+    }
+
+    fn help(&self, name: Option<&str>) -> Result<Vec<String>, String> {
+        match name {
+            Some(name) => {
+                let name = name.trim_start_matches(COMMAND_PREFIX);
+
+                self.commands
+                    .get(name)
+                    .map(|command| vec![
+                        format!("{} - {}", usage(command), command.description)])
+                    .ok_or_else(|| format!("Unknown command {}{}", COMMAND_PREFIX, name))
+            }
+            None => Ok(self.commands
+                .values()
+                .map(|command| format!("{} - {}", usage(command), command.description))
+                .collect()),
+        }
+    }
+}
+
+fn usage(command: &Command) -> String {
+    if command.usage.is_empty() {
+        format!("{}{}", COMMAND_PREFIX, command.name)
+    } else {
+        format!("{}{} {}", COMMAND_PREFIX, command.name, command.usage)
+    }
+}
+
+fn builtin_commands() -> Vec<Command> {
+    vec![
+        Command {
+            name: "channel",
+            usage: "[chat://<key>]",
+            description: "show the current channel or join another one",
+            min_args: 0,
+            max_args: 1,
+            handler: channel,
+        },
+        Command {
+            name: "checkpoint",
+            usage: "",
+            description: "sign a checkpoint of your log and forget older entries",
+            min_args: 0,
+            max_args: 0,
+            handler: checkpoint,
+        },
+        Command {
+            name: "clear",
+            usage: "",
+            description: "remove all messages from the view",
+            min_args: 0,
+            max_args: 0,
+            handler: |context, _| {
+                context.output(Output::Clear);
+                Ok(())
+            },
+        },
+        Command {
+            name: "export",
+            usage: "<path>",
+            description: "save all logs of the channel to an archive",
+            min_args: 1,
+            max_args: 1,
+            handler: export,
+        },
+        Command {
+            name: "help",
+            usage: "[command]",
+            description: "list all commands or describe one",
+            min_args: 0,
+            max_args: 1,
+            handler: help,
+        },
+        Command {
+            name: "import",
+            usage: "<path>",
+            description: "add the logs of an archive and join its channel",
+            min_args: 1,
+            max_args: 1,
+            handler: import,
+        },
+        Command {
+            name: "me",
+            usage: "<action>",
+            description: "send an action, like \"/me waves\"",
+            min_args: 1,
+            max_args: usize::MAX,
+            handler: |context, _| {
+                let text = format!("{}me {}", COMMAND_PREFIX, context.raw_args);
+                context.output(Output::Message(text));
+                Ok(())
+            },
+        },
+        Command {
+            name: "nick",
+            usage: "[name]",
+            description: "show or change the nickname others see",
+            min_args: 0,
+            max_args: 1,
+            handler: nick,
+        },
+        Command {
+            name: "peers",
+            usage: "",
+            description: "list all discovered peers",
+            min_args: 0,
+            max_args: 0,
+            handler: peers,
+        },
+        Command {
+            name: "quit",
+            usage: "",
+            description: "exit the chat",
+            min_args: 0,
+            max_args: 0,
+            handler: |context, _| {
+                context.output(Output::Quit);
+                Ok(())
+            },
+        },
+        Command {
+            name: "transcript",
+            usage: "<path>",
+            description: "save all messages as text, Markdown (.md) or JSON (.json)",
+            min_args: 1,
+            max_args: 1,
+            handler: transcript,
+        },
+    ]
+}
+
+fn channel(context: &mut Context, args: &[&str]) -> Result<(), String> {
+    match args.first() {
+        Some(url) => {
+            let channel_key = node::parse_channel_url(url)?;
+            context.node.join(&channel_key);
+        }
+        None => {
+            let url = node::channel_url(&context.node.channel_key());
+            context.info(format!("Channel: {}", url));
+        }
+    }
+
+    Ok(())
+}
+
+fn checkpoint(context: &mut Context, _: &[&str]) -> Result<(), String> {
+    let (sequence_number, dropped) = context.node.checkpoint();
+    context.info(format!("Signed checkpoint {}, forgot {} older entries", sequence_number, dropped));
+
+    Ok(())
+}
+
+fn export(context: &mut Context, args: &[&str]) -> Result<(), String> {
+    let archive = context.node.export();
+    let count: u64 = archive.logs.iter().map(|log| log.count()).sum();
+
+    fs::write(args[0], archive.to_bytes()).map_err(|err| err.to_string())?;
+    context.info(format!("Saved {} entries of {} logs to {}", count, archive.logs.len(), args[0]));
+
+    Ok(())
+}
+
+fn import(context: &mut Context, args: &[&str]) -> Result<(), String> {
+    let bytes = fs::read(args[0]).map_err(|err| err.to_string())?;
+    let archive = Archive::from_bytes(&bytes).map_err(|err| err.to_string())?;
+
+    let count = context.node.import(archive);
+    context.info(format!("Imported {} new entries from {}", count, args[0]));
+
+    Ok(())
+}
+
+fn transcript(context: &mut Context, args: &[&str]) -> Result<(), String> {
+    let messages = context.node.messages();
+    let format = export::Format::from_path(args[0]);
+    let text = export::transcript(&context.node.channel_key(), &messages, format);
+
+    fs::write(args[0], text).map_err(|err| err.to_string())?;
+    context.info(format!("Saved {} messages to {}", messages.len(), args[0]));
+
+    Ok(())
+}
+
+fn help(context: &mut Context, args: &[&str]) -> Result<(), String> {
+    let lines = context.commands.help(args.first().cloned())?;
+
+    for line in lines {
+        context.info(line);
+    }
+
+    Ok(())
+}
+
+fn nick(context: &mut Context, args: &[&str]) -> Result<(), String> {
+    match args.first() {
+        Some(name) => context.node.set_nickname(name)?,
+        None => {
+            match context.node.nickname() {
+                Some(name) => context.info(format!("You are known as {}", name)),
+                None => context.info("You have no nickname yet"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn peers(context: &mut Context, _: &[&str]) -> Result<(), String> {
+    let peers = context.node.peers();
+
+    if peers.is_empty() {
+        context.info("No peers found yet");
+    }
+
+    for peer in peers {
+        context.info(format!("Peer: {}, {}, {}", peer.addr(), peer.port(), peer.token()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod command {
+    use super::*;
+
+    use tokio_core::reactor::Core;
+
+    use crate::shutdown::Shutdown;
+
+    fn execute(commands: &Commands, line: &str) -> Vec<Output> {
+        let core = Core::new().unwrap();
+        let node = Node::new(core.handle(), Shutdown::new());
+
+        commands.execute(&node, line)
+    }
+
+    #[test]
+    fn detects_commands() {
+        assert_eq!(Commands::as_message("Hello"), Some("Hello"));
+        assert_eq!(Commands::as_message("/help"), None);
+        assert_eq!(Commands::as_message("//help"), Some("/help"));
+        assert_eq!(Commands::as_message(""), Some(""));
+    }
+
+    #[test]
+    fn validates_arguments() {
+        let commands = Commands::with_defaults();
+
+        assert_eq!(execute(&commands, "/quit"), vec![Output::Quit]);
+        assert_eq!(execute(&commands, "/quit now"), vec![Output::Info(String::from("Usage: /quit"))]);
+        assert_eq!(execute(&commands, "/me"), vec![Output::Info(String::from("Usage: /me <action>"))]);
+        assert_eq!(
+            execute(&commands, "/unknown"),
+            vec![Output::Info(String::from("Unknown command /unknown, see /help"))]);
+        assert_eq!(
+            execute(&commands, "/nick \u{7}"),
+            vec![Output::Info(String::from("/nick: Nickname can not contain control characters"))]);
+    }
+
+    #[test]
+    fn keeps_free_text() {
+        let commands = Commands::with_defaults();
+
+        assert_eq!(
+            execute(&commands, "/me  waves   at you "),
+            vec![Output::Message(String::from("/me waves   at you"))]);
+    }
+
+    #[test]
+    fn registers_commands() {
+        let mut commands = Commands::with_defaults();
+
+        commands.register(Command {
+            name: "ping",
+            usage: "",
+            description: "answer with pong",
+            min_args: 0,
+            max_args: 0,
+            handler: |context, _| {
+                context.info("pong");
+                Ok(())
+            },
+        });
+
+        assert_eq!(execute(&commands, "/ping"), vec![Output::Info(String::from("pong"))]);
+        assert!(execute(&commands, "/help").contains(
+            &Output::Info(String::from("/ping - answer with pong"))));
+    }
+}
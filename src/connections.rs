@@ -0,0 +1,439 @@
+//! Decisions about which peers to connect to and which connections to keep
+//!
+//! Both sides of a pair of peers may dial each other at the same time. Once
+//! the handshake told us who is on the other end, only the connection
+//! dialed by the peer with the lower token is kept. Failed peers are dialed
+//! again after an exponential backoff with jitter, and peers violating the
+//! protocol are banned by address for a while.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+pub type ConnectionId = u64;
+
+/// Limits and timings of connections to peers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+
+    /// Delay before dialing a failed peer again, doubling with every failure
+    pub backoff_min: Duration,
+    pub backoff_max: Duration,
+
+    /// Time a peer violating the protocol is refused
+    pub ban_duration: Duration,
+
+    /// Time between pings, and the silence after which a peer is gone
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_inbound: 16,
+            max_outbound: 8,
+            backoff_min: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(300),
+            ban_duration: Duration::from_secs(600),
+            ping_interval: Duration::from_secs(5),
+            ping_timeout: Duration::from_secs(20),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Reasons to refuse a connection after the handshake.
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    /// We dialed ourselves
+    Own,
+
+    /// Another connection to the same peer is kept instead
+    Duplicate,
+
+    Banned,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::Own => write!(f, "connected to ourselves"),
+            Rejection::Duplicate => write!(f, "already connected to this peer"),
+            Rejection::Banned => write!(f, "peer is banned"),
+        }
+    }
+}
+
+// Discovered peer we can dial
+struct Candidate {
+    addr: SocketAddr,
+    failures: u32,
+    retry_at: Instant,
+}
+
+struct Connection {
+    direction: Direction,
+    addr: SocketAddr,
+
+    // Token of the peer we dialed, confirmed by the handshake
+    token: Option<String>,
+    established: bool,
+}
+
+pub struct ConnectionManager {
+    config: Config,
+
+    // Our own token, also used to break ties
+    token: String,
+
+    candidates: HashMap<String, Candidate>,
+    connections: HashMap<ConnectionId, Connection>,
+
+    // Addresses refused until the given time
+    bans: HashMap<IpAddr, Instant>,
+
+    next_id: ConnectionId,
+}
+
+impl ConnectionManager {
+    pub fn new(config: Config, token: &str) -> Self {
+        Self {
+            config,
+            token: token.to_string(),
+            candidates: HashMap::new(),
+            connections: HashMap::new(),
+            bans: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Remembers a discovered peer to dial, keeping its backoff when it was
+    /// known already.
+    pub fn add_peer(&mut self, token: &str, addr: SocketAddr, now: Instant) {
+        let candidate = self.candidates.entry(token.to_string()).or_insert(Candidate {
+            addr,
+            failures: 0,
+            retry_at: now,
+        });
+
+        candidate.addr = addr;
+    }
+
+    /// Forgets a peer which left, its connection stays until it closes.
+    pub fn remove_peer(&mut self, token: &str) {
+        self.candidates.remove(token);
+    }
+
+    /// Returns the peers to dial now, they count as outbound connections
+    /// from here on.
+    pub fn dial(&mut self, now: Instant) -> Vec<(ConnectionId, SocketAddr)> {
+        let mut dials = Vec::new();
+
+        let mut due: Vec<(String, SocketAddr)> = self.candidates
+            .iter()
+            .filter(|(token, candidate)| {
+                candidate.retry_at <= now
+                    && !self.is_banned(candidate.addr.ip(), now)
+                    && !self.has_connection(token)
+            })
+            .map(|(token, candidate)| (token.clone(), candidate.addr))
+            .collect();
+
+        // Dial the longest waiting peers first
+        due.sort_by_key(|(token, _)| self.candidates[token].retry_at);
+
+        for (token, addr) in due {
+            if self.count(Direction::Outbound) >= self.config.max_outbound {
+                break;
+            }
+
+            let id = self.add_connection(Direction::Outbound, addr, Some(token));
+            dials.push((id, addr));
+        }
+
+        dials
+    }
+
+    /// Registers an incoming connection, returns None when it should be
+    /// refused right away.
+    pub fn accept(&mut self, addr: SocketAddr, now: Instant) -> Option<ConnectionId> {
+        if self.is_banned(addr.ip(), now) || self.count(Direction::Inbound) >= self.config.max_inbound {
+            return None;
+        }
+
+        Some(self.add_connection(Direction::Inbound, addr, None))
+    }
+
+    /// Handles the token the other side sent in the handshake. Returns an
+    /// older connection to the same peer which should be closed now, or
+    /// the reason to close this one instead.
+    pub fn handshake(
+        &mut self,
+        id: ConnectionId,
+        token: &str,
+        now: Instant,
+    ) -> Result<Option<ConnectionId>, Rejection> {
+        let (direction, addr) = match self.connections.get(&id) {
+            Some(connection) => (connection.direction, connection.addr),
+            None => return Err(Rejection::Duplicate),
+        };
+
+        if token == self.token {
+            self.connections.remove(&id);
+            self.candidates.remove(token);
+            return Err(Rejection::Own);
+        }
+
+        if self.is_banned(addr.ip(), now) {
+            self.connections.remove(&id);
+            return Err(Rejection::Banned);
+        }
+
+        let other = self.connections
+            .iter()
+            .find(|(other_id, connection)| {
+                **other_id != id && connection.established && connection.token.as_deref() == Some(token)
+            })
+            .map(|(other_id, connection)| (*other_id, connection.direction));
+
+        let replaced = match other {
+            // Keep the connection dialed by the lower token
+            Some((other_id, other_direction)) if other_direction != direction => {
+                let dialer_is_lower = match direction {
+                    Direction::Outbound => self.token.as_str() < token,
+                    Direction::Inbound => token < self.token.as_str(),
+                };
+
+                if !dialer_is_lower {
+                    self.connections.remove(&id);
+                    return Err(Rejection::Duplicate);
+                }
+
+                Some(other_id)
+            }
+            // The peer reconnected before we noticed the old connection died
+            Some((other_id, _)) => Some(other_id),
+            None => None,
+        };
+
+        if let Some(other_id) = replaced {
+            self.connections.remove(&other_id);
+        }
+
+        let connection = self.connections.get_mut(&id).unwrap();
+        connection.token = Some(token.to_string());
+        connection.established = true;
+
+        if let Some(candidate) = self.candidates.get_mut(token) {
+            candidate.failures = 0;
+        }
+
+        Ok(replaced)
+    }
+
+    /// Forgets a closed connection. Peers we dialed are dialed again after
+    /// a backoff growing with every failure.
+    pub fn closed(&mut self, id: ConnectionId, now: Instant) {
+        let connection = match self.connections.remove(&id) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let token = match connection.token {
+            Some(token) => token,
+            None => return,
+        };
+
+        if let Some(candidate) = self.candidates.get_mut(&token) {
+            let delay = backoff(&self.config, candidate.failures, &mut rand::thread_rng());
+            candidate.failures = candidate.failures.saturating_add(1);
+            candidate.retry_at = now + delay;
+        }
+    }
+
+    /// Closes a connection after a protocol violation and refuses its
+    /// address for a while.
+    pub fn violation(&mut self, id: ConnectionId, now: Instant) {
+        if let Some(connection) = self.connections.get(&id) {
+            // Forget expired bans, addresses of misbehaving peers can change
+            // endlessly
+            self.bans.retain(|_, until| *until > now);
+            self.bans.insert(connection.addr.ip(), now + self.config.ban_duration);
+        }
+
+        self.closed(id, now);
+    }
+
+    /// Returns the ids and tokens of connections which finished the
+    /// handshake.
+    pub fn connected(&self) -> Vec<(ConnectionId, String)> {
+        self.connections
+            .iter()
+            .filter(|(_, connection)| connection.established)
+            .filter_map(|(id, connection)| connection.token.clone().map(|token| (*id, token)))
+            .collect()
+    }
+
+    /// Returns the connection to a peer.
+    pub fn connection_of(&self, token: &str) -> Option<ConnectionId> {
+        self.connected()
+            .into_iter()
+            .find(|(_, connected)| connected == token)
+            .map(|(id, _)| id)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.bans.get(&ip).is_some_and(|until| *until > now)
+    }
+
+    fn has_connection(&self, token: &str) -> bool {
+        self.connections.values().any(|connection| connection.token.as_deref() == Some(token))
+    }
+
+    fn count(&self, direction: Direction) -> usize {
+        self.connections.values().filter(|connection| connection.direction == direction).count()
+    }
+
+    fn add_connection(&mut self, direction: Direction, addr: SocketAddr, token: Option<String>) -> ConnectionId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.connections.insert(id, Connection {
+            direction,
+            addr,
+            token,
+            established: false,
+        });
+
+        id
+    }
+}
+
+/// Returns the delay before the next attempt after the given number of
+/// failures, somewhere in the upper half of the exponential delay.
+fn backoff<R: Rng>(config: &Config, failures: u32, rng: &mut R) -> Duration {
+    let factor = 1u32.checked_shl(cmp::min(failures, 16)).unwrap_or(u32::MAX);
+    let delay = cmp::min(config.backoff_min * factor, config.backoff_max);
+
+    let millis = delay.as_millis() as u64;
+    Duration::from_millis(rng.gen_range(millis / 2, millis + 1))
+}
+
+#[cfg(test)]
+mod connections {
+    use super::*;
+
+    fn addr(host: u8) -> SocketAddr {
+        SocketAddr::from(([192, 168, 0, host], 12345))
+    }
+
+    #[test]
+    fn keeps_one_connection_per_pair() {
+        let now = Instant::now();
+
+        // Both peers dial each other at the same time
+        let mut a = ConnectionManager::new(Config::default(), "a");
+        let mut b = ConnectionManager::new(Config::default(), "b");
+
+        a.add_peer("b", addr(2), now);
+        b.add_peer("a", addr(1), now);
+
+        let (a_out, _) = a.dial(now)[0];
+        let (b_out, _) = b.dial(now)[0];
+        let a_in = a.accept(addr(2), now).unwrap();
+        let b_in = b.accept(addr(1), now).unwrap();
+
+        // No second dial while connecting
+        assert!(a.dial(now).is_empty());
+
+        // The connection dialed by the lower token wins on both sides,
+        // whichever handshake finishes first
+        assert_eq!(a.handshake(a_in, "b", now), Ok(None));
+        assert_eq!(a.handshake(a_out, "b", now), Ok(Some(a_in)));
+
+        assert_eq!(b.handshake(b_out, "a", now), Ok(None));
+        assert_eq!(b.handshake(b_in, "a", now), Ok(Some(b_out)));
+
+        assert_eq!(a.connected(), vec![(a_out, String::from("b"))]);
+        assert_eq!(b.connected(), vec![(b_in, String::from("a"))]);
+
+        let own = a.accept(addr(3), now).unwrap();
+        assert_eq!(a.handshake(own, "a", now), Err(Rejection::Own));
+    }
+
+    #[test]
+    fn retries_with_backoff() {
+        let now = Instant::now();
+        let config = Config::default();
+        let mut manager = ConnectionManager::new(config.clone(), "a");
+
+        manager.add_peer("b", addr(2), now);
+
+        let mut retry_at = now;
+
+        for failures in 0..4 {
+            let dials = manager.dial(retry_at);
+            assert_eq!(dials.len(), 1);
+
+            manager.closed(dials[0].0, retry_at);
+
+            // Not before half of the doubled delay, at the latest after it
+            let delay = config.backoff_min * 2u32.pow(failures);
+            assert!(manager.dial(retry_at + delay / 2 - Duration::from_millis(1)).is_empty());
+
+            retry_at += delay;
+        }
+
+        assert_eq!(manager.dial(retry_at).len(), 1);
+
+        let mut rng = rand::thread_rng();
+        assert!(backoff(&config, 100, &mut rng) <= config.backoff_max);
+        assert!(backoff(&config, 100, &mut rng) >= config.backoff_max / 2);
+    }
+
+    #[test]
+    fn enforces_limits_and_bans() {
+        let now = Instant::now();
+        let config = Config {
+            max_inbound: 1,
+            max_outbound: 2,
+            ..Config::default()
+        };
+
+        let mut manager = ConnectionManager::new(config.clone(), "a");
+
+        for host in 2..6 {
+            manager.add_peer(&host.to_string(), addr(host), now);
+        }
+
+        assert_eq!(manager.dial(now).len(), 2);
+        assert!(manager.dial(now).is_empty());
+
+        let inbound = manager.accept(addr(10), now).unwrap();
+        assert_eq!(manager.accept(addr(11), now), None);
+
+        // Violations free the slot but ban the address
+        manager.violation(inbound, now);
+        assert_eq!(manager.accept(addr(10), now), None);
+        let other = manager.accept(addr(11), now).unwrap();
+        assert!(manager.is_banned(addr(10).ip(), now));
+        assert!(!manager.is_banned(addr(10).ip(), now + config.ban_duration));
+
+        // Expired bans are dropped when banning the next address
+        manager.violation(other, now + config.ban_duration);
+        assert_eq!(manager.bans.len(), 1);
+        assert!(manager.is_banned(addr(11).ip(), now + config.ban_duration));
+    }
+}
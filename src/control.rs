@@ -0,0 +1,231 @@
+//! Local control API speaking line-delimited JSON-RPC 2.0 over a Unix socket
+
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::{Future, Sink, Stream};
+use serde_json::{json, Value};
+use tokio::codec::{Framed, LinesCodec};
+use tokio::net::{UnixListener, UnixStream};
+use tokio_core::reactor::Handle;
+
+use crate::discovery::DiscoveryPeer;
+use crate::node::{self, Node, NodeEvent, Peer};
+use crate::shutdown::{Shutdown, ShutdownReason};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Returns the default location of the control socket.
+pub fn default_socket_path() -> PathBuf {
+    std::env::temp_dir().join("p2p-chat.sock")
+}
+
+/// Starts listening for control connections on the given socket path.
+pub fn serve(
+    handle: Handle,
+    shutdown: Shutdown,
+    node: Node,
+    path: &Path,
+) -> Result<(), io::Error> {
+    // Remove socket file left behind by a previous run, but never steal the
+    // socket of a node which is still running
+    if path.exists() {
+        if StdUnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is used by another node", path.display())));
+        }
+
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+
+    let path_clone = path.to_path_buf();
+    shutdown.on_shutdown(move || {
+        let _ = fs::remove_file(path_clone);
+    });
+
+    let handle_clone = handle.clone();
+    let shutdown_clone = shutdown.clone();
+
+    let server = listener
+        .incoming()
+        .for_each(move |stream| {
+            handle_connection(&handle_clone, node.clone(), stream);
+            Ok(())
+        })
+        .map_err(move |err| {
+            shutdown_clone.trigger(ShutdownReason::Error(
+                format!("Control socket failed. {}", err)));
+        });
+
+    handle.spawn(server);
+
+    Ok(())
+}
+
+fn handle_connection(handle: &Handle, node: Node, stream: UnixStream) {
+    let (sink, stream) = Framed::new(stream, LinesCodec::new()).split();
+    let (tx, rx) = unbounded::<String>();
+
+    // Write responses and notifications back to the client
+    let writer = sink
+        .send_all(rx.map_err(|_| io::Error::other("closed")))
+        .then(|_| Ok(()));
+
+    handle.spawn(writer);
+
+    let handle_clone = handle.clone();
+
+    let reader = stream
+        .for_each(move |line| {
+            if let Some(response) = handle_request(&handle_clone, &node, &tx, &line) {
+                let _ = tx.unbounded_send(response.to_string());
+            }
+
+            Ok(())
+        })
+        .then(|_| Ok(()));
+
+    handle.spawn(reader);
+}
+
+fn handle_request(
+    handle: &Handle,
+    node: &Node,
+    tx: &UnboundedSender<String>,
+    line: &str,
+) -> Option<Value> {
+    if line.trim().is_empty() {
+        return None;
+    }
+
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return Some(error(Value::Null, PARSE_ERROR, &err.to_string())),
+    };
+
+    // Requests without an id are notifications and receive no response
+    let id = request.get("id").cloned();
+
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method,
+        None => {
+            return Some(error(id.unwrap_or(Value::Null), INVALID_REQUEST, "Missing method"));
+        }
+    };
+
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = match method {
+        "channel" => Ok(json!({
+            "url": node::channel_url(&node.channel_key()),
+            "public_key": hex::encode(node.public_key()),
+        })),
+        "join" => join(node, &params),
+        "send" => send(node, &params),
+        "peers" => Ok(peers(node)),
+        "subscribe" => {
+            subscribe(handle, node, tx.clone());
+            Ok(Value::Bool(true))
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+    };
+
+    let id = id?;
+
+    match result {
+        Ok(result) => Some(json!({ "jsonrpc": "2.0", "id": id, "result": result })),
+        Err((code, message)) => Some(error(id, code, &message)),
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, (i64, String)> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| (INVALID_PARAMS, format!("Missing string parameter {}", name)))
+}
+
+fn join(node: &Node, params: &Value) -> Result<Value, (i64, String)> {
+    let url = string_param(params, "channel")?;
+    let channel_key = node::parse_channel_url(url).map_err(|err| (INVALID_PARAMS, err))?;
+
+    node.join(&channel_key);
+
+    Ok(json!({ "url": node::channel_url(&channel_key) }))
+}
+
+fn send(node: &Node, params: &Value) -> Result<Value, (i64, String)> {
+    let text = string_param(params, "text")?;
+
+    if text.is_empty() {
+        return Err((INVALID_PARAMS, String::from("Message can not be empty")));
+    }
+
+    Ok(node.send_message(text).to_json())
+}
+
+fn peers(node: &Node) -> Value {
+    Value::Array(node.peer_list().iter().map(peer_state_to_json).collect())
+}
+
+fn peer_to_json(peer: &DiscoveryPeer) -> Value {
+    json!({
+        "addr": peer.addr().to_string(),
+        "port": peer.port(),
+        "token": peer.token(),
+    })
+}
+
+// Connection state on top of what discovery told us, with the round-trip
+// time in milliseconds once measured
+fn peer_state_to_json(peer: &Peer) -> Value {
+    let mut value = peer_to_json(&peer.discovery);
+    value["connected"] = Value::Bool(peer.connected);
+    value["rtt_ms"] = peer.latency.map_or(Value::Null, |rtt| json!(rtt.as_millis() as u64));
+    value
+}
+
+// Forward node events as JSON-RPC notifications until the client is gone
+fn subscribe(handle: &Handle, node: &Node, tx: UnboundedSender<String>) {
+    let node_events = node.clone();
+
+    let forward = node.subscribe().for_each(move |event| {
+        let (method, params) = match event {
+            NodeEvent::Message(message) => ("message", message.to_json()),
+            NodeEvent::PeerFound(peer) => ("peer_found", peer_to_json(&peer)),
+            NodeEvent::PeerUpdated(token) => {
+                let peer = node_events.peer_list().into_iter().find(|peer| peer.discovery.token() == token);
+                ("peer_updated", peer.as_ref().map_or_else(|| json!({ "token": token }), peer_state_to_json))
+            }
+            NodeEvent::PeerLeft(token) => ("peer_left", json!({ "token": token })),
+            NodeEvent::Nickname { author, nickname } => ("nickname", json!({
+                "author": hex::encode(author),
+                "nickname": nickname,
+            })),
+            NodeEvent::Info(text) => ("info", json!({ "text": text })),
+        };
+
+        let notification = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+
+        tx.unbounded_send(notification.to_string()).map_err(|_| ())
+    });
+
+    handle.spawn(forward);
+}
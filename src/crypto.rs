@@ -67,6 +67,10 @@ pub fn generate_random_token() -> String {
     base64::encode(&Sha256::digest(rnd.as_bytes()))
 }
 
+pub fn generate_nonce() -> [u8; 32] {
+    rand::thread_rng().gen()
+}
+
 pub fn generate_discovery_key(public_key: &[u8], name: &[u8]) -> Blake2bResult {
     blake2b(32, public_key, name)
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
 use std::str;
 use std::time::Duration;
 
@@ -14,8 +14,6 @@ use trust_dns_proto::multicast::{MdnsQueryType, MdnsStream};
 use trust_dns_proto::xfer::SerialMessage;
 use trust_dns_proto::{BufStreamHandle};
 
-use crate::crypto;
-
 const ANNOUNCE_FREQUENCY: u64 = 1000;
 
 const MDNS_ADDRESS: &str = "224.0.0.251";
@@ -23,6 +21,15 @@ const MDNS_PORT: u16 = 5353;
 
 const NAME_SUFFIX: &str = "chat.local";
 
+// Time in seconds other peers can cache our answer
+const RECORD_TTL: u32 = 120;
+
+/// Peers appearing or leaving the network.
+pub enum DiscoveryEvent {
+    Found(DiscoveryPeer),
+    Left(String),
+}
+
 pub struct DiscoveryStream {
     multicast_addr: SocketAddr,
     name: Name,
@@ -36,6 +43,7 @@ impl DiscoveryStream {
         handle: Handle,
         discovery_key: &[u8],
         port: u16,
+        token: &str,
     ) -> impl Future<Item=Self, Error=io::Error> {
         // Shorten and convert hash to 40 hex chars
         let discovery_key_hex = hex::encode(discovery_key);
@@ -44,14 +52,11 @@ impl DiscoveryStream {
         // Set DNS name to identify what we are interested in
         let name = Name::from_ascii(&format!("{}.{}", discovery_key, NAME_SUFFIX)).unwrap();
 
-        // Generate individual token to identify ourselves
-        let token = crypto::generate_random_token();
-
-        // Define own peer node for discovery
+        // Define own peer node for discovery, identified by our token
         let peer = DiscoveryPeer {
             addr: Ipv4Addr::UNSPECIFIED,
             port,
-            token,
+            token: token.to_string(),
         };
 
         // Set multicast address and port
@@ -89,19 +94,27 @@ impl DiscoveryStream {
 
         let query = self.create_mdns_question().to_vec().unwrap();
 
-        // Send queries to find new peers every x seconds
+        // Send queries to find new peers every x seconds, stop as soon as
+        // the discovery stream went away
         Interval::new_interval(Duration::from_millis(frequency))
+            .map_err(|_| ())
             .for_each(move |_| {
                 let question_message = SerialMessage::new(query.clone(), addr_clone);
 
-                sender_clone.unbounded_send(question_message).unwrap();
-
-                Ok(())
+                sender_clone.unbounded_send(question_message).map_err(|_| ())
             })
             .then(|_| Ok(()))
     }
 
-    fn handle_incoming_message(&self, serial_message: SerialMessage) -> Option<DiscoveryPeer> {
+    /// Returns a goodbye announcement to send when we leave the network.
+    pub fn goodbye(&self) -> Goodbye {
+        Goodbye {
+            message: self.create_mdns_answer(0).to_vec().unwrap(),
+            multicast_addr: self.multicast_addr,
+        }
+    }
+
+    fn handle_incoming_message(&self, serial_message: SerialMessage) -> Option<DiscoveryEvent> {
         match Message::from_vec(serial_message.bytes()) {
             Ok(message) => {
                 // Filter messages looking for same name
@@ -112,7 +125,7 @@ impl DiscoveryStream {
                 match message.message_type() {
                     MessageType::Query => {
                         let answer_message = SerialMessage::new(
-                            self.create_mdns_answer().to_vec().unwrap(),
+                            self.create_mdns_answer(RECORD_TTL).to_vec().unwrap(),
                             self.multicast_addr
                         );
 
@@ -124,12 +137,15 @@ impl DiscoveryStream {
                     MessageType::Response => {
                         // Check if we got response with required fields
                         match DiscoveryPeer::from_message(&message) {
-                            Some(interested_peer) => {
+                            Some((interested_peer, ttl)) => {
                                 // Make sure this is not our response
-                                if interested_peer.token != self.peer.token {
-                                    Some(interested_peer)
-                                } else {
+                                if interested_peer.token == self.peer.token {
                                     None
+                                } else if ttl == 0 {
+                                    // Zero TTL announces that the peer is leaving
+                                    Some(DiscoveryEvent::Left(interested_peer.token))
+                                } else {
+                                    Some(DiscoveryEvent::Found(interested_peer))
                                 }
                             }
                             None => None,
@@ -153,7 +169,7 @@ impl DiscoveryStream {
         message
     }
 
-    fn create_mdns_answer(&self) -> Message {
+    fn create_mdns_answer(&self, ttl: u32) -> Message {
         let mut message = self.create_mdns_question();
         message.set_message_type(MessageType::Response);
 
@@ -165,6 +181,7 @@ impl DiscoveryStream {
         let mut record = Record::new();
         record.set_name(self.name.clone());
         record.set_record_type(RecordType::TXT);
+        record.set_ttl(ttl);
         record.set_rdata(RData::TXT(rdata::txt::TXT::new(txt_data)));
 
         message.add_answer(record);
@@ -174,7 +191,7 @@ impl DiscoveryStream {
 }
 
 impl Stream for DiscoveryStream {
-    type Item = DiscoveryPeer;
+    type Item = DiscoveryEvent;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
@@ -190,6 +207,22 @@ impl Stream for DiscoveryStream {
     }
 }
 
+/// Announcement telling other peers that we are gone, sent on shutdown.
+pub struct Goodbye {
+    message: Vec<u8>,
+    multicast_addr: SocketAddr,
+}
+
+impl Goodbye {
+    /// Sends the announcement directly, without depending on the event loop.
+    pub fn send(&self) -> Result<(), io::Error> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.send_to(&self.message, self.multicast_addr)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
 pub struct DiscoveryPeer {
     addr: Ipv4Addr,
     port: u16,
@@ -209,7 +242,7 @@ impl DiscoveryPeer {
         self.token.clone()
     }
 
-    fn from_message(message: &Message) -> Option<DiscoveryPeer> {
+    fn from_message(message: &Message) -> Option<(DiscoveryPeer, u32)> {
         // Check TXT records of message for needed fields
         message.answers().iter().find_map(|rr| {
             if let RData::TXT(ref rdata) = *rr.rdata() {
@@ -243,7 +276,7 @@ impl DiscoveryPeer {
 
                 let (addr, port) = DiscoveryPeer::decode_peers_field(&peers);
 
-                Some(DiscoveryPeer { port, addr, token })
+                Some((DiscoveryPeer { port, addr, token }, rr.ttl()))
             } else {
                 None
             }
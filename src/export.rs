@@ -0,0 +1,292 @@
+//! Export of channel history, as archive to import on another node or as
+//! transcript for humans to read
+//!
+//! Archives hold the raw signed entries of all logs together with the public
+//! keys of their authors, so whoever imports one can verify every entry
+//! again instead of trusting the file:
+//!
+//! ```text
+//! "P2PCHAT" | version | channel key | varint log count
+//!   | (public key | varint entry count | (varint length | entry)*)*
+//! ```
+
+use std::fmt;
+
+use ed25519_dalek::{PublicKey, PUBLIC_KEY_LENGTH};
+use serde_json::json;
+
+use crate::log::{Log, LogError};
+use crate::node::{self, Message};
+use crate::varint;
+
+const MAGIC: &[u8] = b"P2PCHAT";
+const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveError {
+    /// File is no archive at all
+    InvalidFormat,
+    UnsupportedVersion(u8),
+    Truncated,
+
+    /// Log of an author contains entries which are not theirs
+    InvalidLog(String, LogError),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::InvalidFormat => write!(f, "file is no chat archive"),
+            ArchiveError::UnsupportedVersion(version) => {
+                write!(f, "archive version {} is not supported", version)
+            }
+            ArchiveError::Truncated => write!(f, "archive is truncated"),
+            ArchiveError::InvalidLog(author, err) => write!(f, "log of {} is invalid, {}", author, err),
+        }
+    }
+}
+
+/// Verified logs of a channel.
+pub struct Archive {
+    pub channel_key: Vec<u8>,
+    pub logs: Vec<Log>,
+}
+
+impl Archive {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.channel_key);
+        varint::write(&mut bytes, self.logs.len() as u64);
+
+        for log in &self.logs {
+            bytes.extend_from_slice(log.public_key());
+            varint::write(&mut bytes, log.count());
+
+            for entry in log.iter() {
+                let entry = entry.to_bytes();
+                varint::write(&mut bytes, entry.len() as u64);
+                bytes.extend_from_slice(&entry);
+            }
+        }
+
+        bytes
+    }
+
+    /// Decodes an archive, failing when any entry does not belong to the
+    /// log it is stored in.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ArchiveError> {
+        let mut bytes = bytes.strip_prefix(MAGIC).ok_or(ArchiveError::InvalidFormat)?;
+
+        match take(&mut bytes, 1)?[0] {
+            VERSION => {}
+            version => return Err(ArchiveError::UnsupportedVersion(version)),
+        }
+
+        let channel_key = take(&mut bytes, PUBLIC_KEY_LENGTH)?.to_vec();
+        let log_count = varint::read(&mut bytes).ok_or(ArchiveError::Truncated)?;
+        let mut logs = Vec::new();
+
+        for _ in 0..log_count {
+            let author = take(&mut bytes, PUBLIC_KEY_LENGTH)?;
+            let invalid = |err| ArchiveError::InvalidLog(node::fingerprint(author), err);
+
+            let mut log = Log::remote(author).map_err(invalid)?;
+            let entry_count = varint::read(&mut bytes).ok_or(ArchiveError::Truncated)?;
+
+            for _ in 0..entry_count {
+                let length = varint::read(&mut bytes).ok_or(ArchiveError::Truncated)?;
+                let entry = take(&mut bytes, length as usize)?;
+                log.insert(entry).map_err(invalid)?;
+            }
+
+            // Entries were checked one by one, check the log as a whole
+            let public_key = PublicKey::from_bytes(author).map_err(|_| invalid(LogError::InvalidPublicKey))?;
+
+            if !log.verify(&public_key) {
+                return Err(invalid(LogError::InvalidSignature));
+            }
+
+            logs.push(log);
+        }
+
+        if !bytes.is_empty() {
+            return Err(ArchiveError::InvalidFormat);
+        }
+
+        Ok(Self { channel_key, logs })
+    }
+}
+
+// Splits off the next bytes
+fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8], ArchiveError> {
+    if bytes.len() < length {
+        return Err(ArchiveError::Truncated);
+    }
+
+    let (taken, rest) = bytes.split_at(length);
+    *bytes = rest;
+    Ok(taken)
+}
+
+/// Formats of transcripts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Markdown,
+    Json,
+}
+
+impl Format {
+    /// Returns the format matching the extension of a file name, plain text
+    /// for unknown ones.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.to_lowercase();
+
+        if path.ends_with(".md") || path.ends_with(".markdown") {
+            Format::Markdown
+        } else if path.ends_with(".json") {
+            Format::Json
+        } else {
+            Format::Text
+        }
+    }
+}
+
+/// Returns the messages of a channel in the given format, in the order they
+/// are passed in.
+pub fn transcript(channel_key: &[u8], messages: &[Message], format: Format) -> String {
+    let url = node::channel_url(channel_key);
+    let time = |message: &Message| message.timestamp.format("%Y-%m-%d %H:%M:%S");
+
+    match format {
+        Format::Text => {
+            let mut text = format!("{}\n\n", url);
+
+            for message in messages {
+                text.push_str(&format!("[{}] {}: {}\n", time(message), message.display_name(), message.text));
+            }
+
+            text
+        }
+        Format::Markdown => {
+            let mut text = format!("# {}\n\n", url);
+
+            for message in messages {
+                text.push_str(&format!(
+                    "- {} **{}**: {}\n",
+                    time(message),
+                    escape_markdown(&message.display_name()),
+                    escape_markdown(&message.text)));
+            }
+
+            text
+        }
+        Format::Json => {
+            let messages: Vec<serde_json::Value> = messages.iter().map(Message::to_json).collect();
+            let value = json!({ "channel": url, "messages": messages });
+            format!("{:#}\n", value)
+        }
+    }
+}
+
+// Keeps messages from formatting the transcript or each other
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for chr in text.chars() {
+        if "\\`*_{}[]()#+-.!<>|~".contains(chr) {
+            escaped.push('\\');
+        }
+
+        escaped.push(chr);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod export {
+    use super::*;
+
+    use chrono::{Local, TimeZone};
+
+    fn archive() -> Archive {
+        let mut log = Log::new();
+        log.append(b"Hello").unwrap();
+        log.append(b"Test").unwrap();
+
+        // Sparse copies are exported with their holes
+        let mut other = Log::new();
+        let mut sparse = Log::remote(other.public_key()).unwrap();
+
+        for index in 0..3 {
+            other.append(format!("Message {}", index).as_bytes()).unwrap();
+        }
+
+        sparse.insert(&other.entry_bytes(3).unwrap()).unwrap();
+
+        Archive {
+            channel_key: log.public_key().to_vec(),
+            logs: vec![log, sparse],
+        }
+    }
+
+    #[test]
+    fn encode_decode_archive() {
+        let archive = archive();
+        let bytes = archive.to_bytes();
+        let decoded = Archive::from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded.channel_key, archive.channel_key);
+        assert_eq!(decoded.logs.len(), 2);
+        assert_eq!(decoded.logs[0].get(1), Some(b"Test".to_vec()));
+        assert_eq!(decoded.logs[1].bitfield().ranges(), vec![3..4]);
+
+        assert!(matches!(Archive::from_bytes(b"Hello"), Err(ArchiveError::InvalidFormat)));
+        assert!(matches!(Archive::from_bytes(&bytes[..bytes.len() - 1]), Err(ArchiveError::Truncated)));
+
+        let mut version = bytes.clone();
+        version[MAGIC.len()] = 9;
+        assert!(matches!(Archive::from_bytes(&version), Err(ArchiveError::UnsupportedVersion(9))));
+    }
+
+    #[test]
+    fn rejects_forged_entries() {
+        let archive = archive();
+        let mut bytes = archive.to_bytes();
+
+        // Replace the text of the first entry
+        let position = bytes.windows(5).position(|window| window == b"Hello").unwrap();
+        bytes[position..position + 5].copy_from_slice(b"Hallo");
+
+        assert!(matches!(
+            Archive::from_bytes(&bytes),
+            Err(ArchiveError::InvalidLog(_, LogError::InvalidSignature))));
+    }
+
+    #[test]
+    fn formats_transcripts() {
+        let messages = vec![Message {
+            author: vec![1; 32],
+            nickname: Some(String::from("adz")),
+            sequence_number: 1,
+            text: String::from("Hello *everyone*"),
+            timestamp: Local.ymd(2019, 8, 1).and_hms(12, 30, 0),
+        }];
+
+        let text = transcript(&[7; 32], &messages, Format::Text);
+        assert!(text.starts_with("chat://0707"));
+        assert!(text.ends_with("[2019-08-01 12:30:00] adz (010101): Hello *everyone*\n"));
+
+        let markdown = transcript(&[7; 32], &messages, Format::Markdown);
+        assert!(markdown.ends_with("- 2019-08-01 12:30:00 **adz \\(010101\\)**: Hello \\*everyone\\*\n"));
+
+        let json: serde_json::Value = serde_json::from_str(&transcript(&[7; 32], &messages, Format::Json)).unwrap();
+        assert_eq!(json["messages"][0]["text"], "Hello *everyone*");
+
+        assert_eq!(Format::from_path("chat.MD"), Format::Markdown);
+        assert_eq!(Format::from_path("chat.json"), Format::Json);
+        assert_eq!(Format::from_path("chat.log"), Format::Text);
+    }
+}
@@ -1,13 +1,63 @@
 //! Simple append-only log structure
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::ops::Range;
 use std::option;
 
-use byteorder::{BigEndian, WriteBytesExt};
-use ed25519_dalek::{Keypair, PublicKey, Signature};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use ed25519_dalek::{Keypair, PublicKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
 
+use crate::bitfield::Bitfield;
 use crate::crypto;
 
+// Size of the hash and sequence number following the data of an entry
+const CONTENT_TRAILER_LENGTH: usize = 16;
+
+/// Highest sequence number accepted from the network, keeps malicious peers
+/// from making us allocate unbounded memory.
+pub const MAX_SEQUENCE_NUMBER: u64 = 1 << 24;
+
+/// First byte of the data of checkpoint entries, not used for anything else.
+pub const CHECKPOINT_MARKER: u8 = 0xff;
+
+#[derive(Debug, PartialEq)]
+pub enum LogError {
+    /// Only the owner of the keypair can append to a log
+    ReadOnly,
+    InvalidPublicKey,
+
+    /// Entry could not be decoded
+    InvalidEntry,
+
+    /// Signature does not belong to the public key of the log
+    InvalidSignature,
+
+    /// Entry does not link to its neighbours or differs from the held one
+    Conflict(u64),
+
+    /// Entries of a proof do not show a fork of the log
+    InvalidProof,
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::ReadOnly => write!(f, "log belongs to someone else"),
+            LogError::InvalidPublicKey => write!(f, "invalid public key"),
+            LogError::InvalidEntry => write!(f, "entry could not be decoded"),
+            LogError::InvalidSignature => write!(f, "entry has an invalid signature"),
+            LogError::Conflict(sequence_number) => {
+                write!(f, "entry {} conflicts with the log", sequence_number)
+            }
+            LogError::InvalidProof => write!(f, "proof does not show a fork"),
+        }
+    }
+}
+
 // Convenience function to hash value with Blake2b
 fn generate_hash<H: Hash>(value: &H) -> u64 {
     let mut hasher = crypto::Blake2bHasher::new();
@@ -15,7 +65,7 @@ fn generate_hash<H: Hash>(value: &H) -> u64 {
     hasher.finish()
 }
 
-#[derive(Default, PartialEq, Eq)]
+#[derive(Clone, Default, PartialEq, Eq)]
 struct LogEntryContent {
     data: Vec<u8>,
     hash_previous: u64,
@@ -39,7 +89,7 @@ impl LogEntryContent {
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 struct LogEntry {
     content: LogEntryContent,
     signature: Signature,
@@ -59,6 +109,35 @@ impl LogEntry {
         crypto::verify_data(&public_key, &self.content.to_bytes(), &self.signature)
             .is_ok()
     }
+
+    // Content followed by the signature, as sent to other peers
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.content.to_bytes();
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, LogError> {
+        if bytes.len() < CONTENT_TRAILER_LENGTH + SIGNATURE_LENGTH {
+            return Err(LogError::InvalidEntry);
+        }
+
+        let (content, signature) = bytes.split_at(bytes.len() - SIGNATURE_LENGTH);
+        let (data, trailer) = content.split_at(content.len() - CONTENT_TRAILER_LENGTH);
+
+        let mut trailer = Cursor::new(trailer);
+        let hash_previous = trailer.read_u64::<BigEndian>().map_err(|_| LogError::InvalidEntry)?;
+        let sequence_number = trailer.read_u64::<BigEndian>().map_err(|_| LogError::InvalidEntry)?;
+
+        if sequence_number == 0 || sequence_number > MAX_SEQUENCE_NUMBER {
+            return Err(LogError::InvalidEntry);
+        }
+
+        Ok(Self {
+            content: LogEntryContent::new(hash_previous, data.to_vec(), sequence_number),
+            signature: Signature::from_bytes(signature).map_err(|_| LogError::InvalidEntry)?,
+        })
+    }
 }
 
 impl Hash for LogEntry {
@@ -69,50 +148,402 @@ impl Hash for LogEntry {
     }
 }
 
+/// Entry held in a log, borrowed to read it without copying its data.
+#[derive(Clone, Copy)]
+pub struct Entry<'a> {
+    entry: &'a LogEntry,
+}
+
+impl<'a> Entry<'a> {
+    pub fn sequence_number(&self) -> u64 {
+        self.entry.content.sequence_number
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        &self.entry.content.data
+    }
+
+    /// Returns the hash of the entry, which the next entry links to.
+    pub fn hash(&self) -> u64 {
+        generate_hash(self.entry)
+    }
+
+    /// Returns the hash of the previous entry, 0 for the first one.
+    pub fn hash_previous(&self) -> u64 {
+        self.entry.content.hash_previous
+    }
+
+    pub fn signature(&self) -> &'a Signature {
+        &self.entry.signature
+    }
+
+    /// Returns the entry encoded for other peers.
+    pub fn to_bytes(self) -> Vec<u8> {
+        self.entry.to_bytes()
+    }
+}
+
+/// Summary of a log up to an entry, signed by its owner as the entry right
+/// after it.
+///
+/// Peers trusting the checkpoint do not need any entries before it, the
+/// hash of the last one commits to all of them. Entries held before a
+/// checkpoint can still be verified against it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// Number of entries before the checkpoint
+    pub length: u64,
+
+    /// Hash of the last entry before the checkpoint, 0 when there is none
+    pub root: u64,
+}
+
+impl Checkpoint {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![CHECKPOINT_MARKER];
+        bytes.write_u64::<BigEndian>(self.length).unwrap();
+        bytes.write_u64::<BigEndian>(self.root).unwrap();
+        bytes
+    }
+
+    /// Returns the checkpoint when the data of an entry is one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 17 || bytes[0] != CHECKPOINT_MARKER {
+            return None;
+        }
+
+        let mut cursor = Cursor::new(&bytes[1..]);
+
+        Some(Self {
+            length: cursor.read_u64::<BigEndian>().ok()?,
+            root: cursor.read_u64::<BigEndian>().ok()?,
+        })
+    }
+}
+
+/// Two entries signed by the owner of a log which can not both be part of
+/// it, either taking the same place or the second not linking to the first.
+///
+/// Anyone knowing the public key can check the proof, so it is shared with
+/// other peers to warn them about the owner equivocating.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForkProof {
+    pub first: Vec<u8>,
+    pub second: Vec<u8>,
+}
+
+impl ForkProof {
+    fn new(held: &LogEntry, conflicting: &LogEntry) -> Self {
+        let (first, second) = if held.content.sequence_number <= conflicting.content.sequence_number {
+            (held, conflicting)
+        } else {
+            (conflicting, held)
+        };
+
+        Self {
+            first: first.to_bytes(),
+            second: second.to_bytes(),
+        }
+    }
+
+    /// Returns true when both entries are signed with the public key and
+    /// conflict with each other.
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        let (first, second) = match (LogEntry::from_bytes(&self.first), LogEntry::from_bytes(&self.second)) {
+            (Ok(first), Ok(second)) => (first, second),
+            _ => return false,
+        };
+
+        if !first.verify(public_key) || !second.verify(public_key) {
+            return false;
+        }
+
+        let first_number = first.content.sequence_number;
+        let second_number = second.content.sequence_number;
+
+        if first_number == second_number {
+            first != second
+        } else {
+            first_number + 1 == second_number && second.content.hash_previous != generate_hash(&first)
+        }
+    }
+}
+
 /// Append-only log data-structure.
-#[derive(Default)]
+///
+/// Logs of others are replicated sparsely, so entries can be missing in
+/// between. Which ones are present is tracked in a bitfield.
 pub struct Log {
-    entries: Vec<LogEntry>,
-    keypair: Keypair,
+    // Entries by sequence number, missing ones are left out
+    entries: BTreeMap<u64, LogEntry>,
+    bitfield: Bitfield,
+    public_key: PublicKey,
+
+    // Only known for our own log
+    keypair: Option<Keypair>,
+
+    // Sequence number of the latest checkpoint we hold
+    checkpoint: Option<u64>,
+
+    // First proof of the owner signing conflicting entries
+    fork: Option<ForkProof>,
+
+    // Receive sequence numbers of new entries
+    subscribers: Vec<UnboundedSender<u64>>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Log {
     /// Returns new instance of append-only log.
     pub fn new() -> Self {
+        Self::with_keypair(crypto::generate_keypair())
+    }
+
+    /// Returns an empty log we can append to with the given keypair.
+    pub fn with_keypair(keypair: Keypair) -> Self {
         Self {
-            entries: Vec::new(),
-            keypair: crypto::generate_keypair(),
+            entries: BTreeMap::new(),
+            bitfield: Bitfield::new(),
+            public_key: keypair.public,
+            keypair: Some(keypair),
+            checkpoint: None,
+            fork: None,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Returns an empty log of someone else, to be filled with their
+    /// entries.
+    pub fn remote(public_key: &[u8]) -> Result<Self, LogError> {
+        if public_key.len() != PUBLIC_KEY_LENGTH {
+            return Err(LogError::InvalidPublicKey);
         }
+
+        Ok(Self {
+            entries: BTreeMap::new(),
+            bitfield: Bitfield::new(),
+            public_key: PublicKey::from_bytes(public_key).map_err(|_| LogError::InvalidPublicKey)?,
+            keypair: None,
+            checkpoint: None,
+            fork: None,
+            subscribers: Vec::new(),
+        })
     }
 
     /// Returns the public key of the generated keypair.
     pub fn public_key(&self) -> &[u8] {
-        self.keypair.public.as_bytes()
+        self.public_key.as_bytes()
     }
 
-    /// Append new entry to the log with arbitrary data.
-    pub fn append(&mut self, data: &[u8]) {
+    /// Returns true when we can append to this log.
+    pub fn is_writable(&self) -> bool {
+        self.keypair.is_some()
+    }
+
+    /// Append new entry to the log with arbitrary data, returns its
+    /// sequence number.
+    pub fn append(&mut self, data: &[u8]) -> Result<u64, LogError> {
+        let keypair = self.keypair.as_ref().ok_or(LogError::ReadOnly)?;
+
         // Define sequence number
         let sequence_number = self.len() + 1;
 
         // Generate hash of previous entry when one is given
         let mut hash_previous = 0;
         if sequence_number > 1 {
-            let entry_previous = &self.entries[sequence_number - 2];
+            let entry_previous = self.entry(sequence_number - 2).unwrap();
             hash_previous = generate_hash(entry_previous);
         }
 
         // Create content of entry and sign it
         let content = LogEntryContent::new(hash_previous, data.to_vec(), sequence_number as u64);
-        let entry = LogEntry::sign(content, &self.keypair);
+        let entry = LogEntry::sign(content, keypair);
 
         // Append entry to log
-        self.entries.push(entry);
+        self.entries.insert(sequence_number as u64, entry);
+        self.bitfield.set(sequence_number as u64, true);
+
+        if Checkpoint::from_bytes(data).is_some() {
+            self.checkpoint = Some(sequence_number as u64);
+        }
+
+        self.notify(sequence_number as u64);
+
+        Ok(sequence_number as u64)
+    }
+
+    /// Appends a checkpoint of all entries so far, returns its sequence
+    /// number.
+    pub fn append_checkpoint(&mut self) -> Result<u64, LogError> {
+        let length = self.len() as u64;
+        let root = length.checked_sub(1).and_then(|index| self.hash(index as usize)).unwrap_or(0);
+
+        self.append(&Checkpoint { length, root }.to_bytes())
+    }
+
+    /// Returns the sequence number and contents of the latest checkpoint we
+    /// hold.
+    pub fn latest_checkpoint(&self) -> Option<(u64, Checkpoint)> {
+        let sequence_number = self.checkpoint?;
+        let data = self.get(sequence_number as usize - 1)?;
+
+        Checkpoint::from_bytes(&data).map(|checkpoint| (sequence_number, checkpoint))
+    }
+
+    /// Forgets all entries before the latest checkpoint, returns how many
+    /// were dropped.
+    pub fn compact(&mut self) -> u64 {
+        let end = match self.checkpoint {
+            Some(sequence_number) => sequence_number,
+            None => return 0,
+        };
+
+        let count = self.count();
+
+        self.entries = self.entries.split_off(&end);
+
+        for sequence_number in 1..end {
+            self.bitfield.set(sequence_number, false);
+        }
+
+        count - self.count()
+    }
+
+    /// Adds an entry received from another peer at its sequence number,
+    /// returns the sequence number. Holes before it are allowed.
+    ///
+    /// Entries conflicting with the held ones are refused, the log is
+    /// marked as forked when both are signed by its owner.
+    pub fn insert(&mut self, bytes: &[u8]) -> Result<u64, LogError> {
+        let entry = LogEntry::from_bytes(bytes)?;
+        let sequence_number = entry.content.sequence_number;
+        let index = (sequence_number - 1) as usize;
+
+        if !entry.verify(&self.public_key) {
+            return Err(LogError::InvalidSignature);
+        }
+
+        // Entries need to link to their neighbours when we hold them
+        let previous_matches = match index.checked_sub(1).and_then(|index| self.entry(index)) {
+            Some(previous) => generate_hash(previous) == entry.content.hash_previous,
+            None => index > 0 || entry.content.hash_previous == 0,
+        };
+
+        let next_matches = self.entry(index + 1)
+            .is_none_or(|next| next.content.hash_previous == generate_hash(&entry));
+
+        // Checkpoints need to summarize exactly the entries before them
+        let checkpoint = Checkpoint::from_bytes(&entry.content.data);
+
+        if let Some(checkpoint) = checkpoint {
+            if checkpoint.length != sequence_number - 1 || checkpoint.root != entry.content.hash_previous {
+                return Err(LogError::InvalidEntry);
+            }
+        }
+
+        let held = self.entry(index).map(|held| *held == entry);
+
+        if !previous_matches || !next_matches || held == Some(false) {
+            // The first entry can conflict with nothing but its own rules
+            let counterpart = if held == Some(false) {
+                self.entry(index)
+            } else if !previous_matches {
+                index.checked_sub(1).and_then(|index| self.entry(index))
+            } else {
+                self.entry(index + 1)
+            };
+
+            if let Some(counterpart) = counterpart {
+                if self.fork.is_none() {
+                    self.fork = Some(ForkProof::new(counterpart, &entry));
+                }
+            }
+
+            return Err(LogError::Conflict(sequence_number));
+        }
+
+        if held == Some(true) {
+            return Ok(sequence_number);
+        }
+
+        self.entries.insert(sequence_number, entry);
+        self.bitfield.set(sequence_number, true);
+
+        if checkpoint.is_some() && self.checkpoint.is_none_or(|latest| latest < sequence_number) {
+            self.checkpoint = Some(sequence_number);
+        }
+
+        self.notify(sequence_number);
+
+        Ok(sequence_number)
+    }
+
+    /// Returns the proof of the log being forked, if we know any.
+    pub fn fork_proof(&self) -> Option<&ForkProof> {
+        self.fork.as_ref()
     }
 
-    /// Returns the current number of entries in the log.
+    /// Marks the log as forked when a peer proved it, returns true when we
+    /// did not know about it before.
+    pub fn add_fork_proof(&mut self, proof: ForkProof) -> Result<bool, LogError> {
+        if !proof.verify(&self.public_key) {
+            return Err(LogError::InvalidProof);
+        }
+
+        if self.fork.is_some() {
+            return Ok(false);
+        }
+
+        self.fork = Some(proof);
+        Ok(true)
+    }
+
+    /// Returns a stream of the sequence numbers of all entries appended or
+    /// inserted from now on.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<u64> {
+        let (tx, rx) = unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    fn notify(&mut self, sequence_number: u64) {
+        // Forget about subscribers which went away
+        self.subscribers.retain(|tx| tx.unbounded_send(sequence_number).is_ok());
+    }
+
+    /// Returns the entry with this sequence number encoded for other peers.
+    pub fn entry_bytes(&self, sequence_number: u64) -> Option<Vec<u8>> {
+        sequence_number
+            .checked_sub(1)
+            .and_then(|index| self.entry(index as usize))
+            .map(LogEntry::to_bytes)
+    }
+
+    /// Returns the sequence number of an entry encoded for other peers,
+    /// without checking its signature.
+    pub fn sequence_number(entry: &[u8]) -> Result<u64, LogError> {
+        LogEntry::from_bytes(entry).map(|entry| entry.content.sequence_number)
+    }
+
+    /// Returns which entries are present.
+    pub fn bitfield(&self) -> &Bitfield {
+        &self.bitfield
+    }
+
+    /// Returns the length of the log as far as we know it, which is the
+    /// highest sequence number we hold.
     pub fn len(&self) -> usize {
-        self.entries.len()
+        self.entries.keys().next_back().map_or(0, |sequence_number| *sequence_number as usize)
+    }
+
+    /// Returns the number of entries we hold.
+    pub fn count(&self) -> u64 {
+        self.bitfield.count()
     }
 
     /// Returns true if the log does not contain any entries.
@@ -122,25 +553,57 @@ impl Log {
 
     /// Returns the stored data at this position of the log.
     pub fn get(&self, index: usize) -> option::Option<Vec<u8>> {
-        self.entries.get(index).map(|entry| entry.content.data.clone())
+        self.entry(index).map(|entry| entry.content.data.clone())
     }
 
     /// Returns the hash of an entry of the log.
     pub fn hash(&self, index: usize) -> option::Option<u64> {
-        self.entries.get(index).map(|entry| generate_hash(entry))
+        self.entry(index).map(generate_hash)
     }
 
-    /// Checks if order of all entries and theire signatures are correct.
-    pub fn verify(&self, public_key: &PublicKey) -> bool {
-        let mut sequence_number = 1;
+    /// Returns all entries we hold, the oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Entry<'_>> {
+        self.entries.values().map(|entry| Entry { entry })
+    }
+
+    /// Returns the entries we hold at these positions, positions past the
+    /// end of the log are left out.
+    pub fn get_range(&self, range: Range<usize>) -> impl DoubleEndedIterator<Item = Entry<'_>> {
+        let start = (range.start as u64).saturating_add(1);
+        let end = (range.end as u64).saturating_add(1).max(start);
 
-        let has_invalid_entries = self.entries.iter().any(|entry| {
-            let hash_previous = entry.content.hash_previous.clone();
+        self.entries.range(start..end).map(|(_, entry)| Entry { entry })
+    }
+
+    /// Returns the newest entry we hold.
+    pub fn head(&self) -> Option<Entry<'_>> {
+        self.iter().next_back()
+    }
+
+    /// Returns the oldest entry we hold.
+    pub fn tail(&self) -> Option<Entry<'_>> {
+        self.iter().next()
+    }
+
+    /// Returns the entry with this hash, if we hold it.
+    pub fn find_by_hash(&self, hash: u64) -> Option<Entry<'_>> {
+        self.iter().find(|entry| entry.hash() == hash)
+    }
+
+    fn entry(&self, index: usize) -> Option<&LogEntry> {
+        self.entries.get(&(index as u64 + 1))
+    }
+
+    /// Checks if order of all entries and theire signatures are correct,
+    /// links are only checked between entries we both hold.
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        let has_invalid_entries = self.entries.iter().any(|(sequence_number, entry)| {
+            let index = (sequence_number - 1) as usize;
+            let hash_previous = entry.content.hash_previous;
 
             // Regenerate hashes pointing at the previous entries
             // and see if they are consistant with the log
-            if sequence_number > 1 {
-                let entry_previous = &self.entries[sequence_number - 2];
+            if let Some(entry_previous) = index.checked_sub(1).and_then(|index| self.entry(index)) {
                 let id_previous_check = generate_hash(entry_previous);
 
                 if id_previous_check != hash_previous {
@@ -149,12 +612,10 @@ impl Log {
             }
 
             // Check if the entries are numbered sequentially
-            if sequence_number as u64 != entry.content.sequence_number {
+            if *sequence_number != entry.content.sequence_number {
                 return true
             }
 
-            sequence_number += 1;
-
             // Verify signature, check if its invalid
             !entry.verify(&public_key)
         });
@@ -167,14 +628,16 @@ impl Log {
 mod log {
     use super::*;
 
+    use futures::Stream;
+
     #[test]
     fn get() {
         let mut log = Log::new();
 
         assert!(log.is_empty());
 
-        log.append(b"Hello, Test!");
-        log.append(b"1, 2, 3");
+        log.append(b"Hello, Test!").unwrap();
+        log.append(b"1, 2, 3").unwrap();
 
         assert_eq!(log.len(), 2);
         assert_eq!(log.is_empty(), false);
@@ -189,8 +652,8 @@ mod log {
         let mut log = Log::new();
         let mut log_same = Log::new();
 
-        log.append(b"Test");
-        log_same.append(b"Test");
+        log.append(b"Test").unwrap();
+        log_same.append(b"Test").unwrap();
 
         // Hashes should be different even with same contents
         // since the keypairs of the logs are different
@@ -209,16 +672,199 @@ mod log {
         );
     }
 
+    #[test]
+    fn iterates_entries() {
+        let mut log = Log::new();
+        assert!(log.head().is_none());
+
+        for index in 0..5 {
+            log.append(format!("Message {}", index).as_bytes()).unwrap();
+        }
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+
+        for sequence_number in &[2, 3, 5] {
+            copy.insert(&log.entry_bytes(*sequence_number).unwrap()).unwrap();
+        }
+
+        // Holes are skipped
+        let data: Vec<&[u8]> = copy.iter().map(|entry| entry.data()).collect();
+        assert_eq!(data, vec![&b"Message 1"[..], b"Message 2", b"Message 4"]);
+
+        let numbers: Vec<u64> = copy.get_range(2..10).rev().map(|entry| entry.sequence_number()).collect();
+        assert_eq!(numbers, vec![5, 3]);
+        assert_eq!(copy.get_range(8..10).count(), 0);
+
+        assert_eq!(copy.tail().map(|entry| entry.sequence_number()), Some(2));
+        assert_eq!(copy.head().map(|entry| entry.sequence_number()), Some(5));
+
+        // Metadata matches the one of the original entries
+        let entry = copy.find_by_hash(log.hash(2).unwrap()).unwrap();
+        assert_eq!(entry.sequence_number(), 3);
+        assert_eq!(entry.hash_previous(), log.hash(1).unwrap());
+        assert_eq!(entry.to_bytes(), log.entry_bytes(3).unwrap());
+        assert_eq!(entry.signature().to_bytes()[..], entry.to_bytes()[entry.to_bytes().len() - SIGNATURE_LENGTH..]);
+        assert!(copy.find_by_hash(log.hash(0).unwrap()).is_none());
+    }
+
     #[test]
     fn verify() {
         let mut log = Log::new();
-        let public_key = log.keypair.public;
+        let public_key = log.public_key;
         let wrong_keypair = crypto::generate_keypair();
 
-        log.append(b"Test");
-        log.append(b"1, 2, 3");
+        log.append(b"Test").unwrap();
+        log.append(b"1, 2, 3").unwrap();
 
         log.verify(&public_key);
         log.verify(&wrong_keypair.public);
     }
+
+    #[test]
+    fn replicates_with_holes() {
+        let mut log = Log::new();
+
+        for index in 0..5 {
+            log.append(format!("Message {}", index).as_bytes()).unwrap();
+        }
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        assert_eq!(copy.append(b"Test"), Err(LogError::ReadOnly));
+
+        assert_eq!(copy.insert(&log.entry_bytes(4).unwrap()), Ok(4));
+        assert_eq!(copy.insert(&log.entry_bytes(2).unwrap()), Ok(2));
+
+        assert_eq!(copy.len(), 4);
+        assert_eq!(copy.get(1), Some(b"Message 1".to_vec()));
+        assert_eq!(copy.get(2), None);
+        assert_eq!(copy.bitfield().ranges(), vec![2..3, 4..5]);
+        assert!(copy.verify(&log.public_key));
+
+        // Entries of other logs or in the wrong place are refused
+        let other = {
+            let mut other = Log::new();
+            other.append(b"Message 2").unwrap();
+            other.append(b"Message 3").unwrap();
+            other
+        };
+
+        assert_eq!(copy.insert(&other.entry_bytes(2).unwrap()), Err(LogError::InvalidSignature));
+        assert_eq!(copy.insert(&[1, 2, 3]), Err(LogError::InvalidEntry));
+
+        let mut forged = Log::remote(other.public_key()).unwrap();
+        forged.insert(&other.entry_bytes(2).unwrap()).unwrap();
+
+        let mut wrong_link = other.entry_bytes(1).unwrap();
+        wrong_link[0] = b'X';
+        assert_eq!(forged.insert(&wrong_link), Err(LogError::InvalidSignature));
+
+        // Sequence numbers far ahead would make us allocate for all before
+        let content = LogEntryContent::new(0, b"Test".to_vec(), MAX_SEQUENCE_NUMBER + 1);
+        let entry = LogEntry::sign(content, log.keypair.as_ref().unwrap()).to_bytes();
+        assert_eq!(copy.insert(&entry), Err(LogError::InvalidEntry));
+        assert_eq!(copy.len(), 4);
+    }
+
+    #[test]
+    fn notifies_subscribers() {
+        let mut log = Log::new();
+        let mut copy = Log::remote(log.public_key()).unwrap();
+
+        let appended = log.subscribe();
+        let inserted = copy.subscribe();
+
+        log.append(b"Test").unwrap();
+        log.append(b"1, 2, 3").unwrap();
+        copy.insert(&log.entry_bytes(2).unwrap()).unwrap();
+
+        // Already held entries are no news
+        copy.insert(&log.entry_bytes(2).unwrap()).unwrap();
+
+        drop(log);
+        drop(copy);
+
+        assert_eq!(appended.wait().collect::<Result<Vec<_>, _>>(), Ok(vec![1, 2]));
+        assert_eq!(inserted.wait().collect::<Result<Vec<_>, _>>(), Ok(vec![2]));
+    }
+
+    #[test]
+    fn detects_forks() {
+        let mut log = Log::new();
+        log.append(b"Test").unwrap();
+        log.append(b"1, 2, 3").unwrap();
+
+        // Owner signs another entry at the same place
+        let keypair = log.keypair.as_ref().unwrap();
+        let content = LogEntryContent::new(log.hash(0).unwrap(), b"Other".to_vec(), 2);
+        let other = LogEntry::sign(content, keypair).to_bytes();
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        copy.insert(&log.entry_bytes(2).unwrap()).unwrap();
+        assert!(copy.fork_proof().is_none());
+
+        assert_eq!(copy.insert(&other), Err(LogError::Conflict(2)));
+        let proof = copy.fork_proof().cloned().unwrap();
+        assert!(proof.verify(&log.public_key));
+
+        // Peers learn about the fork from the proof alone
+        let mut other_copy = Log::remote(log.public_key()).unwrap();
+        assert_eq!(other_copy.add_fork_proof(proof.clone()), Ok(true));
+        assert_eq!(other_copy.add_fork_proof(proof.clone()), Ok(false));
+
+        // Entries of the same branch prove nothing
+        let honest = ForkProof {
+            first: log.entry_bytes(1).unwrap(),
+            second: log.entry_bytes(2).unwrap(),
+        };
+
+        assert!(!honest.verify(&log.public_key));
+        assert_eq!(Log::remote(log.public_key()).unwrap().add_fork_proof(honest), Err(LogError::InvalidProof));
+
+        // Neither do entries of someone else
+        let other_log = Log::new();
+        assert!(!proof.verify(&other_log.public_key));
+    }
+
+    #[test]
+    fn compacts_to_checkpoints() {
+        let mut log = Log::new();
+
+        log.append(b"Test").unwrap();
+        log.append(b"1, 2, 3").unwrap();
+        assert_eq!(log.append_checkpoint(), Ok(3));
+        log.append(b"After").unwrap();
+
+        let (sequence_number, checkpoint) = log.latest_checkpoint().unwrap();
+        assert_eq!(sequence_number, 3);
+        assert_eq!(checkpoint, Checkpoint { length: 2, root: log.hash(1).unwrap() });
+
+        // Peers trusting the checkpoint start with it
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        copy.insert(&log.entry_bytes(3).unwrap()).unwrap();
+        copy.insert(&log.entry_bytes(4).unwrap()).unwrap();
+        assert_eq!(copy.latest_checkpoint(), Some((3, checkpoint)));
+
+        // ... and can still check older entries against it, even ones the
+        // owner signed later on
+        let content = LogEntryContent::new(log.hash(0).unwrap(), b"Forged".to_vec(), 2);
+        let forged = LogEntry::sign(content, log.keypair.as_ref().unwrap());
+        assert_eq!(copy.insert(&forged.to_bytes()), Err(LogError::Conflict(2)));
+        assert!(copy.fork_proof().is_some());
+
+        assert_eq!(copy.insert(&log.entry_bytes(2).unwrap()), Ok(2));
+        assert!(copy.verify(&log.public_key));
+
+        assert_eq!(log.compact(), 2);
+        assert_eq!(log.bitfield().ranges(), vec![3..5]);
+        assert_eq!(log.append(b"Later"), Ok(5));
+        assert!(log.verify(&log.public_key));
+
+        // Checkpoints lying about the log are refused
+        let mut liar = Log::new();
+        liar.append(b"Test").unwrap();
+        liar.append(&Checkpoint { length: 5, root: 0 }.to_bytes()).unwrap();
+
+        let mut copy = Log::remote(liar.public_key()).unwrap();
+        assert_eq!(copy.insert(&liar.entry_bytes(2).unwrap()), Err(LogError::InvalidEntry));
+    }
 }
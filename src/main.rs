@@ -1,78 +1,251 @@
 //! Local p2p chat program
 
+mod bitfield;
+mod command;
+mod connections;
+mod control;
 mod crypto;
 mod discovery;
+mod export;
 mod log;
+mod mux;
+mod network;
+mod node;
+mod payload;
+mod replication;
+mod seeder;
+mod shutdown;
+mod stdio;
+mod storage;
 mod ui;
+mod varint;
 
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 
 use futures::{Future, Stream};
 use tokio_core::reactor::{Core, Handle};
 
-use discovery::{DiscoveryStream, DiscoveryPeer};
-use ui::{UserInterface, ChatMessage};
+use command::{Commands, Output};
+use node::{Node, NodeEvent};
+use shutdown::{Shutdown, ShutdownReason};
+use ui::{UserInterface, UserInput, UiEvent, ChatMessage, MessageKind, PeerEntry, Status, Theme};
 
-const DISCOVERY_NAME: &[u8] = b"p2p-chat";
-const URL_PROTOCOL: &str = "chat://";
+type RunFuture = Box<dyn Future<Item = ShutdownReason, Error = ()>>;
+
+fn chat_message(node: &Node, event: NodeEvent) -> Option<ChatMessage> {
+    match event {
+        NodeEvent::Message(message) => {
+            let is_own = message.author == node.public_key();
+
+            let sender = if is_own && message.nickname.is_none() {
+                String::from("ME")
+            } else {
+                message.display_name()
+            };
+
+            let kind = if is_own {
+                MessageKind::Own
+            } else if node.nickname().is_some_and(|nickname| node::mentions(&message.text, &nickname)) {
+                MessageKind::Mention
+            } else {
+                MessageKind::Other
+            };
+
+            // Display actions sent with /me
+            let chat_message = match message.text.strip_prefix("/me ") {
+                Some(action) => ChatMessage::new(String::from("*"), format!("{} {}", sender, action)),
+                None => ChatMessage::new(sender, message.text.clone()),
+            };
+
+            Some(chat_message
+                .with_author(&message.author)
+                .with_kind(kind)
+                .with_timestamp(message.timestamp))
+        }
+        NodeEvent::PeerFound(peer) => Some(ChatMessage::from_string(format!(
+            "New peer: {}, {}, {}",
+            peer.addr(),
+            peer.port(),
+            peer.token()
+        ))),
+        NodeEvent::Nickname { author, nickname } => Some(ChatMessage::from_string(format!(
+            "{} is now known as {}",
+            node::fingerprint(&author),
+            nickname
+        ))),
+        // Only shown in the peer list
+        NodeEvent::PeerUpdated(_) => None,
+        NodeEvent::PeerLeft(token) => Some(ChatMessage::from_string(format!("Peer left: {}", token))),
+        NodeEvent::Info(text) => Some(ChatMessage::from_string(text)),
+    }
+}
+
+fn status(node: &Node) -> Status {
+    let sync = node.sync_state();
+
+    Status {
+        channel: format!("{}{}", node::URL_PROTOCOL, node::fingerprint(&node.channel_key())),
+        peers: node.peers().len(),
+        local_length: sync.local,
+        remote_length: sync.remote,
+        warning: node.warning(),
+    }
+}
+
+fn peer_entries(node: &Node) -> Vec<PeerEntry> {
+    node.peer_list()
+        .into_iter()
+        .map(|peer| {
+            let (name, progress) = match &peer.public_key {
+                Some(key) => {
+                    let (local, remote) = node.log_progress(key);
+                    let mut name = node::display_name(key, node.nickname_of(key).as_deref());
+
+                    if node.is_forked(key) {
+                        name.push_str(" [forked]");
+                    }
+
+                    (name, remote.map(|remote| (local, remote)))
+                }
+                None => (peer.discovery.token(), None),
+            };
+
+            PeerEntry {
+                name,
+                address: format!("{}:{}", peer.discovery.addr(), peer.discovery.port()),
+                connected: peer.connected,
+                latency: peer.latency,
+                progress,
+                last_seen: peer.last_seen,
+            }
+        })
+        .collect()
+}
 
 pub fn run(
     handle: Handle,
-    public_key: &[u8],
-) -> impl Future<Item = (), Error = ()> {
-    // Create user interface
-    let (ui, ui_tx) = UserInterface::new().expect("Failed to initialize the UI");
-
-    // Show channel address to the user
-    ui_tx.unbounded_send(
-        ChatMessage::from_string(
-            format!("{}{}", URL_PROTOCOL, hex::encode(public_key))
-        )).unwrap();
-
-    // @TODO Get correct port from listening TCP socket
-    let port = 12345;
-
-    // Discover peers which are interested in the same channel
-    let discovery_key = crypto::generate_discovery_key(&public_key, DISCOVERY_NAME);
-    let discovery_stream = DiscoveryStream::new(handle.clone(), &discovery_key.as_bytes(), port);
-
-    let handle_clone = handle.clone();
-    let ui_tx_clone = ui_tx.clone();
-
-    let mut peers: HashMap<String, DiscoveryPeer> = HashMap::new();
-
-    let discovery_future = discovery_stream.map(move |stream| {
-        let find_peers = stream.for_each(move |peer| {
-            if !peers.contains_key(&peer.token()) {
-                // @TODO Start replication protocol
-                let message = format!(
-                    "New peer: {}, {}, {}",
-                    peer.addr(),
-                    peer.port(),
-                    peer.token()
-                );
-
-                ui_tx_clone.unbounded_send(
-                    ChatMessage::from_string(message)).unwrap();
-
-                peers.insert(peer.token(), peer);
+    shutdown: Shutdown,
+    node: Node,
+    channel_key: &[u8],
+    theme: Theme,
+) -> RunFuture {
+    // Create user interface, remembering sent messages per channel
+    let history_path = storage::channel_dir(channel_key).join("history");
+
+    let (ui, ui_tx) = match UserInterface::new(handle.clone(), Some(history_path), theme) {
+        Ok(ui) => ui,
+        Err(err) => {
+            shutdown.trigger(ShutdownReason::Error(
+                format!("Could not initialize the UI. {}", err)));
+
+            return Box::new(shutdown.wait());
+        }
+    };
+
+    // Display everything happening in the node
+    let node_events = node.clone();
+    let ui_tx_events = ui_tx.clone();
+
+    let events = node.subscribe().for_each(move |event| {
+        if let Some(message) = chat_message(&node_events, event) {
+            ui_tx_events.unbounded_send(UiEvent::Message(message)).map_err(|_| ())?;
+        }
+
+        // Every event might change what the status bar and peer list show
+        ui_tx_events.unbounded_send(UiEvent::Status(status(&node_events))).map_err(|_| ())?;
+        ui_tx_events.unbounded_send(UiEvent::Peers(peer_entries(&node_events))).map_err(|_| ())
+    });
+
+    handle.spawn(events);
+
+    let commands = Commands::with_defaults();
+    let mut channel_key = channel_key.to_vec();
+    let shutdown_ui = shutdown.clone();
+    let shutdown_commands = shutdown.clone();
+
+    let ui_future = ui
+        .for_each(move |input| {
+            let line = match input {
+                UserInput::Line(line) => line,
+                UserInput::ScrolledToTop => {
+                    node.fetch_older();
+                    return Ok(());
+                }
+            };
+
+            if let Some(text) = Commands::as_message(&line) {
+                node.send_message(text);
+                return Ok(());
             }
 
+            for output in commands.execute(&node, &line) {
+                match output {
+                    Output::Info(text) => {
+                        let message = ChatMessage::from_string(text);
+                        let _ = ui_tx.unbounded_send(UiEvent::Message(message));
+                    }
+                    Output::Message(text) => {
+                        node.send_message(&text);
+                    }
+                    Output::Clear => {
+                        let _ = ui_tx.unbounded_send(UiEvent::Clear);
+                    }
+                    Output::Quit => shutdown_commands.trigger(ShutdownReason::Exit),
+                }
+            }
+
+            // Commands might have joined another channel
+            if node.channel_key() != channel_key {
+                channel_key = node.channel_key();
+                let history_path = storage::channel_dir(&channel_key).join("history");
+                let _ = ui_tx.unbounded_send(UiEvent::History(history_path));
+            }
+
+            Ok(())
+        })
+        .then(move |result| -> Result<(), ()> {
+            let reason = match result {
+                Ok(()) => ShutdownReason::Exit,
+                Err(err) => ShutdownReason::Error(format!("UI exited with error. {}", err)),
+            };
+
+            shutdown_ui.trigger(reason);
             Ok(())
         });
 
-        handle_clone.spawn(find_peers.then(|_| Ok(())));
-    })
-    .map_err(|err| {
-        panic!("Error: Could not start discovery stream. {:?}", err)
-    });
+    // Keep the UI running until any component requested shutdown
+    Box::new(
+        ui_future
+            .select2(shutdown.wait())
+            .then(move |_| shutdown.wait()))
+}
 
-    handle.spawn(discovery_future);
+pub fn run_headless(
+    handle: Handle,
+    shutdown: Shutdown,
+    node: Node,
+) -> RunFuture {
+    // Report what is happening, the channel URL goes to stdout so it can be
+    // picked up by scripts
+    let events = node.subscribe().for_each(|event| {
+        match event {
+            NodeEvent::Info(text) => println!("{}", text),
+            NodeEvent::PeerFound(peer) => eprintln!(
+                "New peer: {}, {}, {}", peer.addr(), peer.port(), peer.token()),
+            NodeEvent::PeerLeft(token) => eprintln!("Peer left: {}", token),
+            NodeEvent::Nickname { author, nickname } => eprintln!(
+                "{} is now known as {}", node::fingerprint(&author), nickname),
+            NodeEvent::Message(_) | NodeEvent::PeerUpdated(_) => {},
+        }
 
-    ui.for_each(move |text| {
-        ui_tx.unbounded_send(ChatMessage::new(String::from("ME"), text)).unwrap();
         Ok(())
-    }).map_err(|e| panic!("UI exited with error: {:?}", e)).then(|_| Ok(()))
+    });
+
+    handle.spawn(events);
+
+    Box::new(shutdown.wait())
 }
 
 fn main() {
@@ -81,35 +254,165 @@ fn main() {
 
     let mut opts = getopts::Options::new();
     opts.optopt("c", "channel", "join chat channel with this URL", "<link>");
+    opts.optflag("", "headless", "run without terminal UI, controlled via socket");
+    opts.optopt("s", "socket", "path of the control socket", "<path>");
+    opts.optflag("", "stdio", "read messages from stdin and write them to stdout");
+    opts.optflag("", "json", "write messages as JSON lines in stdio mode");
+    opts.optopt("", "theme", &format!("colours of the UI: {}", ui::THEME_NAMES.join(", ")), "<name>");
+    opts.optopt("", "max-inbound", "accept at most this many connections from peers", "<count>");
+    opts.optopt("", "max-outbound", "dial at most this many peers at once", "<count>");
+    opts.optopt("", "ping-interval", "ping connected peers this often", "<seconds>");
+    opts.optopt("", "ping-timeout", "drop peers silent for this long", "<seconds>");
+    opts.optopt("", "seed", "store and serve the channels configured in this file", "<path>");
 
-    // Generate public and secret keypair
-    let keypair = crypto::generate_keypair();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(err) => {
+            eprintln!("p2p-chat: {}\n\n{}", err, opts.usage("Usage: p2p-chat [options]"));
+            process::exit(2);
+        }
+    };
 
-    // Create new channel or join existing one depending on given arguments
-    let matches = opts.parse(&args[1..]).unwrap();
-    let is_channel_given = matches.opt_present("channel");
+    let is_headless = matches.opt_present("headless");
+    let is_stdio = matches.opt_present("stdio");
 
-    // Prepare chat:// URL with public key
-    let decoded_key;
+    if is_headless && is_stdio {
+        eprintln!("p2p-chat: --headless and --stdio can not be used together");
+        process::exit(2);
+    }
 
-    let public_key: &[u8] = if is_channel_given {
-        let channel_public_key = matches
-            .opt_str("channel")
-            .unwrap()
-            .replace(URL_PROTOCOL, "");
+    // Colours are turned off with NO_COLOR or on terminals without colours
+    let theme = match Theme::from_env(matches.opt_str("theme").as_deref()) {
+        Ok(theme) => theme,
+        Err(err) => {
+            eprintln!("p2p-chat: {}", err);
+            process::exit(2);
+        }
+    };
 
-        decoded_key = hex::decode(channel_public_key).unwrap();
-        &decoded_key
-    } else {
-        keypair.public.as_bytes()
+    // Seeders run on their own, without frontend and own channel
+    if let Some(path) = matches.opt_str("seed") {
+        if is_headless || is_stdio || matches.opt_present("channel") {
+            eprintln!("p2p-chat: --seed can not be used with --headless, --stdio or --channel");
+            process::exit(2);
+        }
+
+        let config = match seeder::Config::load(Path::new(&path)) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("p2p-chat: {}", err);
+                process::exit(2);
+            }
+        };
+
+        let core = Core::new().unwrap();
+        let shutdown = Shutdown::new();
+        shutdown.listen_signals(&core.handle());
+
+        let main = match seeder::run(core.handle(), shutdown.clone(), config) {
+            Ok(main) => main,
+            Err(err) => {
+                eprintln!("p2p-chat: Could not load stored logs. {}", err);
+                process::exit(1);
+            }
+        };
+
+        run_until_shutdown(core, shutdown, Box::new(main));
+    }
+
+    let mut config = connections::Config::default();
+
+    for (name, limit) in &mut [("max-inbound", &mut config.max_inbound), ("max-outbound", &mut config.max_outbound)] {
+        match matches.opt_str(name).map(|count| count.parse()) {
+            Some(Ok(count)) => **limit = count,
+            Some(Err(_)) => {
+                eprintln!("p2p-chat: --{} needs to be a number", name);
+                process::exit(2);
+            }
+            None => {}
+        }
+    }
+
+    for (name, duration) in &mut [("ping-interval", &mut config.ping_interval), ("ping-timeout", &mut config.ping_timeout)] {
+        match matches.opt_str(name).map(|seconds| seconds.parse()) {
+            Some(Ok(seconds)) if seconds > 0 => **duration = Duration::from_secs(seconds),
+            Some(_) => {
+                eprintln!("p2p-chat: --{} needs to be a positive number of seconds", name);
+                process::exit(2);
+            }
+            None => {}
+        }
+    }
+
+    // Join existing channel when given
+    let channel_given = match matches.opt_str("channel").map(|url| node::parse_channel_url(&url)) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(err)) => {
+            eprintln!("p2p-chat: {}", err);
+            process::exit(2);
+        }
+        None => None,
     };
 
     // Create event loop to drive the networking I/O
-    let mut core = Core::new().unwrap();
+    let core = Core::new().unwrap();
+
+    // Coordinate shutdown on signals, user exit and errors
+    let shutdown = Shutdown::new();
+    shutdown.listen_signals(&core.handle());
+
+    // Create a new chat node
+    let node = Node::with_config(core.handle(), shutdown.clone(), config);
+
+    // ... or create a new channel with our own key
+    let channel_key = channel_given.unwrap_or_else(|| node.public_key());
+
+    // Expose local control API, always available when running headless
+    let socket_path = match matches.opt_str("socket") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if is_headless => Some(control::default_socket_path()),
+        None => None,
+    };
+
+    if let Some(path) = socket_path {
+        if let Err(err) = control::serve(core.handle(), shutdown.clone(), node.clone(), &path) {
+            eprintln!("p2p-chat: Could not open control socket. {}", err);
+            process::exit(1);
+        }
+    }
+
+    // Start frontend first so it does not miss any events
+    let main = if is_headless {
+        run_headless(core.handle(), shutdown.clone(), node.clone())
+    } else if is_stdio {
+        let format = if matches.opt_present("json") {
+            stdio::Format::Json
+        } else {
+            stdio::Format::Plain
+        };
+
+        Box::new(stdio::run(core.handle(), shutdown.clone(), node.clone(), format))
+    } else {
+        run(core.handle(), shutdown.clone(), node.clone(), &channel_key, theme)
+    };
+
+    node.join(&channel_key);
+
+    run_until_shutdown(core, shutdown, main);
+}
+
+fn run_until_shutdown(mut core: Core, shutdown: Shutdown, main: RunFuture) -> ! {
+    // Run the frontend in the event loop, the UI is dropped (and with it the
+    // terminal restored) as soon as this returns
+    let reason = core.run(main).unwrap_or_else(|_| {
+        ShutdownReason::Error(String::from("Event loop failed"))
+    });
+
+    shutdown.run_hooks();
 
-    // Create a new chat instance
-    let main = run(core.handle(), public_key);
+    if let ShutdownReason::Error(_) = reason {
+        eprintln!("p2p-chat: {}", reason);
+    }
 
-    // ... and add it to event loop
-    core.run(main).unwrap();
+    process::exit(reason.exit_code());
 }
@@ -0,0 +1,684 @@
+//! Several channels multiplexed over one peer connection
+//!
+//! Every frame starts with its length and the id the sending side gave the
+//! channel, followed by a byte naming its type. Channels are opened with
+//! their discovery key and carry messages once both sides opened them.
+//! Each side may only send a window of bytes per channel before the other
+//! side grants more credit, and queued messages of all channels are sent
+//! in turns, so a long history sync on one channel does not hold back live
+//! messages on another. Pings belong to the connection as a whole and are
+//! answered right away.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::varint;
+
+const TYPE_OPEN: u8 = 0;
+const TYPE_CLOSE: u8 = 1;
+const TYPE_DATA: u8 = 2;
+const TYPE_CREDIT: u8 = 3;
+const TYPE_PING: u8 = 4;
+const TYPE_PONG: u8 = 5;
+
+pub const DISCOVERY_KEY_LENGTH: usize = 32;
+
+/// Bytes of messages a channel may send before the other side grants more.
+pub const WINDOW_SIZE: u64 = 256 * 1024;
+
+/// Largest message sent on a channel.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// Channel id, type and length take a few bytes on top of the message
+const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE + 32;
+
+// Received bytes after which credit is granted back, always reached before
+// the sender runs out of it
+const CREDIT_THRESHOLD: u64 = WINDOW_SIZE / 4;
+
+// Channels the other side can open at most
+const MAX_CHANNELS: usize = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum MuxError {
+    InvalidFrame,
+    FrameTooLarge,
+    MessageTooLarge,
+    TooManyChannels,
+
+    /// Channel was not opened by both sides
+    NotOpen,
+
+    /// Other side used a channel id it did not open
+    UnknownChannel(u64),
+
+    /// Other side opened the same channel id twice
+    DuplicateChannel(u64),
+
+    /// Other side sent more than its credit allowed
+    FlowControl(u64),
+}
+
+impl fmt::Display for MuxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MuxError::InvalidFrame => write!(f, "frame could not be decoded"),
+            MuxError::FrameTooLarge => write!(f, "frame exceeds the maximum size"),
+            MuxError::MessageTooLarge => write!(f, "message exceeds the maximum size"),
+            MuxError::TooManyChannels => write!(f, "too many channels opened"),
+            MuxError::NotOpen => write!(f, "channel is not open"),
+            MuxError::UnknownChannel(id) => write!(f, "unknown channel {}", id),
+            MuxError::DuplicateChannel(id) => write!(f, "channel {} opened twice", id),
+            MuxError::FlowControl(id) => write!(f, "channel {} exceeded its credit", id),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Open { channel: u64, discovery_key: Vec<u8> },
+    Close { channel: u64 },
+    Data { channel: u64, message: Vec<u8> },
+    Credit { channel: u64, amount: u64 },
+
+    // Not part of any channel, the id is always 0
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+}
+
+impl Frame {
+    // Encodes the frame including its length
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        match self {
+            Frame::Open { channel, discovery_key } => {
+                varint::write(&mut body, *channel);
+                body.push(TYPE_OPEN);
+                body.extend_from_slice(discovery_key);
+            }
+            Frame::Close { channel } => {
+                varint::write(&mut body, *channel);
+                body.push(TYPE_CLOSE);
+            }
+            Frame::Data { channel, message } => {
+                varint::write(&mut body, *channel);
+                body.push(TYPE_DATA);
+                body.extend_from_slice(message);
+            }
+            Frame::Credit { channel, amount } => {
+                varint::write(&mut body, *channel);
+                body.push(TYPE_CREDIT);
+                varint::write(&mut body, *amount);
+            }
+            Frame::Ping { nonce } => {
+                varint::write(&mut body, 0);
+                body.push(TYPE_PING);
+                varint::write(&mut body, *nonce);
+            }
+            Frame::Pong { nonce } => {
+                varint::write(&mut body, 0);
+                body.push(TYPE_PONG);
+                varint::write(&mut body, *nonce);
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(body.len() + 3);
+        varint::write(&mut bytes, body.len() as u64);
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    // Decodes the frame without its length
+    fn from_bytes(body: &[u8]) -> Result<Self, MuxError> {
+        let mut body = body;
+        let channel = varint::read(&mut body).ok_or(MuxError::InvalidFrame)?;
+        let (kind, data) = body.split_first().ok_or(MuxError::InvalidFrame)?;
+
+        match *kind {
+            TYPE_OPEN if data.len() == DISCOVERY_KEY_LENGTH => {
+                Ok(Frame::Open { channel, discovery_key: data.to_vec() })
+            }
+            TYPE_CLOSE if data.is_empty() => Ok(Frame::Close { channel }),
+            TYPE_DATA => Ok(Frame::Data { channel, message: data.to_vec() }),
+            TYPE_CREDIT => Ok(Frame::Credit { channel, amount: read_number(data)? }),
+            TYPE_PING => Ok(Frame::Ping { nonce: read_number(data)? }),
+            TYPE_PONG => Ok(Frame::Pong { nonce: read_number(data)? }),
+            _ => Err(MuxError::InvalidFrame),
+        }
+    }
+}
+
+// Reads a frame body consisting of a single number
+fn read_number(data: &[u8]) -> Result<u64, MuxError> {
+    let mut data = data;
+    let number = varint::read(&mut data).ok_or(MuxError::InvalidFrame)?;
+
+    if !data.is_empty() {
+        return Err(MuxError::InvalidFrame);
+    }
+
+    Ok(number)
+}
+
+/// Things happening on channels of the connection.
+#[derive(Debug, PartialEq)]
+pub enum MuxEvent {
+    /// Channel with this discovery key is open on both sides
+    Opened(Vec<u8>),
+
+    /// Other side closed the channel
+    Closed(Vec<u8>),
+
+    /// Message received on the channel
+    Message(Vec<u8>, Vec<u8>),
+
+    /// Other side answered the ping with this nonce
+    Pong(u64),
+}
+
+struct Channel {
+    // Ids both sides gave the channel when opening it
+    local_id: Option<u64>,
+    remote_id: Option<u64>,
+
+    // Bytes we may still send
+    credit: u64,
+    queue: VecDeque<Vec<u8>>,
+
+    // Received bytes the other side cannot send again until our credit
+    // reaches it, and the part of them we did not grant back so far
+    outstanding: u64,
+    ungranted: u64,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            local_id: None,
+            remote_id: None,
+            credit: WINDOW_SIZE,
+            queue: VecDeque::new(),
+            outstanding: 0,
+            ungranted: 0,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.local_id.is_some() && self.remote_id.is_some()
+    }
+}
+
+/// Multiplexer of one connection, turning received bytes into events and
+/// queued messages into bytes to send.
+#[derive(Default)]
+pub struct Mux {
+    // Channels by discovery key
+    channels: HashMap<Vec<u8>, Channel>,
+
+    // Discovery keys by the ids the other side gave them
+    remote_ids: HashMap<u64, Vec<u8>>,
+
+    next_id: u64,
+
+    // Channel id after which sending continues next time
+    turn: u64,
+
+    // Frames which are sent before any messages
+    control: Vec<Frame>,
+
+    // Received bytes not forming a whole frame yet
+    buffer: Vec<u8>,
+}
+
+impl Mux {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a channel, returns true when the other side already opened it.
+    pub fn open(&mut self, discovery_key: &[u8]) -> bool {
+        let channel = self.channels.entry(discovery_key.to_vec()).or_insert_with(Channel::new);
+
+        if channel.local_id.is_none() {
+            // Credit of a channel we closed before starts over, like on the
+            // other side
+            *channel = Channel { remote_id: channel.remote_id, ..Channel::new() };
+            channel.local_id = Some(self.next_id);
+
+            self.control.push(Frame::Open {
+                channel: self.next_id,
+                discovery_key: discovery_key.to_vec(),
+            });
+
+            self.next_id += 1;
+        }
+
+        channel.is_open()
+    }
+
+    /// Closes a channel, dropping the messages which were not sent yet.
+    pub fn close(&mut self, discovery_key: &[u8]) {
+        let remove = match self.channels.get_mut(discovery_key) {
+            Some(channel) => {
+                if let Some(id) = channel.local_id.take() {
+                    self.control.push(Frame::Close { channel: id });
+                }
+
+                channel.queue.clear();
+                channel.remote_id.is_none()
+            }
+            None => false,
+        };
+
+        if remove {
+            self.channels.remove(discovery_key);
+        }
+    }
+
+    /// Returns true when the channel is open on both sides.
+    pub fn is_open(&self, discovery_key: &[u8]) -> bool {
+        self.channels.get(discovery_key).is_some_and(Channel::is_open)
+    }
+
+    /// Asks the other side to answer with a pong carrying the same nonce.
+    pub fn ping(&mut self, nonce: u64) {
+        self.control.push(Frame::Ping { nonce });
+    }
+
+    /// Queues a message on an open channel.
+    pub fn send(&mut self, discovery_key: &[u8], message: Vec<u8>) -> Result<(), MuxError> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(MuxError::MessageTooLarge);
+        }
+
+        match self.channels.get_mut(discovery_key) {
+            Some(channel) if channel.is_open() => {
+                channel.queue.push_back(message);
+                Ok(())
+            }
+            _ => Err(MuxError::NotOpen),
+        }
+    }
+
+    /// Returns true when there is something to send which credit allows.
+    pub fn has_output(&self) -> bool {
+        !self.control.is_empty() || self.channels.values().any(|channel| {
+            channel.is_open() && channel.queue.front()
+                .is_some_and(|message| message.len() as u64 <= channel.credit)
+        })
+    }
+
+    /// Returns bytes to send, about as many as the limit. Channels take
+    /// turns with one message each.
+    pub fn take_output(&mut self, limit: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        for frame in self.control.drain(..) {
+            // The other side can use granted credit once it is on its way
+            if let Frame::Credit { channel: id, amount } = frame {
+                let channel = self.channels.values_mut().find(|channel| channel.local_id == Some(id));
+
+                if let Some(channel) = channel {
+                    channel.outstanding -= amount;
+                }
+            }
+
+            bytes.extend_from_slice(&frame.to_bytes());
+        }
+
+        let mut ids: Vec<(u64, Vec<u8>)> = self.channels
+            .iter()
+            .filter(|(_, channel)| channel.is_open())
+            .map(|(discovery_key, channel)| (channel.local_id.unwrap(), discovery_key.clone()))
+            .collect();
+
+        // Continue with the channel after the one which sent last
+        ids.sort();
+        let split = ids.iter().position(|(id, _)| *id > self.turn).unwrap_or(0);
+        ids.rotate_left(split);
+
+        while bytes.len() < limit {
+            let mut sent = false;
+
+            for (id, discovery_key) in &ids {
+                if bytes.len() >= limit {
+                    break;
+                }
+
+                let channel = self.channels.get_mut(discovery_key).unwrap();
+
+                let fits = channel.queue.front()
+                    .is_some_and(|message| message.len() as u64 <= channel.credit);
+
+                if !fits {
+                    continue;
+                }
+
+                let message = channel.queue.pop_front().unwrap();
+                channel.credit -= message.len() as u64;
+
+                bytes.extend_from_slice(&Frame::Data { channel: *id, message }.to_bytes());
+                self.turn = *id;
+                sent = true;
+            }
+
+            if !sent {
+                break;
+            }
+        }
+
+        bytes
+    }
+
+    /// Handles bytes received from the other side. Errors are violations
+    /// of the protocol after which the connection should be dropped.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<Vec<MuxEvent>, MuxError> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+
+        loop {
+            let mut rest = &self.buffer[..];
+
+            let length = match varint::read(&mut rest) {
+                Some(length) => length as usize,
+                None if self.buffer.len() > 10 => return Err(MuxError::InvalidFrame),
+                None => break,
+            };
+
+            if length > MAX_FRAME_SIZE {
+                return Err(MuxError::FrameTooLarge);
+            }
+
+            if rest.len() < length {
+                break;
+            }
+
+            let frame = Frame::from_bytes(&rest[..length])?;
+            let consumed = self.buffer.len() - rest.len() + length;
+            self.buffer.drain(..consumed);
+
+            if let Some(event) = self.handle(frame)? {
+                events.push(event);
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<Option<MuxEvent>, MuxError> {
+        match frame {
+            Frame::Open { channel: id, discovery_key } => {
+                if self.remote_ids.contains_key(&id) {
+                    return Err(MuxError::DuplicateChannel(id));
+                }
+
+                if self.remote_ids.len() >= MAX_CHANNELS {
+                    return Err(MuxError::TooManyChannels);
+                }
+
+                let channel = self.channels.entry(discovery_key.clone()).or_insert_with(Channel::new);
+
+                if channel.remote_id.is_some() {
+                    return Err(MuxError::DuplicateChannel(id));
+                }
+
+                channel.remote_id = Some(id);
+                self.remote_ids.insert(id, discovery_key.clone());
+
+                if channel.is_open() {
+                    return Ok(Some(MuxEvent::Opened(discovery_key)));
+                }
+            }
+            Frame::Close { channel: id } => {
+                let discovery_key = self.remote_ids.remove(&id).ok_or(MuxError::UnknownChannel(id))?;
+                let channel = self.channels.remove(&discovery_key).unwrap();
+
+                // Keep our side open, the other side might open it again
+                if let Some(local_id) = channel.local_id {
+                    // Credit for the closed channel would count against the
+                    // new one
+                    self.control.retain(|frame| {
+                        !matches!(frame, Frame::Credit { channel, .. } if *channel == local_id)
+                    });
+
+                    let mut reopened = Channel::new();
+                    reopened.local_id = Some(local_id);
+                    self.channels.insert(discovery_key.clone(), reopened);
+                    return Ok(Some(MuxEvent::Closed(discovery_key)));
+                }
+            }
+            Frame::Data { channel: id, message } => {
+                let discovery_key = self.remote_ids.get(&id).ok_or(MuxError::UnknownChannel(id))?;
+                let channel = self.channels.get_mut(discovery_key).unwrap();
+
+                // Messages can still arrive after we closed the channel
+                let local_id = match channel.local_id {
+                    Some(local_id) => local_id,
+                    None => return Ok(None),
+                };
+
+                let length = message.len() as u64;
+                channel.outstanding += length;
+                channel.ungranted += length;
+
+                if channel.outstanding > WINDOW_SIZE {
+                    return Err(MuxError::FlowControl(id));
+                }
+
+                if channel.ungranted >= CREDIT_THRESHOLD {
+                    self.control.push(Frame::Credit { channel: local_id, amount: channel.ungranted });
+                    channel.ungranted = 0;
+                }
+
+                return Ok(Some(MuxEvent::Message(discovery_key.clone(), message)));
+            }
+            Frame::Credit { channel: id, amount } => {
+                let discovery_key = self.remote_ids.get(&id).ok_or(MuxError::UnknownChannel(id))?;
+                let channel = self.channels.get_mut(discovery_key).unwrap();
+
+                channel.credit = channel.credit.saturating_add(amount);
+
+                if channel.credit > WINDOW_SIZE {
+                    return Err(MuxError::FlowControl(id));
+                }
+            }
+            Frame::Ping { nonce } => {
+                // Only the latest ping needs an answer, a peer pinging faster
+                // than we send keeps replacing it
+                match self.control.iter_mut().find(|frame| matches!(frame, Frame::Pong { .. })) {
+                    Some(pong) => *pong = Frame::Pong { nonce },
+                    None => self.control.push(Frame::Pong { nonce }),
+                }
+            }
+            Frame::Pong { nonce } => return Ok(Some(MuxEvent::Pong(nonce))),
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod mux {
+    use super::*;
+
+    const HISTORY: &[u8] = &[1; DISCOVERY_KEY_LENGTH];
+    const LIVE: &[u8] = &[2; DISCOVERY_KEY_LENGTH];
+
+    fn deliver(from: &mut Mux, to: &mut Mux, limit: usize) -> Vec<MuxEvent> {
+        to.receive(&from.take_output(limit)).unwrap()
+    }
+
+    fn connected() -> (Mux, Mux) {
+        let mut a = Mux::new();
+        let mut b = Mux::new();
+
+        assert!(!a.open(HISTORY));
+        assert!(!a.open(LIVE));
+        assert!(!b.open(LIVE));
+
+        assert_eq!(deliver(&mut a, &mut b, usize::MAX), vec![MuxEvent::Opened(LIVE.to_vec())]);
+
+        assert!(b.open(HISTORY));
+        assert_eq!(deliver(&mut b, &mut a, usize::MAX), vec![
+            MuxEvent::Opened(LIVE.to_vec()),
+            MuxEvent::Opened(HISTORY.to_vec()),
+        ]);
+
+        (a, b)
+    }
+
+    #[test]
+    fn encode_decode() {
+        let frames = vec![
+            Frame::Open { channel: 300, discovery_key: LIVE.to_vec() },
+            Frame::Close { channel: 0 },
+            Frame::Data { channel: 1, message: b"Hello".to_vec() },
+            Frame::Credit { channel: 2, amount: WINDOW_SIZE },
+            Frame::Ping { nonce: 7 },
+            Frame::Pong { nonce: 7 },
+        ];
+
+        for frame in frames {
+            let bytes = frame.to_bytes();
+            assert_eq!(Frame::from_bytes(&bytes[1..]), Ok(frame));
+        }
+
+        assert_eq!(Frame::from_bytes(&[0, TYPE_OPEN, 1, 2]), Err(MuxError::InvalidFrame));
+        assert_eq!(Frame::from_bytes(&[0, 9]), Err(MuxError::InvalidFrame));
+    }
+
+    #[test]
+    fn answers_pings() {
+        let (mut a, mut b) = connected();
+
+        a.ping(42);
+        assert!(deliver(&mut a, &mut b, usize::MAX).is_empty());
+        assert_eq!(deliver(&mut b, &mut a, usize::MAX), vec![MuxEvent::Pong(42)]);
+
+        // Pings arriving before we could answer get one pong
+        for nonce in 0..1000 {
+            a.ping(nonce);
+        }
+
+        assert!(deliver(&mut a, &mut b, usize::MAX).is_empty());
+        assert_eq!(deliver(&mut b, &mut a, usize::MAX), vec![MuxEvent::Pong(999)]);
+    }
+
+    #[test]
+    fn live_messages_overtake_history() {
+        let (mut a, mut b) = connected();
+
+        for _ in 0..1000 {
+            a.send(HISTORY, vec![0; 1024]).unwrap();
+        }
+
+        a.send(LIVE, b"Hello".to_vec()).unwrap();
+
+        // Received bytes can be split anywhere
+        let bytes = a.take_output(8 * 1024);
+        let (first, second) = bytes.split_at(100);
+
+        let mut events = b.receive(first).unwrap();
+        events.extend(b.receive(second).unwrap());
+
+        assert!(events.contains(&MuxEvent::Message(LIVE.to_vec(), b"Hello".to_vec())));
+        assert!(events.len() < 10);
+    }
+
+    #[test]
+    fn waits_for_credit() {
+        let (mut a, mut b) = connected();
+
+        for _ in 0..1000 {
+            a.send(HISTORY, vec![0; 1024]).unwrap();
+        }
+
+        let mut received = 0;
+
+        // Without hearing back only one window gets through
+        while a.has_output() {
+            received += deliver(&mut a, &mut b, usize::MAX).len();
+        }
+
+        assert_eq!(received as u64, WINDOW_SIZE / 1024);
+
+        while a.has_output() || b.has_output() {
+            deliver(&mut b, &mut a, usize::MAX);
+            received += deliver(&mut a, &mut b, usize::MAX).len();
+        }
+
+        assert_eq!(received, 1000);
+    }
+
+    #[test]
+    fn forgets_credit_of_closed_channels() {
+        let (_, mut b) = connected();
+        let mut bytes = Vec::new();
+
+        for _ in 0..(CREDIT_THRESHOLD / MAX_MESSAGE_SIZE as u64 + 1) {
+            bytes.extend_from_slice(&Frame::Data { channel: 0, message: vec![0; MAX_MESSAGE_SIZE] }.to_bytes());
+        }
+
+        bytes.extend_from_slice(&Frame::Close { channel: 0 }.to_bytes());
+        b.receive(&bytes).unwrap();
+
+        // No credit is sent for the closed channel
+        assert!(b.take_output(usize::MAX).is_empty());
+        assert!(!b.is_open(HISTORY));
+    }
+
+    #[test]
+    fn resets_credit_of_reopened_channels() {
+        let (mut a, mut b) = connected();
+        let messages = WINDOW_SIZE / MAX_MESSAGE_SIZE as u64;
+
+        for _ in 0..messages {
+            a.send(HISTORY, vec![0; MAX_MESSAGE_SIZE]).unwrap();
+        }
+
+        assert_eq!(deliver(&mut a, &mut b, usize::MAX).len() as u64, messages);
+
+        a.close(HISTORY);
+        assert!(a.open(HISTORY));
+        deliver(&mut a, &mut b, usize::MAX);
+        deliver(&mut b, &mut a, usize::MAX);
+
+        // The full window is available again
+        for _ in 0..messages {
+            a.send(HISTORY, vec![0; MAX_MESSAGE_SIZE]).unwrap();
+        }
+
+        assert_eq!(deliver(&mut a, &mut b, usize::MAX).len() as u64, messages);
+        assert!(!a.has_output());
+    }
+
+    #[test]
+    fn detects_violations() {
+        let (_, mut b) = connected();
+
+        let unknown = Frame::Data { channel: 7, message: Vec::new() }.to_bytes();
+        assert_eq!(b.receive(&unknown), Err(MuxError::UnknownChannel(7)));
+
+        let (_, mut b) = connected();
+        let message = Frame::Data { channel: 0, message: vec![0; MAX_MESSAGE_SIZE] }.to_bytes();
+
+        for _ in 0..(WINDOW_SIZE / MAX_MESSAGE_SIZE as u64) {
+            b.receive(&message).unwrap();
+        }
+
+        // More is allowed only after credit was sent back
+        b.take_output(usize::MAX);
+
+        for _ in 0..(WINDOW_SIZE / MAX_MESSAGE_SIZE as u64) {
+            b.receive(&message).unwrap();
+        }
+
+        assert_eq!(b.receive(&message), Err(MuxError::FlowControl(0)));
+
+        let (_, mut b) = connected();
+        let open = Frame::Open { channel: 0, discovery_key: LIVE.to_vec() }.to_bytes();
+        assert_eq!(b.receive(&open), Err(MuxError::DuplicateChannel(0)));
+    }
+}
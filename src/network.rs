@@ -0,0 +1,881 @@
+//! TCP connections to peers, each carrying multiplexed channels
+//!
+//! Both sides start a connection with a handshake naming their discovery
+//! token, public key and a random nonce. Each side proves it holds the
+//! secret key by signing the nonce of the other, then both exchange
+//! multiplexed frames. Which peers get
+//! dialed and which connections are kept is up to the connection manager.
+//! Every connection pings its peer regularly to measure the round-trip time
+//! and is closed when the peer stays silent for too long.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str;
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::{Keypair, PublicKey, Signature, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{future, Async, Future, Poll, Stream};
+use tokio::timer::Interval;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+
+use crate::connections::{Config, ConnectionId, ConnectionManager};
+use crate::crypto;
+use crate::mux::{Mux, MuxEvent};
+use crate::varint;
+
+// Milliseconds between checks for peers to dial
+const DIAL_INTERVAL: u64 = 500;
+
+const READ_SIZE: usize = 16 * 1024;
+const WRITE_SIZE: usize = 64 * 1024;
+
+const MAX_TOKEN_LENGTH: usize = 64;
+const NONCE_LENGTH: usize = 32;
+
+// Prefix of the data signed in handshakes
+const HANDSHAKE_CONTEXT: &[u8] = b"p2p-chat handshake";
+
+/// Things happening on connections to peers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetworkEvent {
+    /// Handshake with a peer finished
+    Connected { token: String, public_key: Vec<u8> },
+
+    /// Last connection to a peer closed, it might have vanished silently
+    Disconnected { token: String, timed_out: bool },
+
+    /// Peer answered a ping after this round-trip time
+    Latency { token: String, rtt: Duration },
+
+    /// Channel is open on both sides of a connection
+    Opened { token: String, discovery_key: Vec<u8> },
+
+    Message { token: String, discovery_key: Vec<u8>, message: Vec<u8> },
+
+    /// Problem keeping us from accepting connections
+    Warning(String),
+}
+
+enum Command {
+    Open(Vec<u8>),
+    Close(Vec<u8>),
+    Send(Vec<u8>, Vec<u8>),
+    Disconnect,
+}
+
+struct NetworkState {
+    manager: ConnectionManager,
+
+    // Commands to the task of each connection
+    connections: HashMap<ConnectionId, UnboundedSender<Command>>,
+
+    // Peer tokens of connections which finished the handshake
+    tokens: HashMap<ConnectionId, String>,
+
+    // Channels opened on every connection
+    channels: Vec<Vec<u8>>,
+
+    events: UnboundedSender<NetworkEvent>,
+    port: Option<u16>,
+}
+
+/// Shared handle to all connections of the node.
+#[derive(Clone)]
+pub struct Network {
+    handle: Handle,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    keypair: Rc<Keypair>,
+    state: Rc<RefCell<NetworkState>>,
+    token: String,
+}
+
+impl Network {
+    /// Returns the network and the stream of its events, our token and
+    /// public key are sent to every peer, the keypair proves we own it.
+    pub fn new(
+        handle: Handle,
+        config: Config,
+        token: &str,
+        keypair: Keypair,
+    ) -> (Self, UnboundedReceiver<NetworkEvent>) {
+        let (events, events_rx) = unbounded();
+
+        let (ping_interval, ping_timeout) = (config.ping_interval, config.ping_timeout);
+
+        let state = NetworkState {
+            manager: ConnectionManager::new(config, token),
+            connections: HashMap::new(),
+            tokens: HashMap::new(),
+            channels: Vec::new(),
+            events,
+            port: None,
+        };
+
+        let network = Self {
+            handle,
+            ping_interval,
+            ping_timeout,
+            keypair: Rc::new(keypair),
+            state: Rc::new(RefCell::new(state)),
+            token: token.to_string(),
+        };
+
+        (network, events_rx)
+    }
+
+    /// Starts accepting connections and dialing peers, returns the port
+    /// we are listening on.
+    pub fn listen(&self) -> io::Result<u16> {
+        if let Some(port) = self.state.borrow().port {
+            return Ok(port);
+        }
+
+        let listener = TcpListener::bind(&SocketAddr::from(([0, 0, 0, 0], 0)), &self.handle)?;
+        let port = listener.local_addr()?.port();
+        self.state.borrow_mut().port = Some(port);
+
+        let network = self.clone();
+        let network_error = self.clone();
+
+        let incoming = listener
+            .incoming()
+            .for_each(move |(stream, addr)| {
+                let id = network.state.borrow_mut().manager.accept(addr, Instant::now());
+
+                // Dropping the stream refuses the connection
+                if let Some(id) = id {
+                    network.spawn_connection(id, Box::new(future::ok(stream)));
+                }
+
+                Ok(())
+            })
+            .map_err(move |err| {
+                network_error.emit(NetworkEvent::Warning(
+                    format!("Stopped accepting connections. {}", err)));
+            });
+
+        self.handle.spawn(incoming);
+
+        let network = self.clone();
+
+        let dialing = Interval::new_interval(Duration::from_millis(DIAL_INTERVAL))
+            .for_each(move |_| {
+                network.dial();
+                Ok(())
+            })
+            .map_err(|_| ());
+
+        self.handle.spawn(dialing);
+
+        Ok(port)
+    }
+
+    /// Remembers a discovered peer and dials it.
+    pub fn add_peer(&self, token: &str, addr: SocketAddr) {
+        self.state.borrow_mut().manager.add_peer(token, addr, Instant::now());
+        self.dial();
+    }
+
+    pub fn remove_peer(&self, token: &str) {
+        self.state.borrow_mut().manager.remove_peer(token);
+    }
+
+    /// Opens a channel on all current and future connections.
+    pub fn open(&self, discovery_key: &[u8]) {
+        let mut state = self.state.borrow_mut();
+
+        if !state.channels.iter().any(|channel| channel.as_slice() == discovery_key) {
+            state.channels.push(discovery_key.to_vec());
+        }
+
+        for tx in state.connections.values() {
+            let _ = tx.unbounded_send(Command::Open(discovery_key.to_vec()));
+        }
+    }
+
+    /// Closes a channel on all connections, connections sharing no other
+    /// channel with their peer are dropped at the next ping.
+    pub fn close(&self, discovery_key: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        state.channels.retain(|channel| channel.as_slice() != discovery_key);
+
+        for tx in state.connections.values() {
+            let _ = tx.unbounded_send(Command::Close(discovery_key.to_vec()));
+        }
+    }
+
+    /// Returns true when the handshake with the peer is done.
+    pub fn is_connected(&self, token: &str) -> bool {
+        self.state.borrow().tokens.values().any(|connected| connected == token)
+    }
+
+    /// Sends a message on a channel to one peer.
+    pub fn send(&self, token: &str, discovery_key: &[u8], message: Vec<u8>) {
+        let state = self.state.borrow();
+
+        if let Some(tx) = state.manager.connection_of(token).and_then(|id| state.connections.get(&id)) {
+            let _ = tx.unbounded_send(Command::Send(discovery_key.to_vec(), message));
+        }
+    }
+
+    /// Sends a message on a channel to all connected peers.
+    pub fn broadcast(&self, discovery_key: &[u8], message: Vec<u8>) {
+        let state = self.state.borrow();
+
+        for id in state.tokens.keys() {
+            if let Some(tx) = state.connections.get(id) {
+                let _ = tx.unbounded_send(Command::Send(discovery_key.to_vec(), message.clone()));
+            }
+        }
+    }
+
+    /// Drops the connection to a peer which violated the protocol and bans
+    /// its address for a while.
+    pub fn violation(&self, token: &str) {
+        let id = self.state.borrow().manager.connection_of(token);
+
+        if let Some(id) = id {
+            self.disconnect(id, true);
+        }
+    }
+
+    fn dial(&self) {
+        let dials = self.state.borrow_mut().manager.dial(Instant::now());
+
+        for (id, addr) in dials {
+            let stream = TcpStream::connect(&addr, &self.handle);
+            self.spawn_connection(id, Box::new(stream));
+        }
+    }
+
+    fn spawn_connection(&self, id: ConnectionId, stream: Box<dyn Future<Item=TcpStream, Error=io::Error>>) {
+        let (tx, commands) = unbounded();
+
+        // New connections open all current channels right away
+        for discovery_key in &self.state.borrow().channels {
+            let _ = tx.unbounded_send(Command::Open(discovery_key.clone()));
+        }
+
+        self.state.borrow_mut().connections.insert(id, tx);
+
+        let network = self.clone();
+        let nonce = crypto::generate_nonce();
+        let handshake = encode_handshake(&self.token, self.keypair.public.as_bytes(), &nonce);
+
+        let connection = stream
+            .and_then(move |stream| {
+                stream.set_nodelay(true)?;
+                Ok(stream)
+            })
+            .and_then(move |stream| Connection::new(id, network, stream, commands, handshake, nonce));
+
+        let network = self.clone();
+
+        self.handle.spawn(connection.then(move |result| {
+            // Invalid data means the peer did not follow the protocol
+            let kind = result.err().map(|err| err.kind());
+            network.closed(id, kind == Some(io::ErrorKind::InvalidData), kind == Some(io::ErrorKind::TimedOut));
+            Ok(())
+        }));
+    }
+
+    // Decides whether to keep a connection after its handshake
+    fn handshake(&self, id: ConnectionId, token: &str, public_key: &[u8]) -> bool {
+        let result = self.state.borrow_mut().manager.handshake(id, token, Instant::now());
+
+        let replaced = match result {
+            Ok(replaced) => replaced,
+            Err(_) => return false,
+        };
+
+        let mut state = self.state.borrow_mut();
+
+        // Peer stays connected through the new connection
+        let was_connected = match replaced {
+            Some(replaced) => {
+                state.tokens.remove(&replaced);
+
+                if let Some(tx) = state.connections.remove(&replaced) {
+                    let _ = tx.unbounded_send(Command::Disconnect);
+                }
+
+                true
+            }
+            None => false,
+        };
+
+        state.tokens.insert(id, token.to_string());
+
+        if !was_connected {
+            let _ = state.events.unbounded_send(NetworkEvent::Connected {
+                token: token.to_string(),
+                public_key: public_key.to_vec(),
+            });
+        }
+
+        true
+    }
+
+    fn disconnect(&self, id: ConnectionId, violation: bool) {
+        let mut state = self.state.borrow_mut();
+
+        if violation {
+            state.manager.violation(id, Instant::now());
+        }
+
+        if let Some(tx) = state.connections.get(&id) {
+            let _ = tx.unbounded_send(Command::Disconnect);
+        }
+    }
+
+    // Cleans up after the task of a connection ended
+    fn closed(&self, id: ConnectionId, violation: bool, timed_out: bool) {
+        let mut state = self.state.borrow_mut();
+
+        if violation {
+            state.manager.violation(id, Instant::now());
+        } else {
+            state.manager.closed(id, Instant::now());
+        }
+
+        state.connections.remove(&id);
+
+        if let Some(token) = state.tokens.remove(&id) {
+            let _ = state.events.unbounded_send(NetworkEvent::Disconnected { token, timed_out });
+        }
+    }
+
+    fn emit(&self, event: NetworkEvent) {
+        let _ = self.state.borrow().events.unbounded_send(event);
+    }
+}
+
+fn encode_handshake(token: &str, public_key: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    varint::write(&mut bytes, token.len() as u64);
+    bytes.extend_from_slice(token.as_bytes());
+    bytes.extend_from_slice(public_key);
+    bytes.extend_from_slice(nonce);
+    bytes
+}
+
+// Returns the data a side signs to prove its public key. Its own random
+// nonce comes last, so the signature can not pass as one of a log entry
+fn handshake_proof(remote_nonce: &[u8], local_nonce: &[u8]) -> Vec<u8> {
+    [HANDSHAKE_CONTEXT, remote_nonce, local_nonce].concat()
+}
+
+// What a peer tells about itself before its signature
+#[derive(Debug, PartialEq)]
+struct Handshake {
+    token: String,
+    public_key: Vec<u8>,
+    nonce: Vec<u8>,
+}
+
+// Returns the handshake and its length, None when it is not complete yet
+fn decode_handshake(bytes: &[u8]) -> Result<Option<(Handshake, usize)>, ()> {
+    let mut rest = bytes;
+
+    let length = match varint::read(&mut rest) {
+        Some(length) if length as usize > MAX_TOKEN_LENGTH => return Err(()),
+        Some(length) => length as usize,
+        None if bytes.len() > 10 => return Err(()),
+        None => return Ok(None),
+    };
+
+    if rest.len() < length + PUBLIC_KEY_LENGTH + NONCE_LENGTH {
+        return Ok(None);
+    }
+
+    let token = str::from_utf8(&rest[..length]).map_err(|_| ())?.to_string();
+    let public_key = rest[length..length + PUBLIC_KEY_LENGTH].to_vec();
+    let nonce = rest[length + PUBLIC_KEY_LENGTH..length + PUBLIC_KEY_LENGTH + NONCE_LENGTH].to_vec();
+    let consumed = bytes.len() - rest.len() + length + PUBLIC_KEY_LENGTH + NONCE_LENGTH;
+
+    Ok(Some((Handshake { token, public_key, nonce }, consumed)))
+}
+
+// Returns true when the signature proves the peer holds the secret key of
+// the public key it sent
+fn verify_handshake(public_key: &[u8], proof: &[u8], signature: &[u8]) -> bool {
+    match (PublicKey::from_bytes(public_key), Signature::from_bytes(signature)) {
+        (Ok(public_key), Ok(signature)) => crypto::verify_data(&public_key, proof, &signature).is_ok(),
+        _ => false,
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+// Task reading from and writing to one connection
+struct Connection {
+    id: ConnectionId,
+    network: Network,
+    stream: TcpStream,
+    commands: UnboundedReceiver<Command>,
+    mux: Mux,
+
+    // Token of the peer once the handshake arrived, bytes before that
+    token: Option<String>,
+    handshake: Vec<u8>,
+
+    // Nonce the peer signs and its handshake until its signature arrived
+    nonce: [u8; NONCE_LENGTH],
+    remote: Option<Handshake>,
+
+    // Bytes to write and how many of them are written already
+    output: Vec<u8>,
+    written: usize,
+
+    keepalive: Interval,
+    ping_timeout: Duration,
+
+    // Ping waiting for its pong and when it was sent
+    ping: Option<(u64, Instant)>,
+
+    // Whether any channel was open on both sides so far
+    was_shared: bool,
+    next_nonce: u64,
+    last_received: Instant,
+}
+
+impl Connection {
+    fn new(
+        id: ConnectionId,
+        network: Network,
+        stream: TcpStream,
+        commands: UnboundedReceiver<Command>,
+        handshake: Vec<u8>,
+        nonce: [u8; NONCE_LENGTH],
+    ) -> Self {
+        let now = Instant::now();
+        let keepalive = Interval::new(now + network.ping_interval, network.ping_interval);
+        let ping_timeout = network.ping_timeout;
+
+        Self {
+            id,
+            network,
+            stream,
+            commands,
+            mux: Mux::new(),
+            token: None,
+            handshake: Vec::new(),
+            nonce,
+            remote: None,
+            output: handshake,
+            written: 0,
+            keepalive,
+            ping_timeout,
+            ping: None,
+            was_shared: false,
+            next_nonce: 0,
+            last_received: now,
+        }
+    }
+
+    // Sends a ping unless one is still unanswered, fails when the peer was
+    // silent for too long
+    fn keepalive(&mut self, now: Instant) -> io::Result<()> {
+        if now.duration_since(self.last_received) > self.ping_timeout {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "peer stopped answering"));
+        }
+
+        if self.ping.is_none() {
+            self.mux.ping(self.next_nonce);
+            self.ping = Some((self.next_nonce, now));
+            self.next_nonce += 1;
+        }
+
+        Ok(())
+    }
+
+    // Returns true when any channel is open on both sides
+    fn is_shared(&self) -> bool {
+        self.network.state.borrow().channels.iter().any(|channel| self.mux.is_open(channel))
+    }
+
+    // Handles a command, returns false when the connection should close
+    fn command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Open(discovery_key) => {
+                let is_open = self.mux.open(&discovery_key);
+
+                if let (true, Some(token)) = (is_open, self.token.clone()) {
+                    self.network.emit(NetworkEvent::Opened { token, discovery_key });
+                }
+            }
+            Command::Close(discovery_key) => self.mux.close(&discovery_key),
+            Command::Send(discovery_key, message) => {
+                // Messages for channels which just closed get lost
+                let _ = self.mux.send(&discovery_key, message);
+            }
+            Command::Disconnect => return false,
+        }
+
+        true
+    }
+
+    // Handles the handshake of the peer and the signature following it,
+    // returns false when the connection should close
+    fn receive_handshake(&mut self) -> io::Result<bool> {
+        if self.remote.is_none() {
+            let (handshake, length) = match decode_handshake(&self.handshake) {
+                Ok(Some(handshake)) => handshake,
+                Ok(None) => return Ok(true),
+                Err(()) => return Err(invalid_data("invalid handshake")),
+            };
+
+            // Prove we hold the secret key of our public key
+            let keypair = &self.network.keypair;
+            let proof = handshake_proof(&handshake.nonce, &self.nonce);
+            let signature = crypto::sign_data(&keypair.public, &keypair.secret, &proof);
+            self.output.extend_from_slice(&signature.to_bytes());
+
+            self.handshake.drain(..length);
+            self.remote = Some(handshake);
+        }
+
+        if self.handshake.len() < SIGNATURE_LENGTH {
+            return Ok(true);
+        }
+
+        let Handshake { token, public_key, nonce } = self.remote.take().unwrap();
+        let proof = handshake_proof(&self.nonce, &nonce);
+
+        if !verify_handshake(&public_key, &proof, &self.handshake[..SIGNATURE_LENGTH]) {
+            return Err(invalid_data("invalid handshake signature"));
+        }
+
+        if !self.network.handshake(self.id, &token, &public_key) {
+            return Ok(false);
+        }
+
+        self.token = Some(token);
+
+        let rest = self.handshake.split_off(SIGNATURE_LENGTH);
+        self.handshake = Vec::new();
+
+        self.receive(&rest)
+    }
+
+    // Handles received bytes, returns false when the connection should close
+    fn receive(&mut self, bytes: &[u8]) -> io::Result<bool> {
+        let token = match self.token.clone() {
+            Some(token) => token,
+            None => {
+                self.handshake.extend_from_slice(bytes);
+                return self.receive_handshake();
+            }
+        };
+
+        for event in self.mux.receive(bytes).map_err(invalid_data)? {
+            match event {
+                MuxEvent::Opened(discovery_key) => {
+                    self.was_shared = true;
+                    self.network.emit(NetworkEvent::Opened { token: token.clone(), discovery_key });
+                }
+                MuxEvent::Message(discovery_key, message) => {
+                    self.network.emit(NetworkEvent::Message {
+                        token: token.clone(),
+                        discovery_key,
+                        message,
+                    });
+                }
+                MuxEvent::Pong(nonce) => {
+                    if let Some((expected, sent)) = self.ping {
+                        if nonce == expected {
+                            self.ping = None;
+                            self.network.emit(NetworkEvent::Latency { token: token.clone(), rtt: sent.elapsed() });
+                        }
+                    }
+                }
+                MuxEvent::Closed(_) => {}
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Future for Connection {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Async::Ready(command) = self.commands.poll().unwrap() {
+            let keep = match command {
+                Some(command) => self.command(command),
+                None => false,
+            };
+
+            if !keep {
+                return Ok(Async::Ready(()));
+            }
+        }
+
+        while let Async::Ready(Some(_)) = self.keepalive.poll().map_err(io::Error::other)? {
+            // Either side left all channels, the connection only takes a slot
+            if self.was_shared && !self.is_shared() {
+                return Ok(Async::Ready(()));
+            }
+
+            self.keepalive(Instant::now())?;
+        }
+
+        let mut buffer = [0; READ_SIZE];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(Async::Ready(())),
+                Ok(length) => {
+                    self.last_received = Instant::now();
+
+                    if !self.receive(&buffer[..length])? {
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        // Messages go out only after the handshake was sent
+        loop {
+            if self.written == self.output.len() {
+                if self.token.is_none() || !self.mux.has_output() {
+                    break;
+                }
+
+                self.output = self.mux.take_output(WRITE_SIZE);
+                self.written = 0;
+            }
+
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(length) => self.written += length,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod network {
+    use super::*;
+
+    use tokio_core::reactor::{Core, Timeout};
+
+    const CHANNEL: &[u8] = &[7; 32];
+
+    // Runs the core until an event matches or a second passed
+    fn wait_for<F>(core: &mut Core, events: &mut UnboundedReceiver<NetworkEvent>, matches: F) -> Option<NetworkEvent>
+    where
+        F: Fn(&NetworkEvent) -> bool,
+    {
+        let mut timeout = Timeout::new(Duration::from_secs(1), &core.handle()).unwrap();
+
+        core.run(future::poll_fn(|| -> Poll<Option<NetworkEvent>, io::Error> {
+            while let Async::Ready(Some(event)) = events.poll().unwrap() {
+                if matches(&event) {
+                    return Ok(Async::Ready(Some(event)));
+                }
+            }
+
+            match timeout.poll()? {
+                Async::Ready(()) => Ok(Async::Ready(None)),
+                Async::NotReady => Ok(Async::NotReady),
+            }
+        })).unwrap()
+    }
+
+    // Connects like a peer sending the public key of the keypair, with the
+    // handshake signed by the signer, once the handshake of the other side
+    // arrived
+    fn connect_raw(core: &mut Core, port: u16, token: &str, keypair: &Keypair, signer: &Keypair) -> std::net::TcpStream {
+        let nonce = [3; NONCE_LENGTH];
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(&encode_handshake(token, keypair.public.as_bytes(), &nonce)).unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let mut bytes = Vec::new();
+
+        let remote_nonce = loop {
+            core.turn(Some(Duration::from_millis(10)));
+
+            let mut buffer = [0; 1024];
+
+            match stream.read(&mut buffer) {
+                Ok(length) => bytes.extend_from_slice(&buffer[..length]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => panic!("{}", err),
+            }
+
+            if let Ok(Some((handshake, _))) = decode_handshake(&bytes) {
+                break handshake.nonce;
+            }
+        };
+
+        let signature = crypto::sign_data(&signer.public, &signer.secret, &handshake_proof(&remote_nonce, &nonce));
+        stream.write_all(&signature.to_bytes()).unwrap();
+        stream
+    }
+
+    #[test]
+    fn encode_decode_handshake() {
+        let bytes = encode_handshake("abc", &[1; PUBLIC_KEY_LENGTH], &[2; NONCE_LENGTH]);
+
+        assert_eq!(decode_handshake(&bytes[..10]), Ok(None));
+        assert_eq!(decode_handshake(&bytes), Ok(Some((Handshake {
+            token: String::from("abc"),
+            public_key: vec![1; PUBLIC_KEY_LENGTH],
+            nonce: vec![2; NONCE_LENGTH],
+        }, bytes.len()))));
+        assert_eq!(decode_handshake(&[100]), Err(()));
+    }
+
+    #[test]
+    fn exchanges_messages() {
+        let mut core = Core::new().unwrap();
+
+        let keypair = crypto::generate_keypair();
+        let public_key = keypair.public.as_bytes().to_vec();
+
+        let (a, mut a_events) = Network::new(core.handle(), Config::default(), "a", crypto::generate_keypair());
+        let (b, mut b_events) = Network::new(core.handle(), Config::default(), "b", keypair);
+
+        a.open(CHANNEL);
+        b.open(CHANNEL);
+
+        let port = a.listen().unwrap();
+        b.listen().unwrap();
+        b.add_peer("a", SocketAddr::from(([127, 0, 0, 1], port)));
+
+        let connected = wait_for(&mut core, &mut a_events, |event| matches!(event, NetworkEvent::Connected { .. }));
+
+        assert_eq!(connected, Some(NetworkEvent::Connected {
+            token: String::from("b"),
+            public_key,
+        }));
+
+        wait_for(&mut core, &mut b_events, |event| matches!(event, NetworkEvent::Opened { .. })).unwrap();
+
+        b.send("a", CHANNEL, b"Hello".to_vec());
+
+        let message = wait_for(&mut core, &mut a_events, |event| matches!(event, NetworkEvent::Message { .. }));
+
+        assert_eq!(message, Some(NetworkEvent::Message {
+            token: String::from("b"),
+            discovery_key: CHANNEL.to_vec(),
+            message: b"Hello".to_vec(),
+        }));
+
+        // Peers breaking the protocol are dropped
+        a.violation("b");
+
+        let disconnected = wait_for(&mut core, &mut b_events, |event| matches!(event, NetworkEvent::Disconnected { .. }));
+
+        assert_eq!(disconnected, Some(NetworkEvent::Disconnected {
+            token: String::from("a"),
+            timed_out: false,
+        }));
+    }
+
+    #[test]
+    fn rejects_foreign_public_keys() {
+        let mut core = Core::new().unwrap();
+        let (a, _a_events) = Network::new(core.handle(), Config::default(), "a", crypto::generate_keypair());
+        let port = a.listen().unwrap();
+
+        // Peers can not claim a public key without its secret key
+        let mut stream = connect_raw(&mut core, port, "d", &crypto::generate_keypair(), &crypto::generate_keypair());
+        let mut buffer = [0; 1024];
+
+        let is_closed = (0..100).any(|_| {
+            core.turn(Some(Duration::from_millis(10)));
+            match stream.read(&mut buffer) {
+                Ok(length) => length == 0,
+                Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+            }
+        });
+
+        assert!(is_closed);
+        assert!(!a.is_connected("d"));
+    }
+
+    #[test]
+    fn drops_connections_without_channels() {
+        let mut core = Core::new().unwrap();
+
+        let config = Config {
+            ping_interval: Duration::from_millis(50),
+            ..Config::default()
+        };
+
+        let (a, mut a_events) = Network::new(core.handle(), config.clone(), "a", crypto::generate_keypair());
+        let (b, mut b_events) = Network::new(core.handle(), config, "b", crypto::generate_keypair());
+
+        a.open(CHANNEL);
+        b.open(CHANNEL);
+
+        let port = a.listen().unwrap();
+        b.listen().unwrap();
+        b.add_peer("a", SocketAddr::from(([127, 0, 0, 1], port)));
+
+        wait_for(&mut core, &mut b_events, |event| matches!(event, NetworkEvent::Opened { .. })).unwrap();
+        assert!(a.is_connected("b"));
+
+        // Leaving the only shared channel ends the connection on both sides
+        b.close(CHANNEL);
+
+        let disconnected = wait_for(&mut core, &mut a_events, |event| matches!(event, NetworkEvent::Disconnected { .. }));
+
+        assert_eq!(disconnected, Some(NetworkEvent::Disconnected {
+            token: String::from("b"),
+            timed_out: false,
+        }));
+        assert!(!a.is_connected("b"));
+    }
+
+    #[test]
+    fn measures_latency_and_times_out() {
+        let mut core = Core::new().unwrap();
+
+        let config = Config {
+            ping_interval: Duration::from_millis(50),
+            ping_timeout: Duration::from_millis(300),
+            ..Config::default()
+        };
+
+        let (a, mut a_events) = Network::new(core.handle(), config.clone(), "a", crypto::generate_keypair());
+        let (b, _b_events) = Network::new(core.handle(), config, "b", crypto::generate_keypair());
+
+        let port = a.listen().unwrap();
+        b.listen().unwrap();
+        b.add_peer("a", SocketAddr::from(([127, 0, 0, 1], port)));
+
+        let latency = wait_for(&mut core, &mut a_events, |event| matches!(event, NetworkEvent::Latency { .. }));
+
+        assert!(matches!(latency, Some(NetworkEvent::Latency { ref token, .. }) if token == "b"));
+
+        // A peer which completes the handshake but never answers pings
+        let keypair = crypto::generate_keypair();
+        let _silent = connect_raw(&mut core, port, "c", &keypair, &keypair);
+
+        let disconnected = wait_for(&mut core, &mut a_events, |event| matches!(event, NetworkEvent::Disconnected { .. }));
+
+        assert_eq!(disconnected, Some(NetworkEvent::Disconnected {
+            token: String::from("c"),
+            timed_out: true,
+        }));
+    }
+}
@@ -0,0 +1,1540 @@
+//! Chat node shared by all frontends
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use ed25519_dalek::Keypair;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use serde_json::json;
+use tokio_core::reactor::Handle;
+
+use crate::connections::Config;
+use crate::crypto;
+use crate::discovery::{DiscoveryEvent, DiscoveryPeer, DiscoveryStream, Goodbye};
+use crate::export::Archive;
+use crate::log::{Checkpoint, ForkProof, Log};
+use crate::network::{Network, NetworkEvent};
+use crate::payload::{Payload, PayloadError};
+use crate::replication::{self, Feed, Message as ReplicationMessage, RECENT_ENTRIES};
+use crate::shutdown::{Shutdown, ShutdownReason};
+use crate::storage;
+
+const DISCOVERY_NAME: &[u8] = b"p2p-chat";
+pub const URL_PROTOCOL: &str = "chat://";
+
+const NICKNAME_MAX_LEN: usize = 32;
+
+// Logs of other authors replicated per channel at most
+const MAX_LOGS: usize = 1024;
+
+/// Returns the channel public key of a chat:// URL.
+pub fn parse_channel_url(url: &str) -> Result<Vec<u8>, String> {
+    let key = hex::decode(url.trim().replace(URL_PROTOCOL, ""))
+        .map_err(|err| format!("Invalid channel URL: {}", err))?;
+
+    if key.len() != 32 {
+        return Err(String::from("Invalid channel URL: key needs to be 32 bytes long"));
+    }
+
+    Ok(key)
+}
+
+/// Returns the chat:// URL of a channel public key.
+pub fn channel_url(channel_key: &[u8]) -> String {
+    format!("{}{}", URL_PROTOCOL, hex::encode(channel_key))
+}
+
+/// Returns the first bytes of a public key as hex string, short enough to
+/// display next to nicknames to tell authors apart.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    hex::encode(&public_key[..3])
+}
+
+/// Returns how an author is displayed, like "adz (abcd12)".
+pub fn display_name(public_key: &[u8], nickname: Option<&str>) -> String {
+    match nickname {
+        Some(nickname) => format!("{} ({})", nickname, fingerprint(public_key)),
+        None => fingerprint(public_key),
+    }
+}
+
+/// Returns true when the text contains the nickname as a word, ignoring case.
+pub fn mentions(text: &str, nickname: &str) -> bool {
+    !nickname.is_empty() && text
+        .split(|c: char| !c.is_alphanumeric() && c != '_' && c != '-')
+        .any(|word| word.to_lowercase() == nickname.to_lowercase())
+}
+
+fn validate_nickname(nickname: &str) -> Result<(), String> {
+    if nickname.is_empty() || nickname.chars().count() > NICKNAME_MAX_LEN {
+        return Err(format!("Nickname needs to have 1 to {} characters", NICKNAME_MAX_LEN));
+    }
+
+    if nickname.chars().any(char::is_control) {
+        return Err(String::from("Nickname can not contain control characters"));
+    }
+
+    if nickname.chars().any(char::is_whitespace) {
+        return Err(String::from("Nickname can not contain whitespace"));
+    }
+
+    Ok(())
+}
+
+/// Creates an empty log signed with our keypair, which can not be cloned.
+fn own_log(keypair: &Keypair) -> Log {
+    Log::with_keypair(Keypair::from_bytes(&keypair.to_bytes()).unwrap())
+}
+
+/// Chat message stored in the log of its author.
+#[derive(Clone)]
+pub struct Message {
+    pub author: Vec<u8>,
+    pub nickname: Option<String>,
+    pub sequence_number: u64,
+    pub text: String,
+
+    /// Time the author sent the message, as claimed in their log
+    pub timestamp: DateTime<Local>,
+}
+
+impl Message {
+    pub fn display_name(&self) -> String {
+        display_name(&self.author, self.nickname.as_deref())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "author": hex::encode(&self.author),
+            "nickname": self.nickname,
+            "sequence_number": self.sequence_number,
+            "text": self.text,
+            "timestamp": self.timestamp.to_rfc3339(),
+        })
+    }
+}
+
+/// Events frontends can subscribe to.
+#[derive(Clone)]
+pub enum NodeEvent {
+    Message(Message),
+    Nickname {
+        author: Vec<u8>,
+        nickname: String,
+    },
+    PeerFound(DiscoveryPeer),
+    PeerUpdated(String),
+    PeerLeft(String),
+    Info(String),
+}
+
+/// Peer of the current channel and what we know about it.
+#[derive(Clone)]
+pub struct Peer {
+    pub discovery: DiscoveryPeer,
+
+    /// Public key of the peer's log, known once connected
+    pub public_key: Option<Vec<u8>>,
+
+    pub connected: bool,
+
+    /// Round-trip time of the connection
+    pub latency: Option<Duration>,
+
+    /// Last time we heard of the peer
+    pub last_seen: DateTime<Local>,
+}
+
+impl Peer {
+    fn new(discovery: DiscoveryPeer) -> Self {
+        Self {
+            discovery,
+            public_key: None,
+            connected: false,
+            latency: None,
+            last_seen: Local::now(),
+        }
+    }
+}
+
+/// Progress of replicating the logs of the current channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SyncState {
+    /// Number of entries we hold of all logs
+    pub local: u64,
+
+    /// Number of entries we hold and the ones peers can still send us of
+    /// the ranges we fetch, unknown until anyone announced a log
+    pub remote: Option<u64>,
+}
+
+impl SyncState {
+    pub fn is_synced(&self) -> bool {
+        self.remote.is_none_or(|remote| self.local >= remote)
+    }
+}
+
+struct NodeState {
+    // Public key of the channel we are interested in and the key its
+    // connections are opened with
+    channel_key: Vec<u8>,
+    discovery_key: Vec<u8>,
+
+    // Stops the discovery of the current channel when dropped
+    discovery: Option<oneshot::Sender<()>>,
+
+    // Announcement to send when leaving the current channel
+    goodbye: Option<Goodbye>,
+
+    // Our own append-only log holding all messages sent in the current
+    // channel
+    log: Log,
+
+    // Our own logs of channels we left, continued when joining them again
+    own_logs: HashMap<Vec<u8>, Log>,
+
+    // Signs our own logs, the same in every channel
+    keypair: Keypair,
+
+    // Logs of other authors of the channel, as far as we replicated them
+    logs: HashMap<Vec<u8>, Log>,
+
+    // Replication of each log with each peer, by peer token and author
+    feeds: HashMap<(String, Vec<u8>), Feed>,
+
+    // Messages of the channel in the order we got them
+    messages: Vec<Message>,
+
+    // Latest nicknames claimed in the profile entries of each author
+    nicknames: HashMap<Vec<u8>, String>,
+
+    // Number of entries received of each remote log and the longest
+    // length peers announced for it
+    received_lengths: HashMap<Vec<u8>, u64>,
+    remote_lengths: HashMap<Vec<u8>, u64>,
+
+    // Directory all logs of the channel are kept in when seeding it
+    storage: Option<PathBuf>,
+
+    // Problem with the network which keeps us from finding peers
+    warning: Option<String>,
+
+    // Discovered peers by their token
+    peers: HashMap<String, Peer>,
+
+    // Frontends listening to events
+    subscribers: Vec<UnboundedSender<NodeEvent>>,
+}
+
+/// Shared handle to the chat node driving discovery, connections to peers
+/// and the logs.
+#[derive(Clone)]
+pub struct Node {
+    handle: Handle,
+    network: Network,
+    shutdown: Shutdown,
+    state: Rc<RefCell<NodeState>>,
+
+    // Identifies us in discovery and handshakes
+    token: String,
+}
+
+impl Node {
+    pub fn new(handle: Handle, shutdown: Shutdown) -> Self {
+        Self::with_config(handle, shutdown, Config::default())
+    }
+
+    /// Returns a node limiting its connections as configured.
+    pub fn with_config(handle: Handle, shutdown: Shutdown, config: Config) -> Self {
+        let keypair = crypto::generate_keypair();
+        let log = own_log(&keypair);
+        let token = crypto::generate_random_token();
+        let network_keypair = Keypair::from_bytes(&keypair.to_bytes()).unwrap();
+        let (network, network_events) = Network::new(handle.clone(), config, &token, network_keypair);
+
+        let state = NodeState {
+            channel_key: log.public_key().to_vec(),
+            discovery: None,
+            discovery_key: Vec::new(),
+            feeds: HashMap::new(),
+            goodbye: None,
+            keypair,
+            log,
+            logs: HashMap::new(),
+            messages: Vec::new(),
+            nicknames: HashMap::new(),
+            own_logs: HashMap::new(),
+            peers: HashMap::new(),
+            received_lengths: HashMap::new(),
+            remote_lengths: HashMap::new(),
+            storage: None,
+            subscribers: Vec::new(),
+            warning: None,
+        };
+
+        let node = Self {
+            handle,
+            network,
+            shutdown,
+            state: Rc::new(RefCell::new(state)),
+            token,
+        };
+
+        // Tell others we are gone when shutting down
+        let node_clone = node.clone();
+        node.shutdown.on_shutdown(move || node_clone.leave());
+
+        let node_clone = node.clone();
+
+        node.handle.spawn(network_events.for_each(move |event| {
+            node_clone.handle_network(event);
+            Ok(())
+        }));
+
+        let public_key = node.public_key();
+        node.announce(&public_key);
+
+        node
+    }
+
+    /// Returns the public key of our own log.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.state.borrow().log.public_key().to_vec()
+    }
+
+    /// Returns our own nickname.
+    pub fn nickname(&self) -> Option<String> {
+        self.nickname_of(&self.public_key())
+    }
+
+    /// Returns the nickname an author claimed last.
+    pub fn nickname_of(&self, author: &[u8]) -> Option<String> {
+        self.state.borrow().nicknames.get(author).cloned()
+    }
+
+    /// Changes our nickname by publishing a new profile entry in our log,
+    /// it needs to be a single word.
+    pub fn set_nickname(&self, nickname: &str) -> Result<(), String> {
+        validate_nickname(nickname)?;
+
+        self.append(Payload::Profile { nickname: nickname.to_string() });
+
+        Ok(())
+    }
+
+    /// Returns the public key of the current channel.
+    pub fn channel_key(&self) -> Vec<u8> {
+        self.state.borrow().channel_key.clone()
+    }
+
+    /// Returns the currently discovered peers.
+    pub fn peers(&self) -> Vec<DiscoveryPeer> {
+        self.state.borrow().peers.values().map(|peer| peer.discovery.clone()).collect()
+    }
+
+    /// Returns everything known about the peers, the most recently seen first.
+    pub fn peer_list(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.state.borrow().peers.values().cloned().collect();
+        peers.sort_by_key(|peer| cmp::Reverse(peer.last_seen));
+        peers
+    }
+
+    /// Returns how many entries of an author's log we have and how many
+    /// peers announced.
+    pub fn log_progress(&self, author: &[u8]) -> (u64, Option<u64>) {
+        let state = self.state.borrow();
+
+        if author == state.log.public_key() {
+            return (state.log.len() as u64, None);
+        }
+
+        (state.received_lengths.get(author).cloned().unwrap_or(0),
+         state.remote_lengths.get(author).cloned())
+    }
+
+    /// Returns true when the author of a log was caught signing conflicting
+    /// entries.
+    pub fn is_forked(&self, author: &[u8]) -> bool {
+        self.state.borrow().logs.get(author).is_some_and(|log| log.fork_proof().is_some())
+    }
+
+    /// Returns how far the logs of the channel are replicated.
+    pub fn sync_state(&self) -> SyncState {
+        let state = self.state.borrow();
+        let local = state.log.count() + state.logs.values().map(Log::count).sum::<u64>();
+
+        if state.feeds.is_empty() {
+            return SyncState { local, remote: None };
+        }
+
+        // Entries outside the window we fetch do not count, peers holding
+        // the same missing entries count once
+        let mut missing: HashMap<&[u8], u64> = HashMap::new();
+
+        for ((_, author), feed) in &state.feeds {
+            if let Some(log) = state.logs.get(author) {
+                let count = missing.entry(author).or_insert(0);
+                *count = cmp::max(*count, feed.missing(log));
+            }
+        }
+
+        SyncState {
+            local,
+            remote: Some(local + missing.values().sum::<u64>()),
+        }
+    }
+
+    /// Remembers the length of a remote log announced by a peer.
+    pub fn handle_remote_length(&self, author: &[u8], length: u64) {
+        if author == self.public_key().as_slice() {
+            return;
+        }
+
+        let mut state = self.state.borrow_mut();
+        let known = state.remote_lengths.entry(author.to_vec()).or_insert(0);
+        *known = cmp::max(*known, length);
+    }
+
+    /// Returns the current problem with the network, if any.
+    pub fn warning(&self) -> Option<String> {
+        self.state.borrow().warning.clone()
+    }
+
+    /// Returns a stream of all events happening from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<NodeEvent> {
+        let (tx, rx) = unbounded();
+        self.state.borrow_mut().subscribers.push(tx);
+        rx
+    }
+
+    /// Leaves the current channel and starts discovering peers of the given one.
+    pub fn join(&self, channel_key: &[u8]) {
+        self.leave();
+
+        let discovery_key = crypto::generate_discovery_key(channel_key, DISCOVERY_NAME);
+
+        let is_new_log = {
+            let mut state = self.state.borrow_mut();
+            let had_channel = !state.channel_key.is_empty();
+            state.channel_key = channel_key.to_vec();
+            state.discovery_key = discovery_key.as_bytes().to_vec();
+            state.received_lengths.clear();
+            state.remote_lengths.clear();
+            state.warning = None;
+
+            // Messages we sent in other channels stay there, the ones sent
+            // before joining any go to the first
+            match state.own_logs.remove(channel_key) {
+                Some(log) => {
+                    state.log = log;
+                    false
+                }
+                None => had_channel,
+            }
+        };
+
+        // Logs we continue are announced since we first joined them
+        if is_new_log {
+            let public_key = self.public_key();
+            self.announce(&public_key);
+
+            // Peers of the channel learn our name from our log in it
+            if let Some(nickname) = self.nickname() {
+                let mut state = self.state.borrow_mut();
+                state.log.append(&Payload::Profile { nickname }.to_bytes()).unwrap();
+            }
+        }
+
+        // Peers connect to the port announced in discovery
+        let port = match self.network.listen() {
+            Ok(port) => port,
+            Err(err) => {
+                self.warn(format!("Could not accept connections. {}", err));
+                0
+            }
+        };
+
+        self.network.open(discovery_key.as_bytes());
+
+        // Discover peers which are interested in the same channel
+        let discovery_stream = DiscoveryStream::new(
+            self.handle.clone(), discovery_key.as_bytes(), port, &self.token);
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        self.state.borrow_mut().discovery = Some(stop_tx);
+
+        let node = self.clone();
+        let node_error = self.clone();
+        let shutdown = self.shutdown.clone();
+
+        let discovery_future = discovery_stream
+            .map_err(move |err| {
+                shutdown.trigger(ShutdownReason::Error(
+                    format!("Could not start discovery stream. {}", err)));
+            })
+            .and_then(move |stream| {
+                node.state.borrow_mut().goodbye = Some(stream.goodbye());
+
+                stream
+                    .map_err(move |err| {
+                        node_error.warn(format!("Discovery stopped, no new peers will be found. {}", err));
+                    })
+                    .for_each(move |event| {
+                        node.handle_discovery(event);
+                        Ok(())
+                    })
+            })
+            .select2(stop_rx)
+            .then(|_| Ok(()));
+
+        self.handle.spawn(discovery_future);
+
+        self.emit(NodeEvent::Info(channel_url(channel_key)));
+    }
+
+    /// Joins a channel as seeder which replicates every entry of all its logs
+    /// and keeps them in the given directory, to serve them to peers even
+    /// when their authors are offline. Logs stored earlier are loaded again.
+    pub fn seed(&self, channel_key: &[u8], dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+
+        self.join(channel_key);
+        self.state.borrow_mut().storage = Some(dir.to_path_buf());
+
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+
+            // Files are named after the public key of the log's author, with
+            // proofs of forks next to them
+            let log = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| hex::decode(name).ok())
+                .and_then(|author| Log::remote(&author).ok());
+
+            let mut log = match log {
+                Some(log) => log,
+                None => continue,
+            };
+
+            // Entries are verified again, broken ones are fetched anew. The
+            // rest of an entry cut off while writing would break new ones
+            let (entries, length) = storage::read_entries(&path)?;
+            storage::truncate(&path, length)?;
+
+            for entry in entries {
+                let _ = log.insert(&entry);
+            }
+
+            let proof_path = path.with_extension("fork");
+
+            if proof_path.exists() {
+                let (proof, length) = storage::read_entries(&proof_path)?;
+                storage::truncate(&proof_path, length)?;
+
+                if let [first, second, ..] = proof.as_slice() {
+                    let _ = log.add_fork_proof(ForkProof { first: first.clone(), second: second.clone() });
+                }
+            }
+
+            let author = log.public_key().to_vec();
+
+            {
+                let mut state = self.state.borrow_mut();
+
+                if state.logs.len() >= MAX_LOGS {
+                    break;
+                }
+
+                state.received_lengths.insert(author.clone(), log.len() as u64);
+                state.logs.insert(author.clone(), log);
+            }
+
+            self.announce(&author);
+            self.store(&author);
+        }
+
+        Ok(())
+    }
+
+    /// Asks connected peers for the entries before the ones we fetched so
+    /// far, like when scrolling back to the start of the history. Returns
+    /// false when no peer holds any older ones.
+    pub fn fetch_older(&self) -> bool {
+        let (requests, discovery_key) = {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+            let mut requests = Vec::new();
+
+            for ((token, author), feed) in state.feeds.iter_mut() {
+                let log = if *author == state.log.public_key() {
+                    &state.log
+                } else {
+                    match state.logs.get(author) {
+                        Some(log) => log,
+                        None => continue,
+                    }
+                };
+
+                if !feed.has_older(log) {
+                    continue;
+                }
+
+                for want in feed.fetch_older(log, RECENT_ENTRIES) {
+                    requests.push((token.clone(), replication::encode(author, &want)));
+                }
+            }
+
+            (requests, state.discovery_key.clone())
+        };
+
+        let is_fetching = !requests.is_empty();
+
+        for (token, message) in requests {
+            self.network.send(&token, &discovery_key, message);
+        }
+
+        is_fetching
+    }
+
+    /// Returns true when every connected peer announced holding the newest
+    /// entry of our log.
+    pub fn is_delivered(&self) -> bool {
+        let state = self.state.borrow();
+        let last = state.log.len() as u64;
+
+        last == 0 || state.peers
+            .iter()
+            .filter(|(_, peer)| peer.connected)
+            .all(|(token, _)| {
+                state.feeds
+                    .get(&(token.clone(), state.log.public_key().to_vec()))
+                    .is_some_and(|feed| feed.has(last))
+            })
+    }
+
+    /// Returns the messages of the current channel in the order we got them.
+    pub fn messages(&self) -> Vec<Message> {
+        self.state.borrow().messages.clone()
+    }
+
+    /// Returns an archive of all logs of the current channel we hold.
+    pub fn export(&self) -> Archive {
+        let state = self.state.borrow();
+
+        // Logs can not be cloned, they are copied entry by entry
+        let logs = std::iter::once(&state.log)
+            .chain(state.logs.values())
+            .filter(|log| log.count() > 0)
+            .map(|log| {
+                let mut copy = Log::remote(log.public_key()).unwrap();
+
+                for entry in log.iter() {
+                    copy.insert(&entry.to_bytes()).unwrap();
+                }
+
+                copy
+            })
+            .collect();
+
+        Archive {
+            channel_key: state.channel_key.clone(),
+            logs,
+        }
+    }
+
+    /// Adds the logs of an archive, joining its channel first when we are
+    /// in another one. Returns the number of entries we did not hold yet.
+    pub fn import(&self, archive: Archive) -> usize {
+        if archive.channel_key != self.channel_key() {
+            self.join(&archive.channel_key);
+        }
+
+        let mut imported = 0;
+
+        for copy in archive.logs {
+            let author = copy.public_key().to_vec();
+
+            // Entries of our own log are all here already
+            if author == self.public_key() {
+                continue;
+            }
+
+            let is_new_log = {
+                let mut state = self.state.borrow_mut();
+
+                if state.logs.contains_key(&author) {
+                    false
+                } else if state.logs.len() >= MAX_LOGS {
+                    break;
+                } else {
+                    state.logs.insert(author.clone(), Log::remote(&author).unwrap());
+                    true
+                }
+            };
+
+            if is_new_log {
+                self.announce(&author);
+                self.store(&author);
+            }
+
+            // Entries conflicting with the ones we hold are left out
+            let entries: Vec<(u64, Vec<u8>)> = {
+                let mut state = self.state.borrow_mut();
+                let log = state.logs.get_mut(&author).unwrap();
+
+                copy.iter()
+                    .filter_map(|entry| {
+                        let count = log.count();
+                        log.insert(&entry.to_bytes()).ok()?;
+
+                        if log.count() > count {
+                            Some((entry.sequence_number(), entry.data().to_vec()))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect()
+            };
+
+            imported += entries.len();
+
+            for (sequence_number, data) in entries {
+                let _ = self.handle_entry(&author, sequence_number, &data);
+            }
+        }
+
+        imported
+    }
+
+    /// Signs a checkpoint of our log and forgets all entries before the
+    /// latest checkpoints of every log, except when seeding the channel.
+    /// Returns the sequence number of the checkpoint and how many entries
+    /// were dropped.
+    pub fn checkpoint(&self) -> (u64, u64) {
+        let sequence_number = {
+            let mut state = self.state.borrow_mut();
+            // Our own log is always writable
+            state.log.append_checkpoint().unwrap()
+        };
+
+        // Peers starting at the checkpoint still need to know our name
+        if let Some(nickname) = self.nickname() {
+            self.append(Payload::Profile { nickname });
+        }
+
+        let mut state = self.state.borrow_mut();
+
+        if state.storage.is_some() {
+            return (sequence_number, 0);
+        }
+
+        let dropped = state.log.compact() + state.logs.values_mut().map(Log::compact).sum::<u64>();
+
+        (sequence_number, dropped)
+    }
+
+    /// Appends a new message to our log and announces it to all frontends.
+    pub fn send_message(&self, text: &str) -> Message {
+        let author = self.public_key();
+        let timestamp = Local::now();
+        let sequence_number = self.append(Payload::Message { text: text.to_string(), timestamp });
+
+        Message {
+            nickname: self.nickname_of(&author),
+            author,
+            sequence_number,
+            text: text.to_string(),
+            timestamp,
+        }
+    }
+
+    /// Handles a verified entry of any author's log.
+    pub fn handle_entry(
+        &self,
+        author: &[u8],
+        sequence_number: u64,
+        data: &[u8],
+    ) -> Result<(), PayloadError> {
+        {
+            let mut state = self.state.borrow_mut();
+            let received = state.received_lengths.entry(author.to_vec()).or_insert(0);
+            *received = cmp::max(*received, sequence_number);
+        }
+
+        // Checkpoints only matter to the log
+        if Checkpoint::from_bytes(data).is_some() {
+            return Ok(());
+        }
+
+        let payload = Payload::from_bytes(data)?;
+        self.apply(author, sequence_number, payload);
+        Ok(())
+    }
+
+    // Appends entry to our own log, returns its sequence number
+    fn append(&self, payload: Payload) -> u64 {
+        let (author, sequence_number) = {
+            let mut state = self.state.borrow_mut();
+            // Our own log is always writable
+            let sequence_number = state.log.append(&payload.to_bytes()).unwrap();
+            (state.log.public_key().to_vec(), sequence_number)
+        };
+
+        self.apply(&author, sequence_number, payload);
+
+        sequence_number
+    }
+
+    fn apply(&self, author: &[u8], sequence_number: u64, payload: Payload) {
+        match payload {
+            Payload::Message { text, timestamp } => {
+                let message = Message {
+                    author: author.to_vec(),
+                    nickname: self.nickname_of(author),
+                    sequence_number,
+                    text,
+                    timestamp,
+                };
+
+                self.state.borrow_mut().messages.push(message.clone());
+                self.emit(NodeEvent::Message(message));
+            }
+            Payload::Profile { nickname } => {
+                // Ignore names which could mess up the display
+                if validate_nickname(&nickname).is_err() {
+                    return;
+                }
+
+                self.state.borrow_mut().nicknames.insert(author.to_vec(), nickname.clone());
+
+                self.emit(NodeEvent::Nickname {
+                    author: author.to_vec(),
+                    nickname: nickname.clone(),
+                });
+
+                // Warn when someone else claims the same name, only the
+                // fingerprint tells them apart then
+                let others: Vec<String> = self.state.borrow().nicknames
+                    .iter()
+                    .filter(|(key, name)| **name == nickname && key.as_slice() != author)
+                    .map(|(key, _)| fingerprint(key))
+                    .collect();
+
+                if !others.is_empty() {
+                    self.emit(NodeEvent::Info(format!(
+                        "Warning: nickname {} is claimed by {} and {}",
+                        nickname,
+                        fingerprint(author),
+                        others.join(", "))));
+                }
+            }
+        }
+    }
+
+    fn leave(&self) {
+        {
+            let mut state = self.state.borrow_mut();
+
+            if let Some(goodbye) = state.goodbye.take() {
+                let _ = goodbye.send();
+            }
+
+            state.discovery = None;
+            state.storage = None;
+
+            if !state.channel_key.is_empty() {
+                let state = &mut *state;
+                let log = mem::replace(&mut state.log, own_log(&state.keypair));
+                state.own_logs.insert(state.channel_key.clone(), log);
+            }
+
+            state.logs.clear();
+            state.feeds.clear();
+            state.messages.clear();
+
+            // Connections stay for peers we meet in the next channel
+            for token in state.peers.keys() {
+                self.network.remove_peer(token);
+            }
+
+            state.peers.clear();
+            self.network.close(&state.discovery_key);
+        }
+    }
+
+    fn warn(&self, warning: String) {
+        self.state.borrow_mut().warning = Some(warning.clone());
+        self.emit(NodeEvent::Info(format!("Warning: {}", warning)));
+    }
+
+    fn handle_discovery(&self, event: DiscoveryEvent) {
+        match event {
+            DiscoveryEvent::Found(peer) => {
+                let token = peer.token();
+
+                self.network.add_peer(&token, SocketAddr::new(peer.addr().into(), peer.port()));
+
+                let is_new = {
+                    let mut state = self.state.borrow_mut();
+
+                    match state.peers.get_mut(&token) {
+                        Some(known) => {
+                            known.discovery = peer.clone();
+                            known.last_seen = Local::now();
+                            false
+                        }
+                        None => {
+                            // We might still be connected from another channel
+                            let mut found = Peer::new(peer.clone());
+                            found.connected = self.network.is_connected(&token);
+
+                            state.peers.insert(token.clone(), found);
+                            true
+                        }
+                    }
+                };
+
+                if is_new {
+                    self.emit(NodeEvent::PeerFound(peer));
+                } else {
+                    self.emit(NodeEvent::PeerUpdated(token));
+                }
+            }
+            DiscoveryEvent::Left(token) => {
+                self.network.remove_peer(&token);
+
+                if self.state.borrow_mut().peers.remove(&token).is_some() {
+                    self.emit(NodeEvent::PeerLeft(token));
+                }
+            }
+        }
+    }
+
+    fn handle_network(&self, event: NetworkEvent) {
+        match event {
+            NetworkEvent::Connected { token, public_key } => {
+                self.set_connected(&token, Some(public_key));
+            }
+            NetworkEvent::Disconnected { token, timed_out } => {
+                self.state.borrow_mut().feeds.retain(|(peer, _), _| *peer != token);
+
+                // Peers which vanished without saying goodbye are gone until
+                // discovery finds them again
+                if timed_out && self.state.borrow_mut().peers.remove(&token).is_some() {
+                    self.emit(NodeEvent::PeerLeft(token));
+                } else {
+                    self.set_connected(&token, None);
+                }
+            }
+            NetworkEvent::Latency { token, rtt } => {
+                let is_known = match self.state.borrow_mut().peers.get_mut(&token) {
+                    Some(peer) => {
+                        peer.latency = Some(rtt);
+                        true
+                    }
+                    None => false,
+                };
+
+                if is_known {
+                    self.emit(NodeEvent::PeerUpdated(token));
+                }
+            }
+            NetworkEvent::Opened { token, discovery_key } => {
+                if discovery_key != self.state.borrow().discovery_key {
+                    return;
+                }
+
+                // Start over, requests sent on an earlier connection are lost
+                let haves: Vec<Vec<u8>> = {
+                    let mut state = self.state.borrow_mut();
+                    state.feeds.retain(|(peer, _), _| *peer != token);
+
+                    // Forks are told about right away
+                    let forks = state.logs.values().filter_map(|log| {
+                        log.fork_proof().map(|proof| {
+                            replication::encode(log.public_key(), &ReplicationMessage::Fork { proof: proof.clone() })
+                        })
+                    });
+
+                    std::iter::once(&state.log)
+                        .chain(state.logs.values())
+                        .map(|log| replication::encode(log.public_key(), &Feed::have(log)))
+                        .chain(forks)
+                        .collect()
+                };
+
+                for have in haves {
+                    self.network.send(&token, &discovery_key, have);
+                }
+            }
+            NetworkEvent::Message { token, discovery_key, message } => {
+                if discovery_key != self.state.borrow().discovery_key {
+                    return;
+                }
+
+                match replication::decode(&message) {
+                    Ok((author, message)) => self.handle_replication(&token, &author, message),
+                    Err(_) => self.network.violation(&token),
+                }
+            }
+            NetworkEvent::Warning(warning) => self.warn(warning),
+        }
+    }
+
+    // Handles a replication message of a peer about the log of an author
+    fn handle_replication(&self, token: &str, author: &[u8], message: ReplicationMessage) {
+        if let ReplicationMessage::Have { bitfield, .. } = &message {
+            if let Some(last) = bitfield.last() {
+                self.handle_remote_length(author, last);
+            }
+        }
+
+        let is_new_log = {
+            let mut state = self.state.borrow_mut();
+
+            if author == state.log.public_key() || state.logs.contains_key(author) {
+                false
+            } else if state.logs.len() >= MAX_LOGS {
+                return;
+            } else {
+                match Log::remote(author) {
+                    Ok(log) => {
+                        state.logs.insert(author.to_vec(), log);
+                        true
+                    }
+                    Err(_) => {
+                        drop(state);
+                        self.network.violation(token);
+                        return;
+                    }
+                }
+            }
+        };
+
+        // Tell other peers about entries we get of this log
+        if is_new_log {
+            self.announce(author);
+            self.store(author);
+        }
+
+        let (result, discovery_key) = {
+            let mut state = self.state.borrow_mut();
+            let state = &mut *state;
+
+            let log = if author == state.log.public_key() {
+                &mut state.log
+            } else {
+                state.logs.get_mut(author).unwrap()
+            };
+
+            let is_seeding = state.storage.is_some();
+
+            let feed = state.feeds
+                .entry((token.to_string(), author.to_vec()))
+                .or_insert_with(|| if is_seeding { Feed::complete() } else { Feed::new() });
+
+            let result = feed.handle(log, message).map(|outcome| {
+                let entries: Vec<(u64, Vec<u8>)> = outcome.received
+                    .iter()
+                    .filter_map(|sequence_number| {
+                        log.get(*sequence_number as usize - 1).map(|data| (*sequence_number, data))
+                    })
+                    .collect();
+
+                (outcome.replies, entries, outcome.fork)
+            });
+
+            (result, state.discovery_key.clone())
+        };
+
+        let (replies, entries, fork) = match result {
+            Ok(result) => result,
+            Err(_) => {
+                self.network.violation(token);
+                return;
+            }
+        };
+
+        for reply in replies {
+            self.network.send(token, &discovery_key, replication::encode(author, &reply));
+        }
+
+        for (sequence_number, data) in entries {
+            // Entries are signed, but their payload can still be garbage
+            let _ = self.handle_entry(author, sequence_number, &data);
+        }
+
+        if let Some(proof) = fork {
+            self.handle_fork(author, proof);
+        }
+    }
+
+    // Warns about an author who signed conflicting entries and passes the
+    // proof on to all peers
+    fn handle_fork(&self, author: &[u8], proof: ForkProof) {
+        let (discovery_key, path) = {
+            let state = self.state.borrow();
+            let path = state.storage.as_ref().map(|dir| dir.join(format!("{}.fork", hex::encode(author))));
+            (state.discovery_key.clone(), path)
+        };
+
+        // Seeders keep the proof next to the log
+        if let Some(path) = path {
+            let result = storage::append_entry(&path, &proof.first)
+                .and_then(|_| storage::append_entry(&path, &proof.second));
+
+            if let Err(err) = result {
+                self.warn(format!("Could not store proof of fork. {}", err));
+            }
+        }
+
+        let message = ReplicationMessage::Fork { proof };
+        self.network.broadcast(&discovery_key, replication::encode(author, &message));
+
+        self.emit(NodeEvent::Info(format!(
+            "Warning: {} signed conflicting entries, their messages can not be trusted",
+            display_name(author, self.nickname_of(author).as_deref()))));
+    }
+
+    // Pushes announcements of new entries of a log to all peers
+    fn announce(&self, author: &[u8]) {
+        let announcements = {
+            let mut state = self.state.borrow_mut();
+
+            let log = if author == state.log.public_key() {
+                &mut state.log
+            } else {
+                match state.logs.get_mut(author) {
+                    Some(log) => log,
+                    None => return,
+                }
+            };
+
+            Feed::announcements(log)
+        };
+
+        let node = self.clone();
+        let author = author.to_vec();
+
+        // Ends when the log is dropped
+        self.handle.spawn(announcements.for_each(move |message| {
+            let discovery_key = node.state.borrow().discovery_key.clone();
+            node.network.broadcast(&discovery_key, replication::encode(&author, &message));
+            Ok(())
+        }));
+    }
+
+    // Writes every entry a remote log gets from now on to its file, when
+    // seeding the channel
+    fn store(&self, author: &[u8]) {
+        let (entries, path) = {
+            let mut state = self.state.borrow_mut();
+
+            let path = match &state.storage {
+                Some(dir) => dir.join(hex::encode(author)),
+                None => return,
+            };
+
+            match state.logs.get_mut(author) {
+                Some(log) => (log.subscribe(), path),
+                None => return,
+            }
+        };
+
+        let node = self.clone();
+        let author = author.to_vec();
+
+        // Ends when the log is dropped
+        self.handle.spawn(entries.for_each(move |sequence_number| {
+            let entry = node.state.borrow().logs
+                .get(&author)
+                .and_then(|log| log.entry_bytes(sequence_number));
+
+            if let Some(entry) = entry {
+                if let Err(err) = storage::append_entry(&path, &entry) {
+                    node.warn(format!("Could not store entry. {}", err));
+                }
+            }
+
+            Ok(())
+        }));
+    }
+
+    fn set_connected(&self, token: &str, public_key: Option<Vec<u8>>) {
+        let is_known = match self.state.borrow_mut().peers.get_mut(token) {
+            Some(peer) => {
+                peer.connected = public_key.is_some();
+
+                if public_key.is_some() {
+                    peer.public_key = public_key;
+                }
+
+                true
+            }
+            None => false,
+        };
+
+        if is_known {
+            self.emit(NodeEvent::PeerUpdated(token.to_string()));
+        }
+    }
+
+    fn emit(&self, event: NodeEvent) {
+        // Forget about subscribers which went away
+        self.state.borrow_mut().subscribers
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod node {
+    use super::*;
+
+    use chrono::TimeZone;
+    use ed25519_dalek::Keypair;
+    use futures::{future, Async, Poll};
+    use tokio::timer::Interval;
+    use tokio_core::reactor::{Core, Timeout};
+
+    fn message_payload(text: &str) -> Payload {
+        Payload::Message {
+            text: text.to_string(),
+            timestamp: Local.ymd(2019, 8, 1).and_hms(12, 30, 0),
+        }
+    }
+
+    // Runs the core until the next message arrives or a second passed
+    fn next_message(core: &mut Core, events: &mut UnboundedReceiver<NodeEvent>) -> Option<String> {
+        let mut timeout = Timeout::new(Duration::from_secs(1), &core.handle()).unwrap();
+
+        core.run(future::poll_fn(|| -> Poll<Option<String>, std::io::Error> {
+            while let Async::Ready(Some(event)) = events.poll().unwrap() {
+                if let NodeEvent::Message(message) = event {
+                    return Ok(Async::Ready(Some(message.text)));
+                }
+            }
+
+            match timeout.poll()? {
+                Async::Ready(()) => Ok(Async::Ready(None)),
+                Async::NotReady => Ok(Async::NotReady),
+            }
+        })).unwrap()
+    }
+
+    // Runs the core until the condition holds or a second passed, returns
+    // whether it holds
+    fn run_until<F: Fn() -> bool>(core: &mut Core, condition: F) -> bool {
+        let mut timeout = Timeout::new(Duration::from_secs(1), &core.handle()).unwrap();
+        let mut interval = Interval::new_interval(Duration::from_millis(10));
+
+        core.run(future::poll_fn(|| -> Poll<bool, std::io::Error> {
+            while let Async::Ready(Some(_)) = interval.poll().map_err(std::io::Error::other)? {
+                if condition() {
+                    return Ok(Async::Ready(true));
+                }
+            }
+
+            match timeout.poll()? {
+                Async::Ready(()) => Ok(Async::Ready(condition())),
+                Async::NotReady => Ok(Async::NotReady),
+            }
+        })).unwrap()
+    }
+
+    #[test]
+    fn detects_mentions() {
+        assert!(mentions("Hello adz!", "adz"));
+        assert!(mentions("@ADZ what's up", "adz"));
+        assert!(!mentions("Hello adzy", "adz"));
+        assert!(!mentions("Hello", ""));
+    }
+
+    #[test]
+    fn channel_url_roundtrip() {
+        let key = [7; 32];
+        let url = channel_url(&key);
+
+        assert!(url.starts_with(URL_PROTOCOL));
+        assert_eq!(parse_channel_url(&url), Ok(key.to_vec()));
+        assert_eq!(parse_channel_url(&hex::encode(key)), Ok(key.to_vec()));
+
+        assert!(parse_channel_url("chat://xyz").is_err());
+        assert!(parse_channel_url("chat://0102").is_err());
+    }
+
+    #[test]
+    fn warns_about_same_nicknames() {
+        let core = Core::new().unwrap();
+        let node = Node::new(core.handle(), Shutdown::new());
+        let events = node.subscribe();
+
+        node.set_nickname("adz").unwrap();
+        assert!(node.set_nickname("a dz").is_err());
+        assert_eq!(node.nickname(), Some(String::from("adz")));
+
+        let other = [7; 32];
+        let profile = Payload::Profile { nickname: String::from("adz") };
+        let message = message_payload("Hello, Test!");
+
+        node.handle_entry(&other, 1, &profile.to_bytes()).unwrap();
+        node.handle_entry(&other, 2, &message.to_bytes()).unwrap();
+        assert!(node.handle_entry(&other, 3, &[]).is_err());
+
+        let events: Vec<NodeEvent> = events.take(4).wait().map(Result::unwrap).collect();
+
+        match &events[2] {
+            NodeEvent::Info(text) => assert!(text.contains("adz is claimed by 070707 and")),
+            _ => panic!("Expected warning"),
+        }
+
+        match &events[3] {
+            NodeEvent::Message(message) => {
+                assert_eq!(message.display_name(), "adz (070707)");
+
+                // Messages show when they were sent, not when they arrived
+                assert_eq!(message.timestamp, Local.ymd(2019, 8, 1).and_hms(12, 30, 0));
+            }
+            _ => panic!("Expected message"),
+        }
+    }
+
+    #[test]
+    fn tracks_sync_state() {
+        let mut core = Core::new().unwrap();
+        let a = Node::new(core.handle(), Shutdown::new());
+        let b = Node::new(core.handle(), Shutdown::new());
+
+        for index in 0..60 {
+            a.send_message(&format!("Message {}", index));
+        }
+
+        let channel_key = a.public_key();
+        a.join(&channel_key);
+        b.join(&channel_key);
+
+        b.send_message("Hello!");
+        assert_eq!(b.sync_state(), SyncState { local: 1, remote: None });
+
+        let port = a.network.listen().unwrap();
+        b.network.add_peer(&a.token, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        // Only the recent entries are fetched, older ones do not count
+        assert!(run_until(&mut core, || b.sync_state().remote.is_some() && b.sync_state().is_synced()));
+        assert_eq!(b.sync_state(), SyncState { local: 1 + RECENT_ENTRIES, remote: Some(1 + RECENT_ENTRIES) });
+    }
+
+    #[test]
+    fn replicates_between_nodes() {
+        let mut core = Core::new().unwrap();
+        let a = Node::new(core.handle(), Shutdown::new());
+        let b = Node::new(core.handle(), Shutdown::new());
+        let mut events = b.subscribe();
+
+        a.send_message("Before");
+
+        let channel_key = a.public_key();
+        a.join(&channel_key);
+        b.join(&channel_key);
+
+        // Connect directly instead of waiting for discovery
+        let port = a.network.listen().unwrap();
+        b.network.add_peer(&a.token, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        assert_eq!(next_message(&mut core, &mut events), Some(String::from("Before")));
+
+        // New messages are pushed right away
+        a.send_message("After");
+        assert_eq!(next_message(&mut core, &mut events), Some(String::from("After")));
+
+        assert_eq!(b.log_progress(&channel_key), (2, Some(2)));
+    }
+
+    #[test]
+    fn fetches_older_messages() {
+        let mut core = Core::new().unwrap();
+        let a = Node::new(core.handle(), Shutdown::new());
+        let b = Node::new(core.handle(), Shutdown::new());
+        let mut events = b.subscribe();
+
+        for index in 0..60 {
+            a.send_message(&format!("Message {}", index));
+        }
+
+        let channel_key = a.public_key();
+        a.join(&channel_key);
+        b.join(&channel_key);
+
+        let port = a.network.listen().unwrap();
+        b.network.add_peer(&a.token, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        // Only the recent messages are fetched at first
+        for index in 10..60 {
+            assert_eq!(next_message(&mut core, &mut events), Some(format!("Message {}", index)));
+        }
+
+        assert!(b.fetch_older());
+
+        for index in 0..10 {
+            assert_eq!(next_message(&mut core, &mut events), Some(format!("Message {}", index)));
+        }
+
+        assert!(!b.fetch_older());
+        assert_eq!(b.messages().len(), 60);
+    }
+
+    #[test]
+    fn keeps_own_logs_per_channel() {
+        let mut core = Core::new().unwrap();
+        let a = Node::new(core.handle(), Shutdown::new());
+        let b = Node::new(core.handle(), Shutdown::new());
+        let mut events = b.subscribe();
+
+        a.join(&[1; 32]);
+        a.send_message("Secret");
+        a.join(&[2; 32]);
+        a.send_message("Hello");
+        b.join(&[2; 32]);
+
+        assert!(a.export().logs.iter().all(|log| log.len() == 1));
+
+        let port = a.network.listen().unwrap();
+        b.network.add_peer(&a.token, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        assert_eq!(next_message(&mut core, &mut events), Some(String::from("Hello")));
+        assert_eq!(next_message(&mut core, &mut events), None);
+
+        // Joining again continues the log of the channel
+        a.join(&[1; 32]);
+        assert_eq!(a.send_message("Again").sequence_number, 2);
+    }
+
+    #[test]
+    fn imports_exported_logs() {
+        let core = Core::new().unwrap();
+        let a = Node::new(core.handle(), Shutdown::new());
+        let b = Node::new(core.handle(), Shutdown::new());
+
+        a.set_nickname("adz").unwrap();
+        a.send_message("Hello");
+        a.send_message("Test");
+
+        let archive = Archive::from_bytes(&a.export().to_bytes()).unwrap();
+
+        // Importing joins the channel, twice adds nothing new
+        assert_eq!(b.import(archive), 3);
+        assert_eq!(b.channel_key(), a.channel_key());
+        assert_eq!(b.import(a.export()), 0);
+
+        let texts: Vec<String> = b.messages().into_iter().map(|message| message.text).collect();
+        assert_eq!(texts, vec![String::from("Hello"), String::from("Test")]);
+        assert_eq!(b.nickname_of(&a.public_key()), Some(String::from("adz")));
+    }
+
+    #[test]
+    fn compacts_to_checkpoints() {
+        let core = Core::new().unwrap();
+        let a = Node::new(core.handle(), Shutdown::new());
+        let b = Node::new(core.handle(), Shutdown::new());
+
+        a.set_nickname("adz").unwrap();
+
+        for index in 0..3 {
+            a.send_message(&format!("Message {}", index));
+        }
+
+        assert_eq!(a.checkpoint(), (5, 4));
+
+        // Peers starting at the checkpoint learn the nickname again
+        let archive = a.export();
+        assert_eq!(archive.logs[0].count(), 2);
+
+        b.import(archive);
+        assert_eq!(b.nickname_of(&a.public_key()), Some(String::from("adz")));
+        assert_eq!(b.log_progress(&a.public_key()), (6, None));
+    }
+
+    #[test]
+    fn warns_about_forks() {
+        let core = Core::new().unwrap();
+        let node = Node::new(core.handle(), Shutdown::new());
+        let events = node.subscribe();
+
+        let keypair = crypto::generate_keypair();
+        let mut log = Log::with_keypair(Keypair::from_bytes(&keypair.to_bytes()).unwrap());
+        let mut fork = Log::with_keypair(keypair);
+
+        log.append(&message_payload("Hello").to_bytes()).unwrap();
+        fork.append(&message_payload("Hallo").to_bytes()).unwrap();
+
+        let author = log.public_key().to_vec();
+        node.handle_replication("a", &author, Feed::have(&log));
+
+        for entry in [log.entry_bytes(1).unwrap(), fork.entry_bytes(1).unwrap()] {
+            node.handle_replication("a", &author, ReplicationMessage::Data { entry });
+        }
+
+        assert!(node.is_forked(&author));
+
+        let events: Vec<NodeEvent> = events.take(2).wait().map(Result::unwrap).collect();
+
+        match &events[1] {
+            NodeEvent::Info(text) => assert!(text.contains("signed conflicting entries")),
+            _ => panic!("Expected warning"),
+        }
+    }
+
+    #[test]
+    fn seeds_across_restarts() {
+        let mut core = Core::new().unwrap();
+        let dir = std::env::temp_dir().join(format!("p2p-chat-seed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let a = Node::new(core.handle(), Shutdown::new());
+        let channel_key = a.public_key();
+
+        for index in 0..60 {
+            a.send_message(&format!("Message {}", index));
+        }
+
+        a.join(&channel_key);
+
+        // Seeders fetch all entries, not only the recent ones
+        let seeder = Node::new(core.handle(), Shutdown::new());
+        seeder.seed(&channel_key, &dir).unwrap();
+
+        let port = a.network.listen().unwrap();
+        seeder.network.add_peer(&a.token, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        assert!(run_until(&mut core, || seeder.log_progress(&channel_key) == (60, Some(60))));
+
+        // Stored entries are served after a restart, with their author gone
+        drop(seeder);
+        a.leave();
+
+        let seeder = Node::new(core.handle(), Shutdown::new());
+        seeder.seed(&channel_key, &dir).unwrap();
+        assert_eq!(seeder.log_progress(&channel_key), (60, None));
+
+        let b = Node::new(core.handle(), Shutdown::new());
+        let mut events = b.subscribe();
+        b.join(&channel_key);
+
+        let port = seeder.network.listen().unwrap();
+        b.network.add_peer(&seeder.token, SocketAddr::from(([127, 0, 0, 1], port)));
+
+        assert_eq!(next_message(&mut core, &mut events), Some(String::from("Message 10")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
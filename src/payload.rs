@@ -0,0 +1,108 @@
+//! Data stored in the entries of a chat log
+
+use std::fmt;
+use std::io::Cursor;
+use std::str;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, Local, LocalResult, TimeZone};
+
+const TYPE_MESSAGE: u8 = 0;
+const TYPE_PROFILE: u8 = 1;
+
+// Type 0xff is taken by checkpoints of the log, see log::CHECKPOINT_MARKER
+
+#[derive(Debug, PartialEq)]
+pub enum PayloadError {
+    Empty,
+    UnknownType(u8),
+    InvalidText,
+    InvalidTimestamp,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadError::Empty => write!(f, "payload is empty"),
+            PayloadError::UnknownType(kind) => write!(f, "unknown payload type {}", kind),
+            PayloadError::InvalidText => write!(f, "payload is not valid UTF-8"),
+            PayloadError::InvalidTimestamp => write!(f, "payload has an invalid timestamp"),
+        }
+    }
+}
+
+/// Content of a log entry, prefixed with one byte naming its type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    /// Chat message written by the owner of the log, with the time they
+    /// sent it, precise to milliseconds
+    Message { text: String, timestamp: DateTime<Local> },
+
+    /// Profile of the owner of the log, the newest entry is valid
+    Profile { nickname: String },
+}
+
+impl Payload {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let text = match self {
+            Payload::Message { text, timestamp } => {
+                bytes.push(TYPE_MESSAGE);
+                bytes.write_i64::<BigEndian>(timestamp.timestamp_millis()).unwrap();
+                text
+            }
+            Payload::Profile { nickname } => {
+                bytes.push(TYPE_PROFILE);
+                nickname
+            }
+        };
+
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PayloadError> {
+        let (kind, data) = bytes.split_first().ok_or(PayloadError::Empty)?;
+        let text = |data| str::from_utf8(data).map(str::to_string).map_err(|_| PayloadError::InvalidText);
+
+        match *kind {
+            TYPE_MESSAGE => {
+                let millis = Cursor::new(data).read_i64::<BigEndian>().map_err(|_| PayloadError::InvalidTimestamp)?;
+
+                let timestamp = match Local.timestamp_millis_opt(millis) {
+                    LocalResult::Single(timestamp) => timestamp,
+                    _ => return Err(PayloadError::InvalidTimestamp),
+                };
+
+                Ok(Payload::Message { text: text(&data[8..])?, timestamp })
+            }
+            TYPE_PROFILE => Ok(Payload::Profile { nickname: text(data)? }),
+            kind => Err(PayloadError::UnknownType(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod payload {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let message = Payload::Message {
+            text: String::from("Hello, Test!"),
+            timestamp: Local.ymd(2019, 8, 1).and_hms_milli(12, 30, 0, 250),
+        };
+        let profile = Payload::Profile { nickname: String::from("adz") };
+
+        assert_eq!(Payload::from_bytes(&message.to_bytes()), Ok(message));
+        assert_eq!(Payload::from_bytes(&profile.to_bytes()), Ok(profile));
+
+        assert_eq!(Payload::from_bytes(&[]), Err(PayloadError::Empty));
+        assert_eq!(Payload::from_bytes(&[7, 1]), Err(PayloadError::UnknownType(7)));
+        assert_eq!(Payload::from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff]), Err(PayloadError::InvalidText));
+        assert_eq!(Payload::from_bytes(&[0, 1, 2]), Err(PayloadError::InvalidTimestamp));
+        assert_eq!(Payload::from_bytes(&[0, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]), Err(PayloadError::InvalidTimestamp));
+        assert_eq!(Payload::from_bytes(&[1, 0xff]), Err(PayloadError::InvalidText));
+    }
+}
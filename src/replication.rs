@@ -0,0 +1,666 @@
+//! Sparse replication of logs between two peers
+//!
+//! Peers announce which entries of a log they hold with HAVE messages and
+//! request the ones they miss with WANT messages, which are answered with
+//! DATA messages carrying single entries. Only recent history is fetched
+//! at first, older ranges follow on demand when the user scrolls back.
+//! New entries are announced to connected peers as soon as they arrive.
+//! Conflicting entries signed by the owner of a log prove it forked, the
+//! proof is passed on to all peers with FORK messages.
+
+use std::cmp;
+use std::fmt;
+use std::io::Cursor;
+use std::ops::Range;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ed25519_dalek::PUBLIC_KEY_LENGTH;
+use futures::Stream;
+
+use crate::bitfield::{Bitfield, BitfieldError};
+use crate::log::{ForkProof, Log, LogError};
+
+const TYPE_HAVE: u8 = 0;
+const TYPE_WANT: u8 = 1;
+const TYPE_DATA: u8 = 2;
+const TYPE_FORK: u8 = 3;
+
+/// Number of newest entries fetched of every log right away.
+pub const RECENT_ENTRIES: u64 = 50;
+
+// Entries answered for one WANT message at most
+const MAX_WANT_LENGTH: u64 = 1024;
+
+// Entries requested of one peer at a time at most, more follow once all
+// of them arrived
+const MAX_REQUESTED: u64 = 4 * MAX_WANT_LENGTH;
+
+#[derive(Debug, PartialEq)]
+pub enum ReplicationError {
+    Empty,
+    UnknownType(u8),
+    Truncated,
+    InvalidBitfield(BitfieldError),
+    InvalidEntry(LogError),
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplicationError::Empty => write!(f, "message is empty"),
+            ReplicationError::UnknownType(kind) => write!(f, "unknown message type {}", kind),
+            ReplicationError::Truncated => write!(f, "message is truncated"),
+            ReplicationError::InvalidBitfield(err) => write!(f, "invalid bitfield: {}", err),
+            ReplicationError::InvalidEntry(err) => write!(f, "invalid entry: {}", err),
+        }
+    }
+}
+
+/// Message exchanged about one log, prefixed with one byte naming its type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    /// Entries the sender holds, in addition to the ones announced before,
+    /// and the sequence number of its latest checkpoint, 0 when it has none
+    Have { checkpoint: u64, bitfield: Bitfield },
+
+    /// Entries the sender asks for, end is exclusive
+    Want { start: u64, end: u64 },
+
+    /// One entry as encoded by the log
+    Data { entry: Vec<u8> },
+
+    /// Two entries proving the owner forked the log
+    Fork { proof: ForkProof },
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        match self {
+            Message::Have { checkpoint, bitfield } => {
+                bytes.push(TYPE_HAVE);
+                bytes.write_u64::<BigEndian>(*checkpoint).unwrap();
+                bytes.extend_from_slice(&bitfield.to_bytes());
+            }
+            Message::Want { start, end } => {
+                bytes.push(TYPE_WANT);
+                bytes.write_u64::<BigEndian>(*start).unwrap();
+                bytes.write_u64::<BigEndian>(*end).unwrap();
+            }
+            Message::Data { entry } => {
+                bytes.push(TYPE_DATA);
+                bytes.extend_from_slice(entry);
+            }
+            Message::Fork { proof } => {
+                bytes.push(TYPE_FORK);
+                bytes.write_u64::<BigEndian>(proof.first.len() as u64).unwrap();
+                bytes.extend_from_slice(&proof.first);
+                bytes.extend_from_slice(&proof.second);
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ReplicationError> {
+        let (kind, data) = bytes.split_first().ok_or(ReplicationError::Empty)?;
+
+        match *kind {
+            TYPE_HAVE => {
+                let checkpoint = Cursor::new(data).read_u64::<BigEndian>().map_err(|_| ReplicationError::Truncated)?;
+                let bitfield = Bitfield::from_bytes(&data[8..]).map_err(ReplicationError::InvalidBitfield)?;
+                Ok(Message::Have { checkpoint, bitfield })
+            }
+            TYPE_WANT => {
+                let mut data = Cursor::new(data);
+                let start = data.read_u64::<BigEndian>().map_err(|_| ReplicationError::Truncated)?;
+                let end = data.read_u64::<BigEndian>().map_err(|_| ReplicationError::Truncated)?;
+                Ok(Message::Want { start, end })
+            }
+            TYPE_DATA => Ok(Message::Data { entry: data.to_vec() }),
+            TYPE_FORK => {
+                let mut cursor = Cursor::new(data);
+                let length = cursor.read_u64::<BigEndian>().map_err(|_| ReplicationError::Truncated)?;
+                let entries = &data[8..];
+
+                if length > entries.len() as u64 {
+                    return Err(ReplicationError::Truncated);
+                }
+
+                let (first, second) = entries.split_at(length as usize);
+
+                Ok(Message::Fork {
+                    proof: ForkProof {
+                        first: first.to_vec(),
+                        second: second.to_vec(),
+                    },
+                })
+            }
+            kind => Err(ReplicationError::UnknownType(kind)),
+        }
+    }
+}
+
+/// Encodes a message about the log of an author, prefixed with its public
+/// key, as sent over the channel.
+pub fn encode(author: &[u8], message: &Message) -> Vec<u8> {
+    let mut bytes = author.to_vec();
+    bytes.extend_from_slice(&message.to_bytes());
+    bytes
+}
+
+/// Decodes the author and message sent over the channel.
+pub fn decode(bytes: &[u8]) -> Result<(Vec<u8>, Message), ReplicationError> {
+    if bytes.len() <= PUBLIC_KEY_LENGTH {
+        return Err(ReplicationError::Truncated);
+    }
+
+    let (author, message) = bytes.split_at(PUBLIC_KEY_LENGTH);
+    Ok((author.to_vec(), Message::from_bytes(message)?))
+}
+
+/// Result of handling a message.
+#[derive(Debug, Default, PartialEq)]
+pub struct Outcome {
+    /// Messages to send back to the peer
+    pub replies: Vec<Message>,
+
+    /// Sequence numbers of entries added to the log
+    pub received: Vec<u64>,
+
+    /// Proof of the log being forked, when we just learned about it
+    pub fork: Option<ForkProof>,
+}
+
+/// State of replicating one log with one peer.
+#[derive(Default)]
+pub struct Feed {
+    // Entries the peer announced
+    remote: Bitfield,
+
+    // Entries we asked for and did not receive yet
+    requested: Bitfield,
+
+    // Oldest sequence number we are interested in, 0 before the first HAVE
+    window_start: u64,
+
+    // Fetch entries before the latest checkpoint as well
+    complete: bool,
+}
+
+impl Feed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a feed fetching every entry the peer has instead of only the
+    /// recent ones, like seeders do. Checkpoints are not trusted, entries
+    /// before them are fetched as well.
+    pub fn complete() -> Self {
+        Self {
+            window_start: 1,
+            complete: true,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the message announcing the entries we hold.
+    pub fn have(log: &Log) -> Message {
+        Message::Have {
+            checkpoint: log.latest_checkpoint().map_or(0, |(sequence_number, _)| sequence_number),
+            bitfield: log.bitfield().clone(),
+        }
+    }
+
+    /// Returns HAVE messages for every entry the log gets from now on, to
+    /// push them to all connected peers right away.
+    pub fn announcements(log: &mut Log) -> impl Stream<Item = Message, Error = ()> {
+        log.subscribe().map(|sequence_number| {
+            let mut bitfield = Bitfield::new();
+            bitfield.set(sequence_number, true);
+            Message::Have { checkpoint: 0, bitfield }
+        })
+    }
+
+    /// Handles a message of the peer, entries it sends are added to the log.
+    pub fn handle(&mut self, log: &mut Log, message: Message) -> Result<Outcome, ReplicationError> {
+        let mut outcome = Outcome::default();
+
+        match message {
+            Message::Have { checkpoint, bitfield } => {
+                // Peers never lose entries, announcements only add to them
+                self.remote.union(&bitfield);
+
+                // Entries before the checkpoint of the peer are summarized by
+                // it, the window starts there when it holds it
+                if self.window_start == 0 {
+                    let last = self.remote.last().unwrap_or(0);
+                    self.window_start = cmp::max(1, (last + 1).saturating_sub(RECENT_ENTRIES));
+
+                    if self.remote.get(checkpoint) {
+                        self.window_start = cmp::max(self.window_start, checkpoint);
+                    }
+                }
+
+                outcome.replies = self.wants(log);
+            }
+            Message::Want { start, end } => {
+                let end = cmp::min(end, start.saturating_add(MAX_WANT_LENGTH));
+
+                // Positions in the log start at 0, sequence numbers at 1
+                outcome.replies = log
+                    .get_range(start.saturating_sub(1) as usize..end.saturating_sub(1) as usize)
+                    .map(|entry| Message::Data { entry: entry.to_bytes() })
+                    .collect();
+            }
+            Message::Data { entry } => {
+                let sequence_number = Log::sequence_number(&entry).map_err(ReplicationError::InvalidEntry)?;
+
+                // Peers only fill in what we asked for or send other versions
+                // of entries we hold, which might prove a fork
+                let was_requested = self.requested.get(sequence_number);
+
+                if !was_requested && !log.bitfield().get(sequence_number) {
+                    return Ok(outcome);
+                }
+
+                let count = log.count();
+                let was_forked = log.fork_proof().is_some();
+
+                match log.insert(&entry) {
+                    Ok(sequence_number) => {
+                        self.requested.set(sequence_number, false);
+
+                        // Entries we held already are no news
+                        if log.count() > count {
+                            outcome.received.push(sequence_number);
+                        }
+                    }
+                    // The peer is not to blame for what the owner signed
+                    Err(LogError::Conflict(sequence_number)) if log.fork_proof().is_some() => {
+                        self.requested.set(sequence_number, false);
+
+                        if !was_forked {
+                            outcome.fork = log.fork_proof().cloned();
+                        }
+                    }
+                    Err(err) => return Err(ReplicationError::InvalidEntry(err)),
+                }
+
+                // The next entries are requested once all asked for arrived
+                if was_requested && self.requested.last().is_none() {
+                    outcome.replies = self.wants(log);
+                }
+            }
+            Message::Fork { proof } => {
+                if log.add_fork_proof(proof.clone()).map_err(ReplicationError::InvalidEntry)? {
+                    outcome.fork = Some(proof);
+                }
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Extends the window by older entries, like when scrolling back, and
+    /// returns the requests for them.
+    pub fn fetch_older(&mut self, log: &Log, count: u64) -> Vec<Message> {
+        self.window_start = cmp::max(1, self.window_start.saturating_sub(count));
+        self.wants(log)
+    }
+
+    /// Returns true when the peer announced holding the entry.
+    pub fn has(&self, sequence_number: u64) -> bool {
+        self.remote.get(sequence_number)
+    }
+
+    /// Returns the number of entries within the window the peer holds and
+    /// we miss.
+    pub fn missing(&self, log: &Log) -> u64 {
+        let last = match self.remote.last() {
+            Some(last) if self.window_start > 0 => last,
+            _ => return 0,
+        };
+
+        let start = cmp::max(self.window_start, self.oldest(log));
+
+        log.bitfield()
+            .missing(start..last + 1)
+            .into_iter()
+            .flat_map(|range| self.remote.present(range))
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+
+    /// Returns true when the peer holds older entries outside the window,
+    /// which are not summarized by a checkpoint we hold.
+    pub fn has_older(&self, log: &Log) -> bool {
+        !self.remote.present(self.oldest(log)..self.window_start).is_empty()
+    }
+
+    // Returns the oldest sequence number we fetch at all, entries before a
+    // checkpoint are summarized by it
+    fn oldest(&self, log: &Log) -> u64 {
+        match log.latest_checkpoint() {
+            Some((checkpoint, _)) if !self.complete => checkpoint,
+            _ => 1,
+        }
+    }
+
+    // Requests entries within the window the peer has and we miss
+    fn wants(&mut self, log: &Log) -> Vec<Message> {
+        let last = match self.remote.last() {
+            Some(last) => last,
+            None => return Vec::new(),
+        };
+
+        let start = cmp::max(self.window_start, self.oldest(log));
+        let mut budget = MAX_REQUESTED.saturating_sub(self.requested.count());
+
+        if budget == 0 {
+            return Vec::new();
+        }
+
+        let mut wants: Vec<Range<u64>> = Vec::new();
+
+        // Runs we miss, the peer has and we did not ask for yet
+        'runs: for missing in log.bitfield().missing(start..last + 1) {
+            for present in self.remote.present(missing) {
+                for range in self.requested.missing(present) {
+                    let end = cmp::min(range.end, range.start + budget);
+                    self.requested.set_range(range.start..end, true);
+                    budget -= end - range.start;
+
+                    // Peers do not answer longer requests in full
+                    let mut start = range.start;
+
+                    while start < end {
+                        let want_end = cmp::min(end, start + MAX_WANT_LENGTH);
+                        wants.push(start..want_end);
+                        start = want_end;
+                    }
+
+                    if budget == 0 {
+                        break 'runs;
+                    }
+                }
+            }
+        }
+
+        wants.into_iter()
+            .map(|range| Message::Want { start: range.start, end: range.end })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod replication {
+    use super::*;
+
+    use ed25519_dalek::Keypair;
+
+    use crate::crypto;
+    use crate::log::MAX_SEQUENCE_NUMBER;
+
+    // Delivers messages back and forth until both sides are quiet
+    fn exchange(a: (&mut Feed, &mut Log), b: (&mut Feed, &mut Log), messages: Vec<Message>) -> usize {
+        let (feed_a, log_a) = a;
+        let (feed_b, log_b) = b;
+
+        let mut to_b = messages;
+        let mut received = 0;
+
+        while !to_b.is_empty() {
+            let mut to_a = Vec::new();
+
+            for message in to_b.drain(..) {
+                let message = Message::from_bytes(&message.to_bytes()).unwrap();
+                to_a.extend(feed_b.handle(log_b, message).unwrap().replies);
+            }
+
+            for message in to_a {
+                let message = Message::from_bytes(&message.to_bytes()).unwrap();
+                let outcome = feed_a.handle(log_a, message).unwrap();
+                received += outcome.received.len();
+                to_b.extend(outcome.replies);
+            }
+        }
+
+        received
+    }
+
+    #[test]
+    fn encode_decode() {
+        let messages = vec![
+            Message::Have { checkpoint: 3, bitfield: Bitfield::with_length(20) },
+            Message::Want { start: 3, end: 10 },
+            Message::Data { entry: vec![1, 2, 3] },
+            Message::Fork { proof: ForkProof { first: vec![1, 2], second: vec![3] } },
+        ];
+
+        for message in messages {
+            assert_eq!(Message::from_bytes(&message.to_bytes()), Ok(message));
+        }
+
+        let message = Message::Want { start: 1, end: 2 };
+        assert_eq!(decode(&encode(&[3; PUBLIC_KEY_LENGTH], &message)), Ok((vec![3; PUBLIC_KEY_LENGTH], message)));
+        assert_eq!(decode(&[3; PUBLIC_KEY_LENGTH]), Err(ReplicationError::Truncated));
+
+        assert_eq!(Message::from_bytes(&[]), Err(ReplicationError::Empty));
+        assert_eq!(Message::from_bytes(&[TYPE_HAVE, 0, 0]), Err(ReplicationError::Truncated));
+        assert_eq!(Message::from_bytes(&[TYPE_WANT, 1]), Err(ReplicationError::Truncated));
+        assert_eq!(Message::from_bytes(&[TYPE_FORK, 0, 0, 0, 0, 0, 0, 0, 9, 1]), Err(ReplicationError::Truncated));
+        assert_eq!(Message::from_bytes(&[9]), Err(ReplicationError::UnknownType(9)));
+    }
+
+    #[test]
+    fn fetches_recent_then_older() {
+        let mut log = Log::new();
+
+        for index in 0..120 {
+            log.append(format!("Message {}", index).as_bytes()).unwrap();
+        }
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let mut feed = Feed::new();
+        let mut remote_feed = Feed::new();
+
+        // Peer announces its log, we want only the newest entries
+        let outcome = feed.handle(&mut copy, Feed::have(&log)).unwrap();
+        assert_eq!(outcome.replies, vec![Message::Want { start: 71, end: 121 }]);
+        assert_eq!(feed.missing(&copy), RECENT_ENTRIES);
+
+        let received = exchange((&mut feed, &mut copy), (&mut remote_feed, &mut log), outcome.replies);
+        assert_eq!(received, RECENT_ENTRIES as usize);
+        assert_eq!(copy.bitfield().ranges(), vec![71..121]);
+        assert_eq!(feed.missing(&copy), 0);
+        assert!(feed.has_older(&copy));
+
+        // Scrolling back fetches the next range only
+        let wants = feed.fetch_older(&copy, 30);
+        assert_eq!(wants, vec![Message::Want { start: 41, end: 71 }]);
+        exchange((&mut feed, &mut copy), (&mut remote_feed, &mut log), wants);
+
+        assert_eq!(copy.bitfield().ranges(), vec![41..121]);
+        assert_eq!(copy.get(40), Some(b"Message 40".to_vec()));
+
+        // Requested entries are not asked for twice
+        assert!(feed.fetch_older(&copy, 0).is_empty());
+
+        let wants = feed.fetch_older(&copy, 1000);
+        exchange((&mut feed, &mut copy), (&mut remote_feed, &mut log), wants);
+        assert_eq!(copy.count(), 120);
+        assert!(!feed.has_older(&copy));
+
+        // Seeders want everything right away
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let outcome = Feed::complete().handle(&mut copy, Feed::have(&log)).unwrap();
+        assert_eq!(outcome.replies, vec![Message::Want { start: 1, end: 121 }]);
+    }
+
+    #[test]
+    fn passes_on_forks() {
+        let keypair = crypto::generate_keypair();
+        let mut log = Log::with_keypair(Keypair::from_bytes(&keypair.to_bytes()).unwrap());
+        let mut fork = Log::with_keypair(keypair);
+
+        log.append(b"Hello").unwrap();
+        fork.append(b"Hallo").unwrap();
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        copy.insert(&log.entry_bytes(1).unwrap()).unwrap();
+
+        // Peers relaying the other branch are not at fault
+        let message = Message::Data { entry: fork.entry_bytes(1).unwrap() };
+        let proof = Feed::new().handle(&mut copy, message.clone()).unwrap().fork.unwrap();
+        assert_eq!(Feed::new().handle(&mut copy, message).unwrap(), Outcome::default());
+
+        // Others learn about the fork from the proof alone, once
+        let message = Message::from_bytes(&Message::Fork { proof: proof.clone() }.to_bytes()).unwrap();
+        let mut other = Log::remote(log.public_key()).unwrap();
+
+        assert_eq!(Feed::new().handle(&mut other, message.clone()).unwrap().fork, Some(proof));
+        assert_eq!(Feed::new().handle(&mut other, message).unwrap().fork, None);
+
+        let forged = ForkProof {
+            first: log.entry_bytes(1).unwrap(),
+            second: log.entry_bytes(1).unwrap(),
+        };
+
+        assert_eq!(
+            Feed::new().handle(&mut other, Message::Fork { proof: forged }),
+            Err(ReplicationError::InvalidEntry(LogError::InvalidProof)));
+    }
+
+    #[test]
+    fn stops_at_checkpoints() {
+        let mut log = Log::new();
+
+        for index in 0..100 {
+            log.append(format!("Message {}", index).as_bytes()).unwrap();
+
+            if index == 89 {
+                log.append_checkpoint().unwrap();
+            }
+        }
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let mut feed = Feed::new();
+        let mut remote_feed = Feed::new();
+
+        // Peers start at the checkpoint instead of the recent entries
+        let outcome = feed.handle(&mut copy, Feed::have(&log)).unwrap();
+        assert_eq!(outcome.replies, vec![Message::Want { start: 91, end: 102 }]);
+
+        exchange((&mut feed, &mut copy), (&mut remote_feed, &mut log), outcome.replies);
+        assert_eq!(copy.latest_checkpoint().map(|(sequence_number, _)| sequence_number), Some(91));
+
+        // Scrolling back ends at the checkpoint
+        assert!(!feed.has_older(&copy));
+        assert!(feed.fetch_older(&copy, 100).is_empty());
+        assert_eq!(copy.bitfield().ranges(), vec![91..102]);
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let outcome = Feed::complete().handle(&mut copy, Feed::have(&log)).unwrap();
+        assert_eq!(outcome.replies, vec![Message::Want { start: 1, end: 102 }]);
+    }
+
+    #[test]
+    fn drops_unrequested_entries() {
+        let mut log = Log::new();
+
+        for index in 0..3 {
+            log.append(format!("Message {}", index).as_bytes()).unwrap();
+        }
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let mut feed = Feed::new();
+
+        let message = Message::Data { entry: log.entry_bytes(3).unwrap() };
+        assert_eq!(feed.handle(&mut copy, message.clone()).unwrap(), Outcome::default());
+        assert!(copy.is_empty());
+
+        feed.handle(&mut copy, Feed::have(&log)).unwrap();
+        assert_eq!(feed.handle(&mut copy, message).unwrap().received, vec![3]);
+    }
+
+    #[test]
+    fn limits_requested_entries() {
+        let log = Log::new();
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let mut feed = Feed::complete();
+
+        // Peers can claim to hold the longest possible log
+        let have = Message::Have { checkpoint: 0, bitfield: Bitfield::with_length(MAX_SEQUENCE_NUMBER - 1) };
+        let replies = feed.handle(&mut copy, have.clone()).unwrap().replies;
+
+        let requested: u64 = replies.iter()
+            .map(|message| match message {
+                Message::Want { start, end } => end - start,
+                _ => 0,
+            })
+            .sum();
+
+        assert_eq!(replies.len() as u64, MAX_REQUESTED / MAX_WANT_LENGTH);
+        assert_eq!(requested, MAX_REQUESTED);
+        assert!(feed.handle(&mut copy, have).unwrap().replies.is_empty());
+    }
+
+    #[test]
+    fn fetches_only_what_peer_has() {
+        let mut log = Log::new();
+
+        for index in 0..10 {
+            log.append(format!("Message {}", index).as_bytes()).unwrap();
+        }
+
+        // Peer holds a sparse copy with holes itself
+        let mut sparse = Log::remote(log.public_key()).unwrap();
+
+        for sequence_number in &[2, 3, 7] {
+            sparse.insert(&log.entry_bytes(*sequence_number).unwrap()).unwrap();
+        }
+
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        copy.insert(&log.entry_bytes(3).unwrap()).unwrap();
+
+        let mut feed = Feed::new();
+        let outcome = feed.handle(&mut copy, Feed::have(&sparse)).unwrap();
+
+        assert_eq!(outcome.replies, vec![
+            Message::Want { start: 2, end: 3 },
+            Message::Want { start: 7, end: 8 },
+        ]);
+    }
+
+    #[test]
+    fn pushes_new_entries() {
+        let mut log = Log::new();
+        let mut copy = Log::remote(log.public_key()).unwrap();
+        let mut feed = Feed::new();
+        let mut remote_feed = Feed::new();
+
+        let announcements = Feed::announcements(&mut log);
+
+        log.append(b"Hello").unwrap();
+        log.append(b"Test").unwrap();
+        drop(log.subscribe());
+
+        // Every announcement leads to a request of just the new entry
+        let mut wants = Vec::new();
+
+        for message in announcements.wait().take(2) {
+            let message = Message::from_bytes(&message.unwrap().to_bytes()).unwrap();
+            wants.extend(feed.handle(&mut copy, message).unwrap().replies);
+        }
+
+        assert_eq!(wants, vec![
+            Message::Want { start: 1, end: 2 },
+            Message::Want { start: 2, end: 3 },
+        ]);
+
+        exchange((&mut feed, &mut copy), (&mut remote_feed, &mut log), wants);
+        assert_eq!(copy.get(1), Some(b"Test".to_vec()));
+    }
+}
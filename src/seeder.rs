@@ -0,0 +1,174 @@
+//! Long-running node keeping channels available without any frontend
+//!
+//! A seeder joins every channel listed in its configuration file,
+//! replicates all entries of all logs and stores them on disk, so peers can
+//! fetch the history of a channel while its authors are offline. Entries are
+//! only verified against their signatures, their contents are never read.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use serde_json::Value;
+use tokio_core::reactor::Handle;
+
+use crate::connections;
+use crate::node::{self, Node, NodeEvent};
+use crate::shutdown::{Shutdown, ShutdownReason};
+use crate::storage;
+
+/// Problems with the configuration file.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Read(String),
+    Parse(String),
+    InvalidValue(&'static str),
+    InvalidChannel(String),
+    NoChannels,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(err) => write!(f, "Could not read configuration. {}", err),
+            ConfigError::Parse(err) => write!(f, "Invalid configuration. {}", err),
+            ConfigError::InvalidValue(name) => write!(f, "Invalid configuration value for {}", name),
+            ConfigError::InvalidChannel(err) => write!(f, "Invalid configuration channel. {}", err),
+            ConfigError::NoChannels => write!(f, "Configuration lists no channels to seed"),
+        }
+    }
+}
+
+/// Channels to seed and where to keep them, read from a JSON file like:
+///
+/// ```json
+/// {
+///   "channels": ["chat://20d7eb09..."],
+///   "data_dir": "/var/lib/p2p-chat",
+///   "max_inbound": 64,
+///   "ping_timeout": 60
+/// }
+/// ```
+///
+/// Everything but the channels is optional.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub channels: Vec<Vec<u8>>,
+    pub data_dir: PathBuf,
+    pub connections: connections::Config,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|err| ConfigError::Read(err.to_string()))?;
+        Self::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, ConfigError> {
+        let value: Value = serde_json::from_str(text).map_err(|err| ConfigError::Parse(err.to_string()))?;
+
+        let channels = value.get("channels")
+            .and_then(Value::as_array)
+            .ok_or(ConfigError::NoChannels)?
+            .iter()
+            .map(|url| {
+                let url = url.as_str().ok_or(ConfigError::InvalidValue("channels"))?;
+                node::parse_channel_url(url).map_err(ConfigError::InvalidChannel)
+            })
+            .collect::<Result<Vec<Vec<u8>>, ConfigError>>()?;
+
+        if channels.is_empty() {
+            return Err(ConfigError::NoChannels);
+        }
+
+        let data_dir = match value.get("data_dir") {
+            Some(dir) => PathBuf::from(dir.as_str().ok_or(ConfigError::InvalidValue("data_dir"))?),
+            None => storage::data_dir(),
+        };
+
+        let mut connections = connections::Config::default();
+
+        for (name, limit) in &mut [("max_inbound", &mut connections.max_inbound), ("max_outbound", &mut connections.max_outbound)] {
+            if let Some(count) = value.get(*name) {
+                **limit = count.as_u64().ok_or(ConfigError::InvalidValue(name))? as usize;
+            }
+        }
+
+        for (name, duration) in &mut [("ping_interval", &mut connections.ping_interval), ("ping_timeout", &mut connections.ping_timeout)] {
+            if let Some(seconds) = value.get(*name) {
+                match seconds.as_u64() {
+                    Some(seconds) if seconds > 0 => **duration = Duration::from_secs(seconds),
+                    _ => return Err(ConfigError::InvalidValue(name)),
+                }
+            }
+        }
+
+        Ok(Self {
+            channels,
+            data_dir,
+            connections,
+        })
+    }
+}
+
+/// Seeds all configured channels until shutdown, each with its own node.
+pub fn run(
+    handle: Handle,
+    shutdown: Shutdown,
+    config: Config,
+) -> Result<impl Future<Item = ShutdownReason, Error = ()>, io::Error> {
+    for channel_key in &config.channels {
+        let node = Node::with_config(handle.clone(), shutdown.clone(), config.connections.clone());
+        let url = node::channel_url(channel_key);
+
+        let events = node.subscribe().for_each(move |event| {
+            match event {
+                // Joining reports the channel URL, which is the prefix already
+                NodeEvent::Info(text) if text == url => eprintln!("Seeding {}", url),
+                NodeEvent::Info(text) => eprintln!("{}: {}", url, text),
+                NodeEvent::PeerFound(peer) => eprintln!(
+                    "{}: New peer: {}, {}, {}", url, peer.addr(), peer.port(), peer.token()),
+                NodeEvent::PeerLeft(token) => eprintln!("{}: Peer left: {}", url, token),
+                NodeEvent::Message(_) | NodeEvent::Nickname { .. } | NodeEvent::PeerUpdated(_) => {},
+            }
+
+            Ok(())
+        });
+
+        handle.spawn(events);
+
+        node.seed(channel_key, &storage::channel_dir_in(&config.data_dir, channel_key).join("logs"))?;
+    }
+
+    Ok(shutdown.wait())
+}
+
+#[cfg(test)]
+mod seeder {
+    use super::*;
+
+    const CHANNEL: &str = "chat://20d7eb0934d482fca4f975270b8ad6e28ecbdeebad5bed8c1acd5006eec771ea";
+
+    #[test]
+    fn parses_config() {
+        let config = Config::from_json(&format!(
+            r#"{{"channels": ["{}"], "data_dir": "/tmp/seed", "max_inbound": 64, "ping_timeout": 60}}"#,
+            CHANNEL)).unwrap();
+
+        assert_eq!(config.channels, vec![node::parse_channel_url(CHANNEL).unwrap()]);
+        assert_eq!(config.data_dir, PathBuf::from("/tmp/seed"));
+        assert_eq!(config.connections.max_inbound, 64);
+        assert_eq!(config.connections.max_outbound, 8);
+        assert_eq!(config.connections.ping_timeout, Duration::from_secs(60));
+
+        assert_eq!(Config::from_json(r#"{"channels": []}"#), Err(ConfigError::NoChannels));
+        assert_eq!(
+            Config::from_json(&format!(r#"{{"channels": ["{}"], "ping_interval": 0}}"#, CHANNEL)),
+            Err(ConfigError::InvalidValue("ping_interval")));
+        assert!(matches!(Config::from_json(r#"{"channels": ["chat://xyz"]}"#), Err(ConfigError::InvalidChannel(_))));
+        assert!(matches!(Config::from_json("channels"), Err(ConfigError::Parse(_))));
+    }
+}
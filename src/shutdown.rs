@@ -0,0 +1,257 @@
+//! Coordinated shutdown of all running components
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::Handle;
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
+
+/// Reasons why the program is shutting down.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShutdownReason {
+    /// User asked to exit from the interface
+    Exit,
+
+    /// Received SIGINT (Ctrl-C outside of raw mode)
+    Interrupt,
+
+    /// Received SIGTERM
+    Terminate,
+
+    /// Received SIGHUP, the controlling terminal went away
+    Hangup,
+
+    /// A component failed and can not continue
+    Error(String),
+}
+
+impl ShutdownReason {
+    /// Returns the exit code the process should end with.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShutdownReason::Exit => 0,
+            ShutdownReason::Interrupt => 128 + SIGINT,
+            ShutdownReason::Terminate => 128 + SIGTERM,
+            ShutdownReason::Hangup => 128 + SIGHUP,
+            ShutdownReason::Error(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShutdownReason::Exit => write!(f, "exit"),
+            ShutdownReason::Interrupt => write!(f, "interrupted"),
+            ShutdownReason::Terminate => write!(f, "terminated"),
+            ShutdownReason::Hangup => write!(f, "hangup"),
+            ShutdownReason::Error(message) => write!(f, "error: {}", message),
+        }
+    }
+}
+
+type ShutdownHook = Box<dyn FnOnce()>;
+
+#[derive(Default)]
+struct ShutdownState {
+    // Reason of the first shutdown trigger, later ones are ignored
+    reason: Option<ShutdownReason>,
+
+    // Clean-up handlers, called in reverse order of registration
+    hooks: Vec<ShutdownHook>,
+
+    // Tasks waiting for the shutdown to happen, by the id of their future
+    waiting: HashMap<u64, Task>,
+    next_id: u64,
+}
+
+/// Shared handle to trigger and wait for the shutdown of the program.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Rc<RefCell<ShutdownState>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a handler which gets called once during shutdown.
+    pub fn on_shutdown<F: FnOnce() + 'static>(&self, hook: F) {
+        self.state.borrow_mut().hooks.push(Box::new(hook));
+    }
+
+    /// Starts the shutdown, only the first given reason is kept.
+    pub fn trigger(&self, reason: ShutdownReason) {
+        let mut state = self.state.borrow_mut();
+
+        if state.reason.is_some() {
+            return;
+        }
+
+        state.reason = Some(reason);
+
+        for (_, task) in state.waiting.drain() {
+            task.notify();
+        }
+    }
+
+    /// Returns the shutdown reason when it was triggered already.
+    pub fn reason(&self) -> Option<ShutdownReason> {
+        self.state.borrow().reason.clone()
+    }
+
+    /// Returns a future resolving with the reason when shutdown got triggered.
+    pub fn wait(&self) -> ShutdownFuture {
+        let id = {
+            let mut state = self.state.borrow_mut();
+            state.next_id += 1;
+            state.next_id
+        };
+
+        ShutdownFuture {
+            id,
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Runs all registered clean-up handlers, newest first.
+    pub fn run_hooks(&self) {
+        // Take hooks out first so handlers can safely use this handle
+        let hooks: Vec<ShutdownHook> = self.state.borrow_mut().hooks.drain(..).collect();
+
+        for hook in hooks.into_iter().rev() {
+            hook();
+        }
+    }
+
+    /// Listens for SIGINT, SIGTERM and SIGHUP and triggers shutdown on them.
+    pub fn listen_signals(&self, handle: &Handle) {
+        let signals = [
+            (SIGINT, ShutdownReason::Interrupt),
+            (SIGTERM, ShutdownReason::Terminate),
+            (SIGHUP, ShutdownReason::Hangup),
+        ];
+
+        for (signal, reason) in signals.iter().cloned() {
+            let shutdown = self.clone();
+            let shutdown_err = self.clone();
+
+            let listener = Signal::with_handle(signal, handle.new_tokio_handle())
+                .flatten_stream()
+                .into_future()
+                .map(move |_| shutdown.trigger(reason))
+                .map_err(move |(err, _)| {
+                    shutdown_err.trigger(signal_error(err));
+                });
+
+            handle.spawn(listener);
+        }
+    }
+}
+
+fn signal_error(err: io::Error) -> ShutdownReason {
+    ShutdownReason::Error(format!("Could not listen to signals: {}", err))
+}
+
+/// Future which resolves as soon as the shutdown got triggered.
+pub struct ShutdownFuture {
+    id: u64,
+    shutdown: Shutdown,
+}
+
+impl Future for ShutdownFuture {
+    type Item = ShutdownReason;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut state = self.shutdown.state.borrow_mut();
+
+        match &state.reason {
+            Some(reason) => Ok(Async::Ready(reason.clone())),
+            None => {
+                // Only the task of the latest poll needs to be notified
+                state.waiting.insert(self.id, task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl Drop for ShutdownFuture {
+    fn drop(&mut self) {
+        self.shutdown.state.borrow_mut().waiting.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod shutdown {
+    use super::*;
+
+    use std::cell::Cell;
+
+    use futures::future;
+
+    #[test]
+    fn first_reason_wins() {
+        let shutdown = Shutdown::new();
+
+        assert_eq!(shutdown.reason(), None);
+
+        shutdown.trigger(ShutdownReason::Hangup);
+        shutdown.trigger(ShutdownReason::Exit);
+
+        assert_eq!(shutdown.reason(), Some(ShutdownReason::Hangup));
+        assert_eq!(shutdown.wait().wait(), Ok(ShutdownReason::Hangup));
+        assert_eq!(ShutdownReason::Hangup.exit_code(), 129);
+    }
+
+    #[test]
+    fn keeps_one_task_per_future() {
+        let shutdown = Shutdown::new();
+        let mut first = shutdown.wait();
+        let second = shutdown.wait();
+
+        future::poll_fn(|| {
+            for _ in 0..100 {
+                assert_eq!(first.poll(), Ok(Async::NotReady));
+            }
+
+            Ok::<_, ()>(Async::Ready(()))
+        }).wait().unwrap();
+
+        assert_eq!(shutdown.state.borrow().waiting.len(), 1);
+
+        drop(first);
+        drop(second);
+        assert!(shutdown.state.borrow().waiting.is_empty());
+    }
+
+    #[test]
+    fn hooks_run_once_newest_first() {
+        let shutdown = Shutdown::new();
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let calls = Rc::new(Cell::new(0));
+
+        for index in 0..3 {
+            let order = order.clone();
+            let calls = calls.clone();
+
+            shutdown.on_shutdown(move || {
+                order.borrow_mut().push(index);
+                calls.set(calls.get() + 1);
+            });
+        }
+
+        shutdown.run_hooks();
+        shutdown.run_hooks();
+
+        assert_eq!(*order.borrow(), vec![2, 1, 0]);
+        assert_eq!(calls.get(), 3);
+    }
+}
@@ -0,0 +1,159 @@
+//! Line-oriented frontend reading messages from stdin and writing to stdout
+
+use std::io::{self, BufRead, Write};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::{Future, IntoFuture, Stream};
+use tokio::timer::Interval;
+use tokio_core::reactor::{Handle, Timeout};
+
+use crate::node::{self, Message, Node, NodeEvent};
+use crate::shutdown::{Shutdown, ShutdownReason};
+
+// Time peers get to fetch our last messages after stdin was closed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DELIVERY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Output format of received messages.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Plain,
+    Json,
+}
+
+fn format_message(message: &Message, format: Format) -> String {
+    match format {
+        Format::Plain => format!("{}: {}", escape(&message.display_name()), escape(&message.text)),
+        Format::Json => message.to_json().to_string(),
+    }
+}
+
+// Escapes line breaks, other control characters and backslashes, so every
+// message stays on its own line and can not control the terminal
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for chr in text.chars() {
+        match chr {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ if chr.is_control() => escaped.extend(chr.escape_unicode()),
+            _ => escaped.push(chr),
+        }
+    }
+
+    escaped
+}
+
+// Reading stdin blocks, so it happens in its own thread
+fn start_stdin_listening(tx: UnboundedSender<String>) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if tx.unbounded_send(line).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+/// Appends every line from stdin as a message and prints messages of other
+/// peers to stdout, one per line. Ends when stdin is closed and connected
+/// peers got our messages, or after a timeout.
+pub fn run(
+    handle: Handle,
+    shutdown: Shutdown,
+    node: Node,
+    format: Format,
+) -> impl Future<Item = ShutdownReason, Error = ()> {
+    let own_key = node.public_key();
+    let shutdown_events = shutdown.clone();
+
+    // Keep stdout free for messages, everything else goes to stderr
+    let events = node.subscribe().for_each(move |event| {
+        match event {
+            NodeEvent::Message(message) => {
+                if message.author == own_key {
+                    return Ok(());
+                }
+
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+
+                let result = writeln!(stdout, "{}", format_message(&message, format))
+                    .and_then(|_| stdout.flush());
+
+                // Reader of our output went away, nothing left to do
+                if result.is_err() {
+                    shutdown_events.trigger(ShutdownReason::Exit);
+                }
+            }
+            NodeEvent::PeerFound(peer) => eprintln!(
+                "New peer: {}, {}, {}", peer.addr(), peer.port(), peer.token()),
+            NodeEvent::PeerUpdated(_) => {},
+            NodeEvent::PeerLeft(token) => eprintln!("Peer left: {}", token),
+            NodeEvent::Nickname { author, nickname } => eprintln!(
+                "{} is now known as {}", node::fingerprint(&author), escape(&nickname)),
+            NodeEvent::Info(text) => eprintln!("{}", text),
+        }
+
+        Ok(())
+    });
+
+    handle.spawn(events);
+
+    let (stdin_tx, stdin_rx) = unbounded();
+    start_stdin_listening(stdin_tx);
+
+    let shutdown_stdin = shutdown.clone();
+    let node_input = node.clone();
+
+    let input = stdin_rx
+        .for_each(move |line| {
+            if !line.is_empty() {
+                node_input.send_message(&line);
+            }
+
+            Ok(())
+        })
+        // Connected peers fetch our last messages before we leave
+        .then(move |_| {
+            let timeout = Timeout::new(DELIVERY_TIMEOUT, &handle).into_future().flatten();
+
+            Interval::new_interval(DELIVERY_CHECK_INTERVAL)
+                .map_err(|_| ())
+                .take_while(move |_| Ok(!node.is_delivered()))
+                .for_each(|_| Ok(()))
+                .select2(timeout)
+        })
+        .then(move |_| -> Result<(), ()> {
+            shutdown_stdin.trigger(ShutdownReason::Exit);
+            Ok(())
+        });
+
+    input
+        .select2(shutdown.wait())
+        .then(move |_| shutdown.wait())
+}
+
+#[cfg(test)]
+mod stdio {
+    use super::*;
+
+    #[test]
+    fn escapes_control_characters() {
+        assert_eq!(escape("Hello\nadz: forged"), "Hello\\nadz: forged");
+        assert_eq!(escape("\x1b[2J\\n"), "\\u{1b}[2J\\\\n");
+        assert_eq!(escape("Grüße"), "Grüße");
+    }
+}
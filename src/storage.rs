@@ -0,0 +1,112 @@
+//! Files stored on disk
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::varint;
+
+const APP_NAME: &str = "p2p-chat";
+
+/// Returns the directory where all data of this program is stored.
+///
+/// Follows the XDG base directory specification and falls back to the
+/// system's temporary directory when no home directory is known.
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("XDG_DATA_HOME").filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir).join(APP_NAME);
+    }
+
+    match env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+        Some(home) => PathBuf::from(home).join(".local").join("share").join(APP_NAME),
+        None => env::temp_dir().join(APP_NAME),
+    }
+}
+
+/// Returns the directory for data belonging to one channel.
+pub fn channel_dir(channel_key: &[u8]) -> PathBuf {
+    channel_dir_in(&data_dir(), channel_key)
+}
+
+/// Returns the directory for data belonging to one channel below another
+/// data directory.
+pub fn channel_dir_in(data_dir: &Path, channel_key: &[u8]) -> PathBuf {
+    data_dir.join("channels").join(hex::encode(channel_key))
+}
+
+/// Reads all entries of a log file, in the order they were stored, and the
+/// length of the file up to the end of the last one.
+///
+/// An entry cut off at the end, like when we crashed while writing it, is
+/// left out. The file needs to be truncated to the returned length before
+/// appending to it again.
+pub fn read_entries(path: &Path) -> io::Result<(Vec<Vec<u8>>, u64)> {
+    let bytes = fs::read(path)?;
+    let mut remaining = bytes.as_slice();
+    let mut entries = Vec::new();
+    let mut complete = 0;
+
+    while let Some(length) = varint::read(&mut remaining) {
+        let length = length as usize;
+
+        if length > remaining.len() {
+            break;
+        }
+
+        let (entry, rest) = remaining.split_at(length);
+        entries.push(entry.to_vec());
+        remaining = rest;
+        complete = bytes.len() - remaining.len();
+    }
+
+    Ok((entries, complete as u64))
+}
+
+/// Cuts off everything after the given length of a file.
+pub fn truncate(path: &Path, length: u64) -> io::Result<()> {
+    OpenOptions::new().write(true).open(path)?.set_len(length)
+}
+
+/// Appends an entry to a log file, creating it when missing.
+pub fn append_entry(path: &Path, entry: &[u8]) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(entry.len() + 10);
+    varint::write(&mut bytes, entry.len() as u64);
+    bytes.extend_from_slice(entry);
+
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(&bytes)
+}
+
+#[cfg(test)]
+mod storage {
+    use super::*;
+
+    #[test]
+    fn appends_entries() {
+        let path = env::temp_dir().join(format!("p2p-chat-entries-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        append_entry(&path, b"Hello").unwrap();
+        append_entry(&path, &[7; 200]).unwrap();
+
+        let (entries, length) = read_entries(&path).unwrap();
+        assert_eq!(entries, vec![b"Hello".to_vec(), vec![7; 200]]);
+        assert_eq!(length, fs::metadata(&path).unwrap().len());
+
+        // Entries cut off while writing are left out
+        let mut bytes = fs::read(&path).unwrap();
+        bytes.extend_from_slice(&[10, 1, 2]);
+        fs::write(&path, bytes).unwrap();
+
+        let (entries, complete) = read_entries(&path).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(complete, length);
+
+        // Appending continues after the last complete entry
+        truncate(&path, complete).unwrap();
+        append_entry(&path, b"Test").unwrap();
+        assert_eq!(read_entries(&path).unwrap().0, vec![b"Hello".to_vec(), vec![7; 200], b"Test".to_vec()]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
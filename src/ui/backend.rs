@@ -0,0 +1,16 @@
+use std::io::Write;
+
+use futures::Stream;
+use termion::event::Event;
+
+pub enum TerminalEvent {
+    Resize((u16, u16)),
+    Input(Event),
+}
+
+/// Terminal the interface draws on, reporting its input and size changes.
+///
+/// Implemented by the real terminal and by an in-memory one for tests.
+pub trait Backend: Stream<Item = TerminalEvent, Error = ()> {
+    fn output(&mut self) -> &mut dyn Write;
+}
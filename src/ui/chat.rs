@@ -1,62 +1,342 @@
 use std::cmp;
 use std::io::{self, Write};
+use std::mem;
 
 use chrono::{DateTime, Local};
-use termion::clear::CurrentLine as ClearLine;
 use termion::cursor::Goto;
+use unicode_width::UnicodeWidthStr;
+
+use super::theme::{Style, Theme};
+use super::wrap::{strip_control, wrap};
 
 const DEFAULT_SENDER: &str = "INFO";
 
+// Minimum columns left for the text next to the sender prefix
+const MIN_TEXT_WIDTH: usize = 10;
+
+/// Decides how a message is highlighted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKind {
+    /// Information from the program itself
+    Info,
+
+    /// Message written by the user
+    Own,
+
+    /// Message of someone else
+    Other,
+
+    /// Message of someone else mentioning the user
+    Mention,
+}
+
 pub struct ChatMessage {
+    // Public key of the author, picks the colour of the sender
+    author: Option<Vec<u8>>,
+    kind: MessageKind,
     sender: Option<String>,
     text: String,
     timestamp: DateTime<Local>,
 }
 
 impl ChatMessage {
+    /// Control characters in the sender and the text of peers are removed,
+    /// they could control the terminal.
     pub fn new(sender: String, text: String) -> Self {
         Self {
-            sender: Some(sender),
-            text,
+            author: None,
+            kind: MessageKind::Other,
+            sender: Some(strip_control(&sender)),
+            text: strip_control(&text),
             timestamp: Local::now(),
         }
     }
 
     pub fn from_string(text: String) -> Self {
         Self {
+            author: None,
+            kind: MessageKind::Info,
             sender: None,
-            text,
+            text: strip_control(&text),
             timestamp: Local::now(),
         }
     }
 
-    pub fn render(&self, max_len: usize) -> String {
-        let mut line = format!("[{}] {}: {}",
-                               self.timestamp.format("%H:%M:%S").to_string(),
-                               self.sender.clone().unwrap_or(String::from(DEFAULT_SENDER)),
-                               self.text);
+    pub fn with_author(mut self, author: &[u8]) -> Self {
+        self.author = Some(author.to_vec());
+        self
+    }
+
+    pub fn with_kind(mut self, kind: MessageKind) -> Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: DateTime<Local>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    fn timestamp(&self) -> String {
+        format!("[{}]", self.timestamp.format("%H:%M:%S"))
+    }
+
+    fn sender(&self) -> &str {
+        self.sender.as_ref().map_or(DEFAULT_SENDER, String::as_str)
+    }
+
+    /// Returns the message wrapped into lines fitting the given width, with
+    /// continuation lines indented under the text column.
+    pub fn render(&self, columns: usize) -> Vec<String> {
+        self.layout(columns, &Theme::monochrome())
+            .into_iter()
+            .map(|(line, _)| line)
+            .collect()
+    }
+
+    /// Same as `render`, with colours of the theme applied and every line
+    /// padded to the full width. Escape codes are added after wrapping and
+    /// do not count towards the width.
+    pub fn render_styled(&self, columns: usize, theme: &Theme) -> Vec<String> {
+        self.layout(columns, theme)
+            .into_iter()
+            .map(|(line, width)| pad(line, width, columns))
+            .collect()
+    }
+
+    // Returns the styled lines together with their display width
+    fn layout(&self, columns: usize, theme: &Theme) -> Vec<(String, usize)> {
+        let (sender_style, text_style) = self.styles(theme);
 
-        // Truncate line when it exceeds our window width
-        if line.len() > max_len as usize {
-            line.truncate(max_len as usize - 3);
-            line.push_str("...");
+        let prefix = format!("{} {}: ", self.timestamp(), self.sender());
+        let indent = prefix.width();
+
+        // Indenting makes no sense anymore on very narrow terminals
+        if indent + MIN_TEXT_WIDTH > columns {
+            return wrap(&format!("{}{}", prefix, self.text), columns)
+                .iter()
+                .map(|line| (text_style.paint(line), line.width()))
+                .collect();
         }
 
-        line
+        wrap(&self.text, columns - indent)
+            .into_iter()
+            .enumerate()
+            .map(|(index, line)| {
+                let width = indent + line.width();
+
+                if index == 0 {
+                    (format!("{} {}: {}",
+                             theme.timestamp.paint(&self.timestamp()),
+                             sender_style.paint(self.sender()),
+                             text_style.paint(&line)), width)
+                } else {
+                    (format!("{:indent$}{}", "", text_style.paint(&line), indent = indent), width)
+                }
+            })
+            .collect()
+    }
+
+    // Returns the styles of the sender and the text
+    fn styles(&self, theme: &Theme) -> (Style, Style) {
+        let author = self.author.as_ref().map_or_else(Style::default, |key| theme.author(key));
+
+        match self.kind {
+            MessageKind::Info => (theme.info.clone(), theme.info.clone()),
+            MessageKind::Own => (theme.own.clone(), Style::default()),
+            MessageKind::Other => (author, Style::default()),
+            MessageKind::Mention => (author, theme.mention.clone()),
+        }
     }
 }
 
+// Fills the line with spaces up to the given columns
+fn pad(mut line: String, width: usize, columns: usize) -> String {
+    line.push_str(&" ".repeat(columns.saturating_sub(width)));
+    line
+}
+
 #[derive(Default)]
 pub struct Chat {
     messages: Vec<ChatMessage>,
+
+    // Number of lines hidden below the view, 0 means following the end
+    scroll: usize,
+
+    // Number of lines each message takes at the current width
+    heights: Vec<usize>,
+
+    // Messages which arrived while scrolled up
+    unread: usize,
+
+    // Size of the message area in the last render
+    height: usize,
+    columns: usize,
+
+    // Rows written in the last render, only changed rows are written again
+    drawn: Vec<String>,
+
+    theme: Theme,
 }
 
 impl Chat {
+    pub fn new(theme: Theme) -> Self {
+        Self {
+            theme,
+            ..Self::default()
+        }
+    }
+
     pub fn add_message(&mut self, message: ChatMessage) {
+        // Wrapping waits until the width is known
+        let height = if self.columns > 0 {
+            message.render(self.columns).len()
+        } else {
+            0
+        };
+
+        // Keep the view in place when the user is reading older messages
+        if self.scroll > 0 {
+            self.scroll += height;
+            self.unread += 1;
+        }
+
         self.messages.push(message);
+        self.heights.push(height);
+    }
+
+    /// Forgets what was drawn, the next render writes every row.
+    pub fn invalidate(&mut self) {
+        self.drawn.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.heights.clear();
+        self.scroll = 0;
+        self.unread = 0;
+    }
+
+    /// Number of rows to move when paging through the history.
+    pub fn page_size(&self) -> usize {
+        cmp::max(1, self.height.saturating_sub(1))
+    }
+
+    pub fn scroll_up(&mut self, amount: usize) {
+        self.set_scroll(self.scroll.saturating_add(amount));
+    }
+
+    pub fn scroll_down(&mut self, amount: usize) {
+        self.set_scroll(self.scroll.saturating_sub(amount));
+    }
+
+    pub fn scroll_to_top(&mut self) {
+        self.set_scroll(usize::MAX);
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.set_scroll(0);
+    }
+
+    /// Returns true when the oldest message is in view.
+    pub fn is_at_top(&self) -> bool {
+        let needed = self.scroll.saturating_add(self.page_size());
+        self.count_lines(needed.saturating_add(1)) <= needed
+    }
+
+    fn set_scroll(&mut self, scroll: usize) {
+        // Do not scroll further than the first page
+        let needed = scroll.saturating_add(self.page_size());
+        let available = self.count_lines(needed);
+
+        self.scroll = if available < needed {
+            available.saturating_sub(self.page_size())
+        } else {
+            scroll
+        };
+
+        if self.scroll == 0 {
+            self.unread = 0;
+        }
+    }
+
+    // Counts rendered lines from the end, stops as soon as limit is reached
+    fn count_lines(&self, limit: usize) -> usize {
+        let mut count = 0;
+
+        for height in self.heights.iter().rev() {
+            if count >= limit {
+                break;
+            }
+
+            count += height;
+        }
+
+        count
+    }
+
+    // Keeps the same line at the bottom of the view when the width changes
+    fn set_columns(&mut self, columns: usize) {
+        let columns_previous = self.columns;
+        self.columns = columns;
+
+        let heights_previous = mem::replace(
+            &mut self.heights,
+            self.messages.iter().map(|message| message.render(columns).len()).collect());
+
+        if self.scroll == 0 || columns_previous == 0 {
+            return;
+        }
+
+        let mut remaining = self.scroll;
+        let mut scroll = 0;
+
+        for (lines_previous, lines) in heights_previous.into_iter().zip(&self.heights).rev() {
+            let lines = *lines;
+
+            if remaining < lines_previous {
+                scroll += cmp::min(remaining, lines - 1);
+                break;
+            }
+
+            remaining -= lines_previous;
+            scroll += lines;
+        }
+
+        self.scroll = scroll;
+    }
+
+    // Returns the lines from the end of the history, skipping the lines
+    // below the view, in top to bottom order
+    fn visible_lines(&self, skip: usize, size: usize) -> Vec<String> {
+        let mut lines = Vec::with_capacity(size);
+        let mut skip = skip;
+
+        for (message, height) in self.messages.iter().zip(&self.heights).rev() {
+            if lines.len() >= size {
+                break;
+            }
+
+            // Skip whole messages below the view without wrapping them
+            if skip >= *height {
+                skip -= height;
+                continue;
+            }
+
+            for line in message.render_styled(self.columns, &self.theme).into_iter().rev() {
+                if skip > 0 {
+                    skip -= 1;
+                } else if lines.len() < size {
+                    lines.push(line);
+                }
+            }
+        }
+
+        lines.reverse();
+        lines
     }
 
-    pub fn render<W: Write>(
+    pub fn render<W: Write + ?Sized>(
         &mut self,
         w: &mut W,
         rows: u16,
@@ -64,27 +344,262 @@ impl Chat {
     )
         -> Result<(), io::Error>
     {
-        let start = cmp::max(0, self.messages.len() as isize - (rows - 1) as isize);
-        let size = rows;
+        // Last row belongs to the prompt
+        self.height = rows.saturating_sub(1) as usize;
+
+        if self.columns != columns as usize {
+            self.set_columns(columns as usize);
+            self.invalidate();
+        }
+
+        // Make sure view is still filled after the terminal got resized
+        self.set_scroll(self.scroll);
+
+        // Reserve last row for the indicator when scrolled up
+        let size = if self.scroll > 0 {
+            self.height.saturating_sub(1)
+        } else {
+            self.height
+        };
+
+        let mut rows = self.visible_lines(self.scroll, size);
+        rows.resize(size, pad(String::new(), 0, self.columns));
+
+        if self.scroll > 0 {
+            let indicator = if self.unread > 0 {
+                format!("-- {} new messages below --", self.unread)
+            } else {
+                format!("-- {} more lines below --", self.scroll)
+            };
+
+            let width = indicator.width();
+            rows.push(pad(indicator, width, self.columns));
+        }
+
+        // Everything moved when the layout changed
+        if self.drawn.len() != rows.len() {
+            self.drawn.clear();
+        }
+
+        let mut output = String::new();
+
+        for (row, line) in rows.iter().enumerate() {
+            if self.drawn.get(row) == Some(line) {
+                continue;
+            }
+
+            output.push_str(&format!("{}{}", Goto(1, row as u16 + 1), line));
+        }
+
+        self.drawn = rows;
+
+        w.write_all(output.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod chat {
+    use super::*;
+
+    fn chat_with_messages(count: usize) -> Chat {
+        let mut chat = Chat::default();
+
+        for index in 0..count {
+            chat.add_message(ChatMessage::from_string(format!("Message {}", index)));
+        }
+
+        // Render once to let the chat know about its size
+        chat.render(&mut Vec::new(), 6, 80).unwrap();
+
+        chat
+    }
+
+    fn render_to_string(chat: &mut Chat, rows: u16, columns: u16) -> String {
+        let mut output = Vec::new();
+        chat.render(&mut output, rows, columns).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn wraps_with_indent() {
+        let message = ChatMessage::new(String::from("ME"), String::from("Hello, Test! 1, 2, 3"));
+        let lines = message.render(27);
+
+        // "[00:00:00] ME: " is 15 columns wide
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("ME: Hello, Test!"));
+        assert_eq!(lines[1], format!("{:15}1, 2, 3", ""));
+
+        // No indentation when the terminal is too narrow
+        let lines = message.render(16);
+        assert_eq!(lines[1], "Hello, Test! 1,");
+    }
+
+    #[test]
+    fn strips_escape_sequences() {
+        let message = ChatMessage::new(String::from("\x1b[31mME"), String::from("\x1b[2JHello"));
+        let lines = message.render(80);
+
+        assert!(lines.iter().all(|line| !line.contains('\x1b')));
+        assert!(lines[0].ends_with("[31mME: [2JHello"));
+    }
+
+    #[test]
+    fn scroll_is_clamped() {
+        let mut chat = chat_with_messages(20);
+
+        assert_eq!(chat.page_size(), 4);
+
+        chat.scroll_down(3);
+        assert_eq!(chat.scroll, 0);
+
+        chat.scroll_to_top();
+        assert_eq!(chat.scroll, 16);
+
+        chat.scroll_up(100);
+        assert_eq!(chat.scroll, 16);
 
-        let lines = self
-            .messages
-            .iter()
-            .skip(start as usize)
-            .take(size as usize);
+        chat.scroll_to_bottom();
+        assert_eq!(chat.scroll, 0);
+    }
+
+    #[test]
+    fn view_stays_in_place_on_new_messages() {
+        let mut chat = chat_with_messages(20);
+
+        chat.scroll_up(chat.page_size());
+        chat.add_message(ChatMessage::from_string(String::from("New")));
+        chat.add_message(ChatMessage::from_string(String::from("New")));
+
+        assert_eq!(chat.scroll, 6);
+        assert_eq!(chat.unread, 2);
+
+        let output = render_to_string(&mut chat, 6, 80);
+
+        assert!(output.contains("Message 12"));
+        assert!(output.contains("Message 15"));
+        assert!(!output.contains("Message 16"));
+        assert!(output.contains("2 new messages below"));
+
+        chat.scroll_to_bottom();
+        assert_eq!(chat.unread, 0);
+    }
+
+    #[test]
+    fn lays_out_by_lines() {
+        let mut chat = chat_with_messages(3);
+        chat.add_message(ChatMessage::from_string(String::from("one two three four five six")));
+
+        // Long message takes three rows, leaving one row for older messages
+        let output = render_to_string(&mut chat, 5, 28);
+
+        assert!(!output.contains("Message 1"));
+        assert!(output.contains("Message 2"));
+        assert!(output.contains("one two"));
+        assert!(output.contains("three four"));
+        assert!(output.contains("five six"));
+    }
+
+    #[test]
+    fn scroll_survives_resize() {
+        let mut chat = chat_with_messages(20);
+
+        chat.scroll_up(5);
+        chat.render(&mut Vec::new(), 10, 40).unwrap();
+        assert_eq!(chat.scroll, 5);
+
+        // Every message takes two lines, the same message stays at the bottom
+        chat.render(&mut Vec::new(), 10, 20).unwrap();
+        assert_eq!(chat.scroll, 10);
+
+        // View got so high that all messages fit into it
+        chat.render(&mut Vec::new(), 40, 40).unwrap();
+        assert_eq!(chat.scroll, 0);
+    }
+
+    #[test]
+    fn styles_do_not_change_layout() {
+        let message = ChatMessage::new(String::from("adz"), String::from("Hello, Test! 1, 2, 3"))
+            .with_author(&[1, 2, 3])
+            .with_kind(MessageKind::Mention);
+
+        let theme = Theme::dark();
+        let styled = message.render_styled(28, &theme);
+
+        assert_eq!(styled.len(), message.render(28).len());
+        assert!(styled[0].contains(&theme.author(&[1, 2, 3]).paint("adz")));
+        assert!(styled[1].contains(&theme.mention.paint("1, 2, 3")));
+    }
 
-        let mut line_strings = String::new();
-        for (line_index, message) in lines.enumerate() {
-            let rendered_line = format!("{}{}{}",
-                                        Goto(0, line_index as u16 + 1),
-                                        ClearLine,
-                                        &message.render(columns as usize));
+    #[test]
+    fn writes_changed_rows_only() {
+        let mut chat = chat_with_messages(20);
 
-            line_strings.push_str(&rendered_line);
+        // Nothing changed since the last render
+        assert_eq!(render_to_string(&mut chat, 6, 80), "");
+
+        chat.add_message(ChatMessage::from_string(String::from("New")));
+        let output = render_to_string(&mut chat, 6, 80);
+        assert!(output.contains("New"));
+
+        // Resizing draws everything again
+        chat.add_message(ChatMessage::from_string(String::from("Newer")));
+        chat.render(&mut Vec::new(), 6, 80).unwrap();
+        assert_eq!(render_to_string(&mut chat, 6, 60).matches("Message").count(), 3);
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture render_cost`
+    #[test]
+    #[ignore]
+    fn render_cost() {
+        use std::time::Instant;
+
+        const ROUNDS: u32 = 100;
+
+        let mut chat = Chat::new(Theme::dark());
+
+        for index in 0..10_000 {
+            chat.add_message(ChatMessage::new(
+                String::from("adz"),
+                format!("Message {} with some more text to wrap around the screen", index))
+                .with_author(&[index as u8; 32]));
         }
 
-        w.write_all(line_strings.as_bytes())?;
+        let mut output = Vec::new();
+        let started = Instant::now();
+
+        for index in 0..ROUNDS {
+            chat.render(&mut output, 50, 100 + (index % 2) as u16).unwrap();
+        }
+
+        println!("render after resize: {:?}", started.elapsed() / ROUNDS);
+
+        let started = Instant::now();
+
+        for _ in 0..ROUNDS {
+            chat.invalidate();
+            chat.render(&mut output, 50, 100).unwrap();
+        }
+
+        println!("full redraw: {:?}", started.elapsed() / ROUNDS);
+
+        let started = Instant::now();
+
+        for _ in 0..ROUNDS {
+            chat.add_message(ChatMessage::from_string(String::from("New")));
+            chat.render(&mut output, 50, 100).unwrap();
+        }
+
+        println!("render after new message: {:?}", started.elapsed() / ROUNDS);
+
+        let started = Instant::now();
+
+        for _ in 0..ROUNDS {
+            chat.scroll_to_top();
+            chat.render(&mut output, 50, 100).unwrap();
+            chat.scroll_to_bottom();
+        }
 
-        Ok(())
+        println!("scroll to top of history: {:?}", started.elapsed() / ROUNDS);
     }
 }
@@ -0,0 +1,118 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+// Number of entries kept, older ones are dropped when loading
+const MAX_ENTRIES: usize = 1000;
+
+/// Previously sent messages, optionally persisted in a file.
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// Loads history from file, starts empty when it does not exist yet.
+    pub fn load(path: PathBuf) -> Self {
+        let mut entries: Vec<String> = File::open(&path)
+            .map(|file| BufReader::new(file).lines().map_while(Result::ok).collect())
+            .unwrap_or_default();
+
+        // Keep file from growing forever
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+            let _ = fs::write(&path, entries.join("\n") + "\n");
+        }
+
+        Self {
+            entries,
+            path: Some(path),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    /// Adds a new entry, repeating the last entry is ignored.
+    pub fn push(&mut self, entry: &str) {
+        if entry.contains('\n') || self.entries.last().map(String::as_str) == Some(entry) {
+            return;
+        }
+
+        self.entries.push(entry.to_string());
+
+        // Losing history is not worth interrupting the chat for
+        let _ = self.persist(entry);
+    }
+
+    /// Returns index of the newest entry before `before` containing the query.
+    pub fn search(&self, query: &str, before: usize) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())]
+            .iter()
+            .rposition(|entry| entry.contains(query))
+    }
+
+    fn persist(&self, entry: &str) -> Result<(), io::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{}", entry)
+    }
+}
+
+#[cfg(test)]
+mod history {
+    use super::*;
+
+    #[test]
+    fn persists_entries() {
+        let path = std::env::temp_dir()
+            .join(format!("p2p-chat-history-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut history = History::load(path.clone());
+        assert!(history.is_empty());
+
+        history.push("Hello, Test!");
+        history.push("Hello, Test!");
+        history.push("1, 2, 3");
+
+        let history = History::load(path.clone());
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(0), Some("Hello, Test!"));
+        assert_eq!(history.get(1), Some("1, 2, 3"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn searches_backwards() {
+        let mut history = History::default();
+
+        history.push("Hello, Test!");
+        history.push("1, 2, 3");
+        history.push("Hello again");
+
+        assert_eq!(history.search("Hello", history.len()), Some(2));
+        assert_eq!(history.search("Hello", 2), Some(0));
+        assert_eq!(history.search("Hello", 0), None);
+        assert_eq!(history.search("nothing", history.len()), None);
+    }
+}
@@ -1,103 +1,342 @@
+mod backend;
 mod chat;
+mod history;
+mod peers;
 mod prompt;
+#[cfg(test)]
+mod screen;
+mod status;
 mod terminal;
+mod theme;
+mod wrap;
 
-use std::io::{self, Write};
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::{Async, Poll, Stream};
-use termion::event::{Event, Key};
+use futures::{Async, Future, Poll, Stream};
+use termion::clear::All as ClearAll;
+use termion::event::{Event, Key, MouseButton, MouseEvent};
+use tokio_core::reactor::{Handle, Timeout};
 
-pub use chat::ChatMessage;
+pub use chat::{ChatMessage, MessageKind};
+pub use peers::PeerEntry;
+pub use status::Status;
+pub use theme::{Theme, THEME_NAMES};
 use chat::Chat;
+use history::History;
+use peers::PeerList;
 use prompt::Prompt;
-use terminal::{Terminal, TerminalEvent};
+use status::StatusBar;
+use backend::{Backend, TerminalEvent};
+use terminal::Terminal;
+
+/// Updates given to the interface from the outside.
+pub enum UiEvent {
+    Message(ChatMessage),
+    Status(Status),
+    Peers(Vec<PeerEntry>),
+    Clear,
+
+    /// Remember sent messages in this history file from now on
+    History(PathBuf),
+}
+
+/// What the user did in the interface.
+#[derive(Debug, PartialEq)]
+pub enum UserInput {
+    /// Line entered in the prompt
+    Line(String),
+
+    /// Scrolled up to the oldest message shown
+    ScrolledToTop,
+}
+
+// Number of messages to scroll per mouse wheel step
+const MOUSE_SCROLL_LINES: usize = 3;
+
+// Shortest time between two renders, caps the frame rate at about 60 fps
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+
+// Parts of the screen which changed since the last render
+#[derive(Default)]
+struct Dirty {
+    // Everything, the screen gets cleared first
+    screen: bool,
+    chat: bool,
+    peers: bool,
+    status: bool,
+    prompt: bool,
+}
+
+impl Dirty {
+    fn any(&self) -> bool {
+        self.screen || self.chat || self.peers || self.status || self.prompt
+    }
+}
 
 pub struct UserInterface {
     // Chat interface to display recent ChatMessages
     chat: Chat,
 
+    // Parts of the screen to render again
+    dirty: Dirty,
+
+    // Error which occurred while handling input, ends the stream
+    error: Option<io::Error>,
+
     // Did user send exit command?
     exit: bool,
 
+    // Delays the next render when the last one was too recent
+    frame_timer: Option<Timeout>,
+
+    handle: Handle,
+
     // Buffer to store user input from prompt
-    input: Option<String>,
+    input: Option<UserInput>,
+
+    last_render: Option<Instant>,
+
+    // Side panel listing the peers, toggled with F2
+    peer_list: PeerList,
 
     // Incoming messages to display
-    messages_rx: UnboundedReceiver<ChatMessage>,
+    messages_rx: UnboundedReceiver<UiEvent>,
 
     // User input prompt interface
     prompt: Prompt,
 
+    // Line above the prompt showing the state of the node
+    status_bar: StatusBar,
+
     // Current size of the Terminal (columns, rows)
     term_size: (u16, u16),
 
     // Our terminal instance to write to
-    terminal: Terminal,
+    terminal: Box<dyn Backend>,
 }
 
 impl UserInterface {
-    pub fn new() -> Result<(Self, UnboundedSender<ChatMessage>), io::Error> {
+    /// Creates the interface, sent messages are remembered in the given
+    /// history file.
+    pub fn new(
+        handle: Handle,
+        history_path: Option<PathBuf>,
+        theme: Theme,
+    ) -> Result<(Self, UnboundedSender<UiEvent>), io::Error> {
+        let terminal = Terminal::new(&handle)?;
+        Ok(Self::with_backend(handle, Box::new(terminal), history_path, theme))
+    }
+
+    /// Creates the interface drawing on the given terminal.
+    pub fn with_backend(
+        handle: Handle,
+        terminal: Box<dyn Backend>,
+        history_path: Option<PathBuf>,
+        theme: Theme,
+    ) -> (Self, UnboundedSender<UiEvent>) {
         let (messages_tx, messages_rx) = unbounded();
 
+        let history = match history_path {
+            Some(path) => History::load(path),
+            None => History::default(),
+        };
+
         let view = Self {
-            chat: Chat::default(),
+            chat: Chat::new(theme.clone()),
+            dirty: Dirty::default(),
+            error: None,
             exit: false,
+            frame_timer: None,
+            handle,
             input: None,
+            last_render: None,
             messages_rx,
-            prompt: Prompt::default(),
+            peer_list: PeerList::new(theme.clone()),
+            prompt: Prompt::new(history, theme.prompt.clone()),
+            status_bar: StatusBar::new(theme),
             term_size: (0, 0),
-            terminal: Terminal::new()?,
+            terminal,
         };
 
-        Ok((view, messages_tx))
+        (view, messages_tx)
     }
 
     fn handle_resize(&mut self, size: (u16, u16)) {
         self.term_size = size;
+        self.dirty.screen = true;
     }
 
     fn handle_input(&mut self, event: Event) {
+        // Most keys end up in the prompt, the others mark what they change
+        self.dirty.prompt = true;
+
         match event {
             // Received a signal to exit application
             Event::Key(Key::Ctrl('c')) => self.exit = true,
 
+            // Changes the layout of the whole screen
+            Event::Key(Key::F(2)) => {
+                self.peer_list.toggle();
+                self.dirty.screen = true;
+            }
+
+            Event::Key(Key::PageUp)
+            | Event::Key(Key::PageDown)
+            | Event::Mouse(MouseEvent::Press(MouseButton::WheelUp, _, _))
+            | Event::Mouse(MouseEvent::Press(MouseButton::WheelDown, _, _)) => {
+                self.dirty.chat = true;
+                self.scroll(event);
+            }
+
+            Event::Key(Key::Home) | Event::Key(Key::End) if self.prompt.is_empty() => {
+                self.dirty.chat = true;
+                self.scroll(event);
+            }
+
             // Normal key input, give it to prompt
             event => {
                 match self.prompt.handle_input(&event) {
                     Ok(None) => {
                     },
                     Ok(Some(input)) => {
-                        self.input = Some(input)
+                        self.input = Some(UserInput::Line(input))
                     },
                     Err(err) => {
-                        panic!("Failed to parse command: {:?}", err);
+                        self.error = Some(err);
                     }
                 }
             }
         }
     }
 
-    fn render(&mut self) -> Result<(), io::Error> {
-        // Render interface components
-        self.chat.render(self.terminal.stdout(), self.term_size.1, self.term_size.0)?;
-        self.prompt.render(self.terminal.stdout(), self.term_size.1)?;
+    fn scroll(&mut self, event: Event) {
+        match event {
+
+            // Scroll through chat history
+            Event::Key(Key::PageUp) => self.chat.scroll_up(self.chat.page_size()),
+            Event::Key(Key::PageDown) => self.chat.scroll_down(self.chat.page_size()),
+
+            Event::Key(Key::Home) => self.chat.scroll_to_top(),
+            Event::Key(Key::End) => self.chat.scroll_to_bottom(),
+
+            Event::Mouse(MouseEvent::Press(MouseButton::WheelUp, _, _)) => {
+                self.chat.scroll_up(MOUSE_SCROLL_LINES)
+            }
+            Event::Mouse(MouseEvent::Press(MouseButton::WheelDown, _, _)) => {
+                self.chat.scroll_down(MOUSE_SCROLL_LINES)
+            }
+
+            _ => {}
+        }
+
+        // Older messages might be fetched to show above
+        let is_up = matches!(
+            event,
+            Event::Key(Key::PageUp)
+                | Event::Key(Key::Home)
+                | Event::Mouse(MouseEvent::Press(MouseButton::WheelUp, _, _)));
+
+        if is_up && self.chat.is_at_top() {
+            self.input = Some(UserInput::ScrolledToTop);
+        }
+    }
+
+    // Renders when anything changed, but not more often than the frame
+    // rate allows
+    fn poll_render(&mut self) -> Result<(), io::Error> {
+        if !self.dirty.any() {
+            return Ok(());
+        }
+
+        if let Some(timer) = &mut self.frame_timer {
+            match timer.poll()? {
+                Async::Ready(()) => self.frame_timer = None,
+                Async::NotReady => return Ok(()),
+            }
+        }
+
+        let elapsed = self.last_render.map_or(FRAME_INTERVAL, |at| at.elapsed());
 
-        if let Err(e) = self.terminal.stdout().flush() {
-            panic!("failed to flush stdout: {}", e);
+        if elapsed < FRAME_INTERVAL {
+            let mut timer = Timeout::new(FRAME_INTERVAL - elapsed, &self.handle)?;
+
+            // Polling registers the wakeup
+            if let Async::NotReady = timer.poll()? {
+                self.frame_timer = Some(timer);
+                return Ok(());
+            }
         }
 
+        self.render()?;
+        self.last_render = Some(Instant::now());
+
         Ok(())
     }
 
+    fn render(&mut self) -> Result<(), io::Error> {
+        // Wait until we know the size of the terminal
+        if self.term_size.1 == 0 {
+            return Ok(());
+        }
+
+        let (columns, rows) = self.term_size;
+        let dirty = mem::take(&mut self.dirty);
+
+        if dirty.screen {
+            write!(self.terminal.output(), "{}", ClearAll)?;
+            self.chat.invalidate();
+        }
+
+        // Render interface components, the peer list takes the right side
+        // of the chat and the status bar sits above the prompt
+        let panel_width = self.peer_list.width(columns);
+
+        if dirty.screen || dirty.chat {
+            self.chat.render(self.terminal.output(), rows.saturating_sub(1), columns - panel_width)?;
+        }
+
+        if dirty.screen || dirty.peers {
+            self.peer_list.render(self.terminal.output(), rows.saturating_sub(2), columns)?;
+        }
+
+        if rows > 1 && (dirty.screen || dirty.status) {
+            self.status_bar.render(self.terminal.output(), rows - 1, columns)?;
+        }
+
+        // Always last, it places the cursor
+        self.prompt.render(self.terminal.output(), rows, columns)?;
+        self.terminal.output().flush()
+    }
+
     fn poll_messages(&mut self) {
-        // Check for incoming messages and give them to Chat interface
-        match self.messages_rx.poll() {
-            Ok(Async::Ready(Some(message))) => {
-                self.chat.add_message(message);
-                return;
+        // Take everything which arrived, a burst renders only once
+        while let Ok(Async::Ready(Some(event))) = self.messages_rx.poll() {
+            match event {
+                UiEvent::Message(message) => {
+                    self.chat.add_message(message);
+                    self.dirty.chat = true;
+                }
+                UiEvent::Status(status) => {
+                    self.status_bar.set_status(status);
+                    self.dirty.status = true;
+                }
+                UiEvent::Peers(peers) => {
+                    self.peer_list.set_peers(peers);
+                    self.dirty.peers = true;
+                }
+                UiEvent::Clear => {
+                    self.chat.clear();
+                    self.dirty.chat = true;
+                }
+                UiEvent::History(path) => {
+                    self.prompt.set_history(History::load(path));
+                    self.dirty.prompt = true;
+                }
             }
-            _ => return,
         }
     }
 
@@ -105,10 +344,17 @@ impl UserInterface {
         // Check for input and resize events of the Terminal
         loop {
             match self.terminal.poll() {
-                Ok(Async::Ready(Some(event))) => match event {
-                    TerminalEvent::Input(event) => self.handle_input(event),
-                    TerminalEvent::Resize(event) => self.handle_resize(event),
-                },
+                Ok(Async::Ready(Some(event))) => {
+                    match event {
+                        TerminalEvent::Input(event) => self.handle_input(event),
+                        TerminalEvent::Resize(event) => self.handle_resize(event),
+                    }
+
+                    // Give the input away before reading the next one
+                    if self.input.is_some() {
+                        return;
+                    }
+                }
                 Ok(Async::Ready(None)) => {
                     self.exit = true;
                     return;
@@ -126,7 +372,7 @@ impl UserInterface {
 }
 
 impl Stream for UserInterface {
-    type Item = String;
+    type Item = UserInput;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
@@ -134,23 +380,192 @@ impl Stream for UserInterface {
         self.poll_terminal();
         self.poll_messages();
 
+        // Fail stream when handling the input went wrong
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
         // End stream when user indicated exit
         if self.exit {
             return Ok(Async::Ready(None));
         }
 
-        // Render to the view
-        self.render().expect("failed to render the view");
-
-        // UserInterface is a Stream returning input Strings from the prompt
-        match &self.input {
-            Some(input) => {
-                let message_clone = input.clone();
-                self.input = None;
+        // Render to the view when something changed
+        self.poll_render()?;
 
-                Ok(Async::Ready(Some(message_clone)))
-            },
+        // UserInterface is a Stream returning what the user did
+        match self.input.take() {
+            Some(input) => Ok(Async::Ready(Some(input))),
             None => Ok(Async::NotReady),
         }
     }
 }
+
+#[cfg(test)]
+mod ui {
+    use super::*;
+
+    use chrono::{Local, TimeZone};
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    use screen::{VirtualInput, VirtualTerminal};
+
+    struct Harness {
+        core: Core,
+        input: VirtualInput,
+        ui: UserInterface,
+        ui_tx: UnboundedSender<UiEvent>,
+    }
+
+    impl Harness {
+        fn new(columns: u16, rows: u16) -> Self {
+            let core = Core::new().unwrap();
+
+            let (terminal, input) = VirtualTerminal::new(columns, rows);
+            let (ui, ui_tx) = UserInterface::with_backend(
+                core.handle(), Box::new(terminal), None, Theme::monochrome());
+
+            let harness = Self { core, input, ui, ui_tx };
+
+            harness.send(UiEvent::Status(Status {
+                channel: String::from("chat://abcd12"),
+                ..Status::default()
+            }));
+
+            harness
+        }
+
+        fn send(&self, event: UiEvent) {
+            self.ui_tx.unbounded_send(event).unwrap();
+        }
+
+        fn message(&self, sender: Option<&str>, text: &str) {
+            let message = match sender {
+                Some(sender) => ChatMessage::new(sender.to_string(), text.to_string()),
+                None => ChatMessage::from_string(text.to_string()),
+            };
+
+            let timestamp = Local.ymd(2019, 8, 1).and_hms(12, 0, 0);
+            self.send(UiEvent::Message(message.with_timestamp(timestamp)));
+        }
+
+        // Drives the interface until everything changed got rendered
+        fn render(&mut self) -> String {
+            let ui = &mut self.ui;
+
+            self.core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+                ui.poll()?;
+
+                if ui.dirty.any() {
+                    Ok(Async::NotReady)
+                } else {
+                    Ok(Async::Ready(()))
+                }
+            })).unwrap();
+
+            self.input.snapshot()
+        }
+
+        fn next_input(&mut self) -> Option<UserInput> {
+            let ui = &mut self.ui;
+            self.core.run(future::poll_fn(|| ui.poll())).unwrap()
+        }
+    }
+
+    #[test]
+    fn renders_messages() {
+        let mut harness = Harness::new(40, 6);
+
+        harness.message(None, "Hello, Test!");
+        harness.message(Some("adz"), "A longer message which needs to wrap around");
+
+        assert_eq!(harness.render(), [
+            "[12:00:00] INFO: Hello, Test!",
+            "[12:00:00] adz: A longer message which",
+            "                needs to wrap around",
+            "",
+            " chat://abcd12 | no peers | synced 0",
+            ":",
+        ].join("\n"));
+        assert_eq!(harness.input.cursor(), (2, 6));
+    }
+
+    #[test]
+    fn renders_prompt_and_scrolling() {
+        let mut harness = Harness::new(30, 6);
+
+        for index in 0..10 {
+            harness.message(Some("adz"), &format!("Message {}", index));
+        }
+
+        harness.render();
+
+        harness.input.send_keys("Hello");
+        harness.input.send(Event::Key(Key::Left));
+        harness.input.send(Event::Key(Key::PageUp));
+
+        assert_eq!(harness.render(), [
+            "[12:00:00] adz: Message 4",
+            "[12:00:00] adz: Message 5",
+            "[12:00:00] adz: Message 6",
+            "-- 3 more lines below --",
+            " chat://abcd12 | no peers | sy",
+            ":Hello",
+        ].join("\n"));
+        assert_eq!(harness.input.cursor(), (6, 6));
+
+        harness.input.send(Event::Key(Key::Char('\n')));
+        assert_eq!(harness.next_input(), Some(UserInput::Line(String::from("Hello"))));
+
+        // Reaching the oldest message asks for more
+        harness.input.send(Event::Key(Key::PageUp));
+        harness.input.send(Event::Key(Key::PageUp));
+        assert_eq!(harness.next_input(), Some(UserInput::ScrolledToTop));
+    }
+
+    #[test]
+    fn renders_any_size() {
+        let mut harness = Harness::new(20, 5);
+
+        harness.message(Some("adz"), "Hello, Test! 1, 2, 3");
+
+        // No room to indent on narrow terminals
+        assert_eq!(harness.render(), [
+            "[12:00:00] adz:",
+            "Hello, Test! 1, 2, 3",
+            "",
+            " chat://abcd12 | no",
+            ":",
+        ].join("\n"));
+
+        // Only changed rows are written again
+        harness.input.take_written();
+        harness.render();
+        assert_eq!(harness.input.take_written(), 0);
+
+        harness.input.resize(80, 8);
+        harness.send(UiEvent::Peers(vec![PeerEntry {
+            name: String::from("adz (abcd12)"),
+            address: String::from("192.168.0.2:12345"),
+            connected: true,
+            latency: None,
+            progress: None,
+            last_seen: Local.ymd(2019, 8, 1).and_hms(12, 0, 0),
+        }]));
+        harness.input.send(Event::Key(Key::F(2)));
+
+        let panel = format!("{:50}│", "");
+
+        assert_eq!(harness.render(), [
+            format!("{:50}│ Peers (1)", "[12:00:00] adz: Hello, Test! 1, 2, 3"),
+            panel.clone(),
+            format!("{} * adz (abcd12)", panel),
+            format!("{}   192.168.0.2:12345", panel),
+            format!("{}   rtt ?  sync ?", panel),
+            format!("{}   seen 12:00:00", panel),
+            String::from(" chat://abcd12 | no peers | synced 0"),
+            String::from(":"),
+        ].join("\n"));
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use termion::cursor::Goto;
+use unicode_width::UnicodeWidthStr;
+
+use super::theme::Theme;
+use super::wrap::{strip_control, truncate};
+
+/// Columns taken by the panel including its border.
+pub const PANEL_WIDTH: u16 = 30;
+
+// Columns the chat keeps at least, the panel is hidden below that
+const MIN_CHAT_WIDTH: u16 = 40;
+
+const BORDER: &str = "│";
+
+/// One peer shown in the side panel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerEntry {
+    /// Nickname or key fingerprint, the discovery token when unknown
+    pub name: String,
+    pub address: String,
+    pub connected: bool,
+    pub latency: Option<Duration>,
+
+    /// Entries we have of the peer's log and entries it announced
+    pub progress: Option<(u64, u64)>,
+
+    pub last_seen: DateTime<Local>,
+}
+
+impl PeerEntry {
+    fn lines(&self) -> Vec<String> {
+        let marker = if self.connected { "*" } else { "-" };
+
+        let latency = self.latency
+            .map_or_else(|| String::from("?"), |latency| format!("{}ms", latency.as_millis()));
+
+        let progress = self.progress
+            .map_or_else(|| String::from("?"), |(local, remote)| format!("{}/{}", local, remote));
+
+        // Names come from peers and stay on one line
+        let name = strip_control(&self.name).replace('\n', " ");
+
+        vec![
+            format!("{} {}", marker, name),
+            format!("  {}", self.address),
+            format!("  rtt {}  sync {}", latency, progress),
+            format!("  seen {}", self.last_seen.format("%H:%M:%S")),
+        ]
+    }
+}
+
+#[derive(Default)]
+pub struct PeerList {
+    peers: Vec<PeerEntry>,
+    theme: Theme,
+    visible: bool,
+}
+
+impl PeerList {
+    pub fn new(theme: Theme) -> Self {
+        Self {
+            theme,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_peers(&mut self, peers: Vec<PeerEntry>) {
+        self.peers = peers;
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Returns the columns the panel takes in a terminal of the given width.
+    pub fn width(&self, columns: u16) -> u16 {
+        if self.visible && columns >= MIN_CHAT_WIDTH + PANEL_WIDTH {
+            PANEL_WIDTH
+        } else {
+            0
+        }
+    }
+
+    // Returns the text of every row, each exactly as wide as the panel
+    // content next to the border
+    fn lines(&self, rows: usize) -> Vec<String> {
+        let width = (PANEL_WIDTH - 1) as usize;
+
+        let mut lines = vec![format!(" Peers ({})", self.peers.len())];
+
+        for peer in &self.peers {
+            lines.push(String::new());
+            lines.extend(peer.lines().iter().map(|line| format!(" {}", line)));
+        }
+
+        lines.resize(rows, String::new());
+
+        lines.into_iter()
+            .map(|line| {
+                let line = truncate(&line, width);
+                let padding = width - line.width();
+                line + &" ".repeat(padding)
+            })
+            .collect()
+    }
+
+    /// Draws the panel at the right edge of the given rows.
+    pub fn render<W: Write + ?Sized>(&self, w: &mut W, rows: u16, columns: u16) -> Result<(), io::Error> {
+        let width = self.width(columns);
+
+        if width == 0 {
+            return Ok(());
+        }
+
+        let column = columns - width + 1;
+
+        for (row, line) in self.lines(rows as usize).iter().enumerate() {
+            let line = if row == 0 {
+                self.theme.own.paint(line)
+            } else {
+                line.clone()
+            };
+
+            write!(w, "{}{}{}", Goto(column, row as u16 + 1), self.theme.info.paint(BORDER), line)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod peers {
+    use super::*;
+
+    fn entry(name: &str) -> PeerEntry {
+        PeerEntry {
+            name: String::from(name),
+            address: String::from("192.168.0.2:12345"),
+            connected: true,
+            latency: Some(Duration::from_millis(12)),
+            progress: Some((5, 7)),
+            last_seen: Local::now(),
+        }
+    }
+
+    #[test]
+    fn hides_on_narrow_terminals() {
+        let mut list = PeerList::default();
+        assert_eq!(list.width(100), 0);
+
+        list.toggle();
+        assert_eq!(list.width(100), PANEL_WIDTH);
+        assert_eq!(list.width(50), 0);
+    }
+
+    #[test]
+    fn lists_peers() {
+        let mut list = PeerList::default();
+        list.set_peers(vec![
+            entry("adz (abcd12)"),
+            entry("a-very-long-nickname-for-this (ffffff)"),
+            entry("\x1b[2Jtoken\nforged"),
+        ]);
+
+        let lines = list.lines(16);
+
+        assert_eq!(lines.len(), 16);
+        assert!(lines.iter().all(|line| line.width() == (PANEL_WIDTH - 1) as usize));
+        assert_eq!(lines[0].trim_end(), " Peers (3)");
+        assert_eq!(lines[2].trim_end(), " * adz (abcd12)");
+        assert_eq!(lines[4].trim_end(), "   rtt 12ms  sync 5/7");
+        assert_eq!(lines[7].trim_end(), " * a-very-long-nickname-for-t");
+        assert_eq!(lines[12].trim_end(), " * [2Jtoken forged");
+    }
+}
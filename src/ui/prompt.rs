@@ -1,61 +1,317 @@
 use std::io::{self, Write};
-use std::str::FromStr;
+use std::mem;
 
 use termion::clear::CurrentLine as ClearLine;
 use termion::cursor::Goto;
 use termion::event::{Event, Key};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use super::history::History;
+use super::theme::Style;
+
+const PROMPT: &str = ":";
+
+// Graphemes belonging to words when moving with Alt-B / Alt-F
+fn is_word(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(char::is_alphanumeric)
+}
+
+// Graphemes belonging to words when deleting with Ctrl-W
+fn is_not_whitespace(grapheme: &str) -> bool {
+    !grapheme.trim().is_empty()
+}
+
+// State of an active reverse search through the history (Ctrl-R)
+struct Search {
+    query: String,
+
+    // Index of the currently matching history entry
+    found: Option<usize>,
+
+    // Input before the search started, restored when cancelling
+    draft: String,
+}
 
 #[derive(Default)]
 pub struct Prompt {
+    // Byte position of the cursor, always at a grapheme cluster boundary
     dex: usize,
     chars: String,
+
+    // First visible display column when input is wider than the terminal
+    offset: usize,
+
+    // Previously sent messages to recall
+    history: History,
+
+    // Currently recalled history entry and the input it replaced
+    history_index: Option<usize>,
+    draft: String,
+
+    search: Option<Search>,
+
+    // Colour of the prompt sign
+    style: Style,
 }
 
 impl Prompt {
+    pub fn new(history: History, style: Style) -> Self {
+        Self {
+            history,
+            style,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Recalls entries of another history from now on, the input stays.
+    pub fn set_history(&mut self, history: History) {
+        self.history = history;
+        self.history_index = None;
+        self.search = None;
+    }
+
     pub fn handle_input(&mut self, input: &Event) -> Result<Option<String>, io::Error> {
+        if self.search.is_some() && !self.handle_search(input) {
+            return Ok(None);
+        }
+
         match input {
             Event::Key(Key::Char('\n')) => self.finalize(),
             Event::Key(Key::Backspace) => Ok(self.back()),
-            Event::Key(Key::Delete) => Ok(self.delete()),
-            Event::Key(Key::Left) => Ok(self.left()),
-            Event::Key(Key::Right) => Ok(self.right()),
+            Event::Key(Key::Delete) | Event::Key(Key::Ctrl('d')) => Ok(self.delete()),
+            Event::Key(Key::Left) | Event::Key(Key::Ctrl('b')) => Ok(self.left()),
+            Event::Key(Key::Right) | Event::Key(Key::Ctrl('f')) => Ok(self.right()),
+            Event::Key(Key::Home) | Event::Key(Key::Ctrl('a')) => Ok(self.start()),
+            Event::Key(Key::End) | Event::Key(Key::Ctrl('e')) => Ok(self.end()),
+            Event::Key(Key::Alt('b')) => Ok(self.word_left()),
+            Event::Key(Key::Alt('f')) => Ok(self.word_right()),
+            Event::Key(Key::Ctrl('k')) => Ok(self.kill_end()),
+            Event::Key(Key::Ctrl('u')) => Ok(self.kill_start()),
+            Event::Key(Key::Ctrl('w')) => Ok(self.kill_word()),
+            Event::Key(Key::Up) | Event::Key(Key::Ctrl('p')) => Ok(self.history_previous()),
+            Event::Key(Key::Down) | Event::Key(Key::Ctrl('n')) => Ok(self.history_next()),
+            Event::Key(Key::Ctrl('r')) => Ok(self.start_search()),
             Event::Key(Key::Char(chr)) => Ok(self.new_key(*chr)),
             _ => Ok(None),
         }
     }
 
-    fn left(&mut self) -> Option<String> {
-        if self.dex > 0 {
-            self.dex -= 1;
+    // Handles keys while searching, returns true when the key should be
+    // handled as usual after the search ended
+    fn handle_search(&mut self, input: &Event) -> bool {
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return true,
+        };
+
+        match input {
+            Event::Key(Key::Ctrl('r')) => {
+                if let Some(found) = search.found {
+                    search.found = self.history.search(&search.query, found).or(search.found);
+                }
+            }
+            Event::Key(Key::Backspace) => {
+                search.query.pop();
+                search.found = self.history.search(&search.query, self.history.len());
+            }
+            Event::Key(Key::Char('\n')) => {
+                self.accept_search();
+                return false;
+            }
+            Event::Key(Key::Char(chr)) if !chr.is_control() => {
+                search.query.push(*chr);
+                search.found = self.history.search(&search.query, self.history.len());
+            }
+            Event::Key(Key::Ctrl('g')) | Event::Key(Key::Esc) => {
+                let draft = mem::take(&mut search.draft);
+                self.search = None;
+                self.set_text(draft);
+            }
+            _ => {
+                self.accept_search();
+                return true;
+            }
         }
+
+        false
+    }
+
+    fn start_search(&mut self) -> Option<String> {
+        self.search = Some(Search {
+            query: String::new(),
+            found: None,
+            draft: self.chars.clone(),
+        });
+
         None
     }
 
-    fn right(&mut self) -> Option<String> {
-        if self.dex < self.chars.len() {
-            self.dex += 1;
+    fn accept_search(&mut self) {
+        if let Some(search) = self.search.take() {
+            let text = match search.found.and_then(|index| self.history.get(index)) {
+                Some(entry) => entry.to_string(),
+                None => search.draft,
+            };
+
+            self.set_text(text);
         }
+    }
+
+    fn set_text(&mut self, text: String) {
+        self.chars = text;
+        self.dex = self.chars.len();
+    }
+
+    fn history_previous(&mut self) -> Option<String> {
+        let index = match self.history_index {
+            _ if self.history.is_empty() => return None,
+            None => {
+                self.draft = self.chars.clone();
+                self.history.len() - 1
+            }
+            Some(index) => index.saturating_sub(1),
+        };
+
+        self.history_index = Some(index);
+        self.set_text(self.history.get(index).unwrap_or_default().to_string());
         None
     }
 
-    fn delete(&mut self) -> Option<String> {
-        if self.dex < self.chars.len() {
-            self.chars.remove(self.dex);
+    fn history_next(&mut self) -> Option<String> {
+        match self.history_index {
+            Some(index) if index + 1 < self.history.len() => {
+                self.history_index = Some(index + 1);
+                self.set_text(self.history.get(index + 1).unwrap_or_default().to_string());
+            }
+            Some(_) => {
+                // Went past the newest entry, bring back what was typed before
+                self.history_index = None;
+                let draft = mem::take(&mut self.draft);
+                self.set_text(draft);
+            }
+            None => {}
         }
+
         None
     }
 
-    fn back(&mut self) -> Option<String> {
-        if !self.chars.is_empty() {
-            self.dex -= 1;
-            self.chars.remove(self.dex);
+    // Byte position of the closest word start left of the cursor
+    fn word_start(&self, is_word: fn(&str) -> bool) -> usize {
+        let mut position = self.dex;
+        let mut graphemes = self.chars[..self.dex].grapheme_indices(true).rev().peekable();
+
+        // Skip separators first, then the word itself
+        while let Some((index, _)) = graphemes.next_if(|(_, grapheme)| !is_word(grapheme)) {
+            position = index;
+        }
+
+        while let Some((index, _)) = graphemes.next_if(|(_, grapheme)| is_word(grapheme)) {
+            position = index;
         }
+
+        position
+    }
+
+    // Byte position of the closest word end right of the cursor
+    fn word_end(&self) -> usize {
+        let mut position = self.dex;
+        let mut graphemes = self.chars[self.dex..].grapheme_indices(true).peekable();
+
+        while let Some((index, grapheme)) = graphemes.next_if(|(_, grapheme)| !is_word(grapheme)) {
+            position = self.dex + index + grapheme.len();
+        }
+
+        while let Some((index, grapheme)) = graphemes.next_if(|(_, grapheme)| is_word(grapheme)) {
+            position = self.dex + index + grapheme.len();
+        }
+
+        position
+    }
+
+    fn start(&mut self) -> Option<String> {
+        self.dex = 0;
+        None
+    }
+
+    fn end(&mut self) -> Option<String> {
+        self.dex = self.chars.len();
+        None
+    }
+
+    fn word_left(&mut self) -> Option<String> {
+        self.dex = self.word_start(is_word);
+        None
+    }
+
+    fn word_right(&mut self) -> Option<String> {
+        self.dex = self.word_end();
+        None
+    }
+
+    fn kill_end(&mut self) -> Option<String> {
+        self.chars.truncate(self.dex);
+        None
+    }
+
+    fn kill_start(&mut self) -> Option<String> {
+        self.chars.drain(..self.dex);
+        self.dex = 0;
+        None
+    }
+
+    fn kill_word(&mut self) -> Option<String> {
+        let start = self.word_start(is_not_whitespace);
+        self.chars.drain(start..self.dex);
+        self.dex = start;
+        None
+    }
+
+    // Byte length of the grapheme cluster before the cursor
+    fn previous_len(&self) -> usize {
+        self.chars[..self.dex].graphemes(true).next_back().map_or(0, str::len)
+    }
+
+    // Byte length of the grapheme cluster after the cursor
+    fn next_len(&self) -> usize {
+        self.chars[self.dex..].graphemes(true).next().map_or(0, str::len)
+    }
+
+    fn left(&mut self) -> Option<String> {
+        self.dex -= self.previous_len();
+        None
+    }
+
+    fn right(&mut self) -> Option<String> {
+        self.dex += self.next_len();
+        None
+    }
+
+    fn delete(&mut self) -> Option<String> {
+        let len = self.next_len();
+        self.chars.drain(self.dex..self.dex + len);
+        None
+    }
+
+    fn back(&mut self) -> Option<String> {
+        let len = self.previous_len();
+        self.dex -= len;
+        self.chars.drain(self.dex..self.dex + len);
         None
     }
 
     fn new_key(&mut self, chr: char) -> Option<String> {
+        // Ignore control characters, they would corrupt the rendered line
+        if chr.is_control() {
+            return None;
+        }
+
         self.chars.insert(self.dex, chr);
-        self.dex += 1;
+        self.dex += chr.len_utf8();
         None
     }
 
@@ -64,25 +320,260 @@ impl Prompt {
             return Ok(None);
         }
 
-        let message = FromStr::from_str(&self.chars).unwrap();
+        let message = mem::take(&mut self.chars);
 
-        self.chars.drain(..);
         self.dex = 0;
+        self.offset = 0;
+        self.history_index = None;
+        self.history.push(&message);
 
         Ok(Some(message))
     }
 
-    pub fn render<W: Write>(&mut self, w: &mut W, row: u16) -> Result<(), io::Error> {
-        if let Err(err) = write!(
+    pub fn render<W: Write + ?Sized>(&mut self, w: &mut W, row: u16, columns: u16) -> Result<(), io::Error> {
+        if let Some(search) = &self.search {
+            return self.render_search(w, search, row, columns);
+        }
+
+        let prompt_width = PROMPT.width();
+        let width = (columns as usize).saturating_sub(prompt_width).max(1);
+
+        // Scroll horizontally to keep the cursor visible
+        let cursor = self.chars[..self.dex].width();
+
+        if cursor < self.offset {
+            self.offset = cursor;
+        } else if cursor >= self.offset + width {
+            self.offset = cursor + 1 - width;
+        }
+
+        // Collect graphemes which fit entirely into the visible area
+        let mut visible = String::new();
+        let mut column = 0;
+
+        for grapheme in self.chars.graphemes(true) {
+            let grapheme_width = grapheme.width();
+            let end = column + grapheme_width;
+
+            if column >= self.offset && end <= self.offset + width {
+                visible.push_str(grapheme);
+            } else if column < self.offset && end > self.offset {
+                // Wide character cut off at the left edge
+                visible.push_str(&" ".repeat(end - self.offset));
+            }
+
+            column = end;
+        }
+
+        write!(
             w,
-            "{}{}:{}{}",
+            "{}{}{}{}{}",
             Goto(1, row),
             ClearLine,
-            self.chars,
-            Goto(self.dex as u16 + 2, row)
-        ) {
-            panic!("Failed to render command prompt: {:?}", err);
+            self.style.paint(PROMPT),
+            visible,
+            Goto((prompt_width + cursor - self.offset) as u16 + 1, row)
+        )
+    }
+
+    fn render_search<W: Write + ?Sized>(
+        &self,
+        w: &mut W,
+        search: &Search,
+        row: u16,
+        columns: u16,
+    ) -> Result<(), io::Error> {
+        let found = search.found.and_then(|index| self.history.get(index)).unwrap_or("");
+        let line = format!("(reverse-i-search)`{}': {}", search.query, found);
+
+        // Cut off everything not fitting into the row
+        let mut visible = String::new();
+        let mut width = 0;
+
+        for grapheme in line.graphemes(true) {
+            width += grapheme.width();
+
+            if width >= columns as usize {
+                break;
+            }
+
+            visible.push_str(grapheme);
         }
-        Ok(())
+
+        write!(w, "{}{}{}", Goto(1, row), ClearLine, visible)
+    }
+}
+
+#[cfg(test)]
+mod prompt {
+    use super::*;
+
+    fn type_text(prompt: &mut Prompt, text: &str) {
+        for chr in text.chars() {
+            prompt.handle_input(&Event::Key(Key::Char(chr))).unwrap();
+        }
+    }
+
+    fn press(prompt: &mut Prompt, key: Key) {
+        prompt.handle_input(&Event::Key(key)).unwrap();
+    }
+
+    fn render_to_string(prompt: &mut Prompt, columns: u16) -> String {
+        let mut output = Vec::new();
+        prompt.render(&mut output, 1, columns).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn edits_grapheme_clusters() {
+        let mut prompt = Prompt::default();
+
+        type_text(&mut prompt, "añe\u{301}😀");
+        press(&mut prompt, Key::Left);
+        press(&mut prompt, Key::Left);
+        press(&mut prompt, Key::Backspace);
+        type_text(&mut prompt, "日");
+        press(&mut prompt, Key::Right);
+        press(&mut prompt, Key::Delete);
+
+        assert_eq!(prompt.chars, "a日e\u{301}");
+
+        let message = prompt.handle_input(&Event::Key(Key::Char('\n'))).unwrap();
+        assert_eq!(message, Some(String::from("a日e\u{301}")));
+        assert_eq!(prompt.dex, 0);
+    }
+
+    #[test]
+    fn stays_in_bounds() {
+        let mut prompt = Prompt::default();
+
+        press(&mut prompt, Key::Backspace);
+        press(&mut prompt, Key::Left);
+        press(&mut prompt, Key::Delete);
+
+        type_text(&mut prompt, "ü");
+        press(&mut prompt, Key::Left);
+        press(&mut prompt, Key::Backspace);
+        press(&mut prompt, Key::Right);
+        press(&mut prompt, Key::Right);
+
+        assert_eq!(prompt.chars, "ü");
+        assert_eq!(prompt.dex, "ü".len());
+    }
+
+    #[test]
+    fn edits_like_readline() {
+        let mut prompt = Prompt::default();
+
+        type_text(&mut prompt, "Hello, dear Test");
+        press(&mut prompt, Key::Alt('b'));
+        press(&mut prompt, Key::Alt('b'));
+        assert_eq!(&prompt.chars[prompt.dex..], "dear Test");
+
+        press(&mut prompt, Key::Alt('f'));
+        press(&mut prompt, Key::Ctrl('k'));
+        assert_eq!(prompt.chars, "Hello, dear");
+
+        press(&mut prompt, Key::Ctrl('w'));
+        assert_eq!(prompt.chars, "Hello, ");
+
+        press(&mut prompt, Key::Ctrl('a'));
+        type_text(&mut prompt, ">");
+        press(&mut prompt, Key::Ctrl('e'));
+        type_text(&mut prompt, "you");
+        assert_eq!(prompt.chars, ">Hello, you");
+
+        press(&mut prompt, Key::Left);
+        press(&mut prompt, Key::Ctrl('u'));
+        assert_eq!(prompt.chars, "u");
+        assert_eq!(prompt.dex, 0);
+    }
+
+    #[test]
+    fn recalls_history() {
+        let mut prompt = Prompt::default();
+
+        for message in &["one", "two", "three"] {
+            type_text(&mut prompt, message);
+            press(&mut prompt, Key::Char('\n'));
+        }
+
+        type_text(&mut prompt, "draft");
+        press(&mut prompt, Key::Up);
+        assert_eq!(prompt.chars, "three");
+
+        press(&mut prompt, Key::Up);
+        press(&mut prompt, Key::Up);
+        press(&mut prompt, Key::Up);
+        assert_eq!(prompt.chars, "one");
+
+        press(&mut prompt, Key::Down);
+        assert_eq!(prompt.chars, "two");
+
+        press(&mut prompt, Key::Down);
+        press(&mut prompt, Key::Down);
+        assert_eq!(prompt.chars, "draft");
+
+        // Other channels have their own history
+        prompt.set_history(History::default());
+        press(&mut prompt, Key::Up);
+        assert_eq!(prompt.chars, "draft");
+    }
+
+    #[test]
+    fn searches_history() {
+        let mut prompt = Prompt::default();
+
+        for message in &["hello one", "something", "hello two"] {
+            type_text(&mut prompt, message);
+            press(&mut prompt, Key::Char('\n'));
+        }
+
+        press(&mut prompt, Key::Ctrl('r'));
+        type_text(&mut prompt, "hel");
+        assert!(render_to_string(&mut prompt, 80).ends_with("`hel': hello two"));
+
+        press(&mut prompt, Key::Ctrl('r'));
+        press(&mut prompt, Key::Char('\n'));
+        assert_eq!(prompt.chars, "hello one");
+
+        // Cancelling restores previous input
+        press(&mut prompt, Key::Ctrl('r'));
+        type_text(&mut prompt, "some");
+        press(&mut prompt, Key::Esc);
+        assert_eq!(prompt.chars, "hello one");
+
+        // Other keys accept the match and keep editing
+        press(&mut prompt, Key::Ctrl('r'));
+        type_text(&mut prompt, "some");
+        press(&mut prompt, Key::Ctrl('a'));
+        type_text(&mut prompt, ">");
+        assert_eq!(prompt.chars, ">something");
+    }
+
+    #[test]
+    fn places_cursor_by_display_width() {
+        let mut prompt = Prompt::default();
+        type_text(&mut prompt, "日本");
+
+        let output = render_to_string(&mut prompt, 80);
+        assert!(output.ends_with(&format!(":日本{}", Goto(6, 1))));
+    }
+
+    #[test]
+    fn scrolls_horizontally() {
+        let mut prompt = Prompt::default();
+        type_text(&mut prompt, "0123456789");
+
+        // Prompt sign and six characters fit, cursor stays in last column
+        let output = render_to_string(&mut prompt, 7);
+        assert!(output.ends_with(&format!(":56789{}", Goto(7, 1))));
+
+        for _ in 0..10 {
+            press(&mut prompt, Key::Left);
+        }
+
+        let output = render_to_string(&mut prompt, 7);
+        assert!(output.ends_with(&format!(":012345{}", Goto(2, 1))));
     }
 }
@@ -0,0 +1,350 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::str;
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Poll, Stream};
+use termion::event::{Event, Key};
+use unicode_width::UnicodeWidthChar;
+
+use super::backend::{Backend, TerminalEvent};
+
+const ESC: u8 = 0x1b;
+
+/// Grid of cells interpreting what the interface writes, like a terminal
+/// emulator understanding cursor movement and clearing. Colours are ignored.
+pub struct Screen {
+    columns: usize,
+    rows: usize,
+
+    // Graphemes of each cell, the cell right of a wide character is empty
+    cells: Vec<Vec<String>>,
+
+    // Zero-based column and row
+    cursor: (usize, usize),
+
+    // Incomplete escape sequence or character of the last write
+    pending: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(columns: u16, rows: u16) -> Self {
+        let mut screen = Self {
+            columns: 0,
+            rows: 0,
+            cells: Vec::new(),
+            cursor: (0, 0),
+            pending: Vec::new(),
+        };
+
+        screen.resize(columns, rows);
+        screen
+    }
+
+    /// Changes the size, keeping the content which still fits.
+    pub fn resize(&mut self, columns: u16, rows: u16) {
+        self.columns = columns as usize;
+        self.rows = rows as usize;
+
+        self.cells.resize(self.rows, Vec::new());
+
+        for row in &mut self.cells {
+            row.resize(self.columns, String::from(" "));
+        }
+
+        self.cursor = (
+            self.cursor.0.min(self.columns.saturating_sub(1)),
+            self.cursor.1.min(self.rows.saturating_sub(1)),
+        );
+    }
+
+    /// Returns the one-based position of the cursor, like `Goto` takes it.
+    pub fn cursor(&self) -> (u16, u16) {
+        (self.cursor.0 as u16 + 1, self.cursor.1 as u16 + 1)
+    }
+
+    /// Returns the text of every row without trailing whitespace.
+    pub fn lines(&self) -> Vec<String> {
+        self.cells
+            .iter()
+            .map(|row| row.concat().trim_end().to_string())
+            .collect()
+    }
+
+    /// Returns all rows as one string, to compare whole frames.
+    pub fn snapshot(&self) -> String {
+        self.lines().join("\n")
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        let mut bytes = {
+            let mut pending = std::mem::take(&mut self.pending);
+            pending.extend_from_slice(bytes);
+            pending
+        };
+
+        let mut index = 0;
+
+        while index < bytes.len() {
+            let consumed = match bytes[index] {
+                ESC => self.escape(&bytes[index..]),
+                b'\r' => {
+                    self.cursor.0 = 0;
+                    Some(1)
+                }
+                b'\n' => {
+                    self.cursor.1 = (self.cursor.1 + 1).min(self.rows.saturating_sub(1));
+                    Some(1)
+                }
+                _ => self.character(&bytes[index..]),
+            };
+
+            match consumed {
+                Some(count) => index += count,
+                None => {
+                    // Wait for the rest with the next write
+                    self.pending = bytes.split_off(index);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Handles an escape sequence, returns its length or None when incomplete
+    fn escape(&mut self, bytes: &[u8]) -> Option<usize> {
+        match bytes.get(1)? {
+            b'[' => {
+                let end = bytes[2..].iter().position(|byte| (0x40..=0x7e).contains(byte))? + 2;
+                let params = str::from_utf8(&bytes[2..end]).unwrap_or("");
+                self.control(params, bytes[end]);
+                Some(end + 1)
+            }
+            // Other sequences like saving the cursor do not change the content
+            _ => Some(2),
+        }
+    }
+
+    fn control(&mut self, params: &str, command: u8) {
+        let numbers: Vec<usize> = params
+            .split(';')
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+
+        let number = |index: usize, default: usize| {
+            numbers.get(index).cloned().filter(|number| *number > 0).unwrap_or(default)
+        };
+
+        match command {
+            // Goto
+            b'H' => {
+                self.cursor = (
+                    (number(1, 1) - 1).min(self.columns.saturating_sub(1)),
+                    (number(0, 1) - 1).min(self.rows.saturating_sub(1)),
+                );
+            }
+            // Clear line, whole or after the cursor
+            b'K' => {
+                let start = if numbers[0] == 2 { 0 } else { self.cursor.0 };
+
+                if let Some(row) = self.cells.get_mut(self.cursor.1) {
+                    for cell in row.iter_mut().skip(start) {
+                        *cell = String::from(" ");
+                    }
+                }
+            }
+            // Clear screen
+            b'J' if numbers[0] == 2 => {
+                for row in &mut self.cells {
+                    for cell in row.iter_mut() {
+                        *cell = String::from(" ");
+                    }
+                }
+            }
+            // Styles and terminal modes do not change the content
+            _ => {}
+        }
+    }
+
+    // Writes one character at the cursor, returns its length in bytes or
+    // None when it is incomplete
+    fn character(&mut self, bytes: &[u8]) -> Option<usize> {
+        let length = match bytes[0] {
+            0x00..=0x7f => 1,
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            _ => 4,
+        };
+
+        if bytes.len() < length {
+            return None;
+        }
+
+        let character = match str::from_utf8(&bytes[..length]) {
+            Ok(text) => text.chars().next().unwrap_or(' '),
+            Err(_) => return Some(1),
+        };
+
+        let width = character.width().unwrap_or(0);
+        let (column, row) = self.cursor;
+
+        // Combining characters belong to the cell before
+        if width == 0 {
+            if let Some(cell) = column.checked_sub(1).and_then(|column| self.cells[row].get_mut(column)) {
+                cell.push(character);
+            }
+
+            return Some(length);
+        }
+
+        // Characters beyond the right edge are lost
+        if column + width <= self.columns {
+            self.cells[row][column] = character.to_string();
+
+            if width == 2 {
+                self.cells[row][column + 1] = String::new();
+            }
+        }
+
+        self.cursor.0 = (column + width).min(self.columns);
+
+        Some(length)
+    }
+}
+
+// Everything written to the terminal, shared with the input side
+#[derive(Clone)]
+struct Output {
+    screen: Rc<RefCell<Screen>>,
+    written: Rc<RefCell<usize>>,
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.screen.borrow_mut().feed(buf);
+        *self.written.borrow_mut() += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Terminal living in memory, to run the interface in tests.
+pub struct VirtualTerminal {
+    events: UnboundedReceiver<TerminalEvent>,
+    output: Output,
+}
+
+/// Feeds input and size changes into a `VirtualTerminal` and shows what
+/// got drawn on it.
+#[derive(Clone)]
+pub struct VirtualInput {
+    output: Output,
+    tx: UnboundedSender<TerminalEvent>,
+}
+
+impl VirtualInput {
+    /// Returns the current content of the screen.
+    pub fn snapshot(&self) -> String {
+        self.output.screen.borrow().snapshot()
+    }
+
+    pub fn cursor(&self) -> (u16, u16) {
+        self.output.screen.borrow().cursor()
+    }
+
+    /// Returns the number of bytes written since the last call.
+    pub fn take_written(&self) -> usize {
+        self.output.written.replace(0)
+    }
+
+    pub fn resize(&self, columns: u16, rows: u16) {
+        self.output.screen.borrow_mut().resize(columns, rows);
+        let _ = self.tx.unbounded_send(TerminalEvent::Resize((columns, rows)));
+    }
+
+    pub fn send(&self, event: Event) {
+        let _ = self.tx.unbounded_send(TerminalEvent::Input(event));
+    }
+
+    pub fn send_keys(&self, text: &str) {
+        for key in text.chars() {
+            self.send(Event::Key(Key::Char(key)));
+        }
+    }
+}
+
+impl VirtualTerminal {
+    /// Creates a terminal of the given size.
+    pub fn new(columns: u16, rows: u16) -> (Self, VirtualInput) {
+        let (tx, events) = unbounded();
+
+        let output = Output {
+            screen: Rc::new(RefCell::new(Screen::new(columns, rows))),
+            written: Rc::new(RefCell::new(0)),
+        };
+
+        let input = VirtualInput { output: output.clone(), tx };
+        let _ = input.tx.unbounded_send(TerminalEvent::Resize((columns, rows)));
+
+        (Self { events, output }, input)
+    }
+}
+
+impl Backend for VirtualTerminal {
+    fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+}
+
+impl Stream for VirtualTerminal {
+    type Item = TerminalEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.events.poll()
+    }
+}
+
+#[cfg(test)]
+mod screen {
+    use super::*;
+
+    use termion::clear;
+    use termion::cursor::Goto;
+
+    #[test]
+    fn interprets_sequences() {
+        let mut screen = Screen::new(10, 3);
+
+        let output = format!("{}Hello{}{}Test{}",
+                             Goto(3, 2), Goto(1, 3), termion::style::Bold, termion::style::Reset);
+        screen.feed(output.as_bytes());
+
+        assert_eq!(screen.snapshot(), "\n  Hello\nTest");
+        assert_eq!(screen.cursor(), (5, 3));
+
+        screen.feed(format!("{}{}", Goto(1, 2), clear::CurrentLine).as_bytes());
+        assert_eq!(screen.snapshot(), "\n\nTest");
+
+        screen.feed(format!("{}", clear::All).as_bytes());
+        assert_eq!(screen.snapshot(), "\n\n");
+    }
+
+    #[test]
+    fn handles_split_writes_and_wide_characters() {
+        let mut screen = Screen::new(10, 1);
+        let output = format!("{}日本e\u{301}", Goto(1, 1));
+        let bytes = output.as_bytes();
+
+        // Split within the escape sequence and within a character
+        screen.feed(&bytes[..2]);
+        screen.feed(&bytes[2..8]);
+        screen.feed(&bytes[8..]);
+
+        assert_eq!(screen.snapshot(), "日本e\u{301}");
+        assert_eq!(screen.cursor(), (6, 1));
+    }
+}
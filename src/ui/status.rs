@@ -0,0 +1,138 @@
+use std::io::{self, Write};
+
+use termion::clear::CurrentLine as ClearLine;
+use termion::cursor::Goto;
+use unicode_width::UnicodeWidthStr;
+
+use super::theme::Theme;
+use super::wrap::truncate;
+
+/// State of the node shown in the status bar.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Status {
+    /// Short name of the current channel
+    pub channel: String,
+
+    /// Number of peers in the peer table
+    pub peers: usize,
+
+    /// Entries we hold and entries peers announced, unknown until any did
+    pub local_length: u64,
+    pub remote_length: Option<u64>,
+
+    /// Problem with the network, highlighted at the end of the bar
+    pub warning: Option<String>,
+}
+
+impl Status {
+    fn sync_text(&self) -> String {
+        match self.remote_length {
+            Some(remote) if self.local_length < remote => {
+                format!("syncing {}/{}", self.local_length, remote)
+            }
+            _ => format!("synced {}", self.local_length),
+        }
+    }
+
+    fn peers_text(&self) -> String {
+        match self.peers {
+            0 => String::from("no peers"),
+            1 => String::from("1 peer"),
+            peers => format!("{} peers", peers),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct StatusBar {
+    status: Status,
+    theme: Theme,
+}
+
+impl StatusBar {
+    pub fn new(theme: Theme) -> Self {
+        Self {
+            theme,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    // Returns the text of the bar and the warning aligned to the right,
+    // together filling exactly the given columns
+    fn text(&self, columns: usize) -> (String, String) {
+        let info = truncate(&format!(" {} | {} | {} ",
+                                     self.status.channel,
+                                     self.status.peers_text(),
+                                     self.status.sync_text()), columns);
+
+        let remaining = columns - info.width();
+
+        let warning = self.status.warning.as_ref()
+            .map_or_else(String::new, |warning| truncate(&format!(" ! {} ", warning), remaining));
+
+        let padding = " ".repeat(remaining - warning.width());
+
+        (info + &padding, warning)
+    }
+
+    pub fn render<W: Write + ?Sized>(&self, w: &mut W, row: u16, columns: u16) -> Result<(), io::Error> {
+        let (info, warning) = self.text(columns as usize);
+
+        write!(w, "{}{}{}{}",
+               Goto(1, row),
+               ClearLine,
+               self.theme.status.paint(&info),
+               self.theme.warning.paint(&warning))
+    }
+}
+
+#[cfg(test)]
+mod status {
+    use super::*;
+
+    fn status() -> Status {
+        Status {
+            channel: String::from("chat://abcd12"),
+            peers: 2,
+            local_length: 3,
+            remote_length: Some(5),
+            warning: None,
+        }
+    }
+
+    #[test]
+    fn shows_state() {
+        let mut bar = StatusBar::default();
+        bar.set_status(status());
+
+        let (text, warning) = bar.text(50);
+        assert_eq!(text.trim_end(), " chat://abcd12 | 2 peers | syncing 3/5");
+        assert_eq!(text.width(), 50);
+        assert_eq!(warning, "");
+
+        bar.set_status(Status {
+            remote_length: Some(3),
+            warning: Some(String::from("Discovery stopped")),
+            ..status()
+        });
+
+        let (text, warning) = bar.text(60);
+        assert!(text.starts_with(" chat://abcd12 | 2 peers | synced 3 "));
+        assert_eq!(warning, " ! Discovery stopped ");
+        assert_eq!(text.width() + warning.width(), 60);
+    }
+
+    #[test]
+    fn fits_into_columns() {
+        let mut bar = StatusBar::default();
+        bar.set_status(status());
+
+        let (text, warning) = bar.text(10);
+        assert_eq!(text, " chat://ab");
+        assert_eq!(warning, "");
+    }
+}
@@ -1,91 +1,165 @@
-use std::io::{self, Stdout};
-use std::thread;
-use std::time::Duration;
+use std::io::{self, Stdout, Write};
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::{Async, Poll, Sink, Stream};
-use termion::event::Event;
-use termion::input::TermRead;
+use futures::{Async, Future, Poll, Stream};
+use termion::event::{parse_event, Event, Key};
+use termion::input::MouseTerminal;
 use termion::raw::{IntoRawMode, RawTerminal};
-use termion::screen::AlternateScreen;
+use termion::screen::{AlternateScreen, ToMainScreen};
 use termion::terminal_size;
+use tokio_core::reactor::Handle;
+use tokio_signal::unix::Signal;
 
-type RenderTarget = AlternateScreen<RawTerminal<Stdout>>;
+use super::backend::{Backend, TerminalEvent};
+
+type RenderTarget = MouseTerminal<AlternateScreen<RawTerminal<Stdout>>>;
+
+// Milliseconds to wait for input before checking if the thread should stop
+const INPUT_POLL_TIMEOUT: i32 = 100;
+
+const ESCAPE: u8 = 0x1b;
+
+// Longest incomplete sequence held back until the next read
+const MAX_SEQUENCE_LENGTH: usize = 32;
 
 pub struct Terminal {
+    // Size changes, starting with the size when the terminal was created
     size: UnboundedReceiver<(u16, u16)>,
+
     stdin: UnboundedReceiver<Event>,
     stdout: RenderTarget,
+
+    // Tells the input thread to stop when the terminal gets dropped
+    stop: Arc<AtomicBool>,
+    input_thread: Option<JoinHandle<()>>,
 }
 
 impl Terminal {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new(handle: &Handle) -> Result<Self, io::Error> {
         let (stdin_tx, stdin_rx) = unbounded();
         let (size_tx, size_rx) = unbounded();
 
-        let stdout = AlternateScreen::from(
-            io::stdout()
-                .into_raw_mode()
-                .expect("Failed to put terminal into raw mode"),
-        );
+        let stdout = MouseTerminal::from(AlternateScreen::from(io::stdout().into_raw_mode()?));
+        let stop = Arc::new(AtomicBool::new(false));
 
-        let term = Terminal {
-            stdin: stdin_rx,
+        Terminal::start_size_listening(handle, size_tx)?;
+        let input_thread = Terminal::start_stdin_listening(stdin_tx, stop.clone());
+        Terminal::restore_on_panic();
+
+        Ok(Terminal {
             size: size_rx,
+            stdin: stdin_rx,
             stdout,
-        };
+            stop,
+            input_thread: Some(input_thread),
+        })
+    }
+
+    // Leave the alternate screen before the panic message gets printed,
+    // raw mode itself is restored when the terminal gets dropped
+    fn restore_on_panic() {
+        let default_hook = panic::take_hook();
 
-        Terminal::start_stdin_listening(stdin_tx);
-        Terminal::start_size_listening(size_tx);
+        panic::set_hook(Box::new(move |info| {
+            let mut stdout = io::stdout();
+            let _ = write!(stdout, "{}\r\n", ToMainScreen);
+            let _ = stdout.flush();
 
-        Ok(term)
+            default_hook(info);
+        }));
     }
 
-    fn start_size_listening(tx: UnboundedSender<(u16, u16)>) {
-        let mut tx = tx;
+    // Reports the current size and then a new one on every SIGWINCH, the
+    // listener ends together with the receiver
+    fn start_size_listening(
+        handle: &Handle,
+        tx: UnboundedSender<(u16, u16)>,
+    ) -> Result<(), io::Error> {
+        tx.unbounded_send(terminal_size()?).map_err(|_| io::ErrorKind::BrokenPipe)?;
+
+        let listener = Signal::with_handle(libc::SIGWINCH, handle.new_tokio_handle())
+            .flatten_stream()
+            .map_err(|_| ())
+            .for_each(move |_| {
+                let size = terminal_size().map_err(|_| ())?;
+                tx.unbounded_send(size).map_err(|_| ())
+            });
+
+        handle.spawn(listener);
+
+        Ok(())
+    }
+
+    // Reads input until the stop flag is set, the receiver went away or
+    // stdin is gone, which closes the stream and lets the UI shut down
+    fn start_stdin_listening(tx: UnboundedSender<Event>, stop: Arc<AtomicBool>) -> JoinHandle<()> {
         thread::spawn(move || {
-            let mut current_size = (0, 0);
-            loop {
-                match terminal_size() {
-                    Ok(new_size) => {
-                        if new_size != current_size {
-                            current_size = new_size;
-                            let _ = tx.start_send(current_size).unwrap();
-                            let _ = tx.poll_complete().unwrap();
-                        }
-                    }
-                    Err(e) => {
-                        panic!("failed to get terminal size: {}", e);
+            let fd = io::stdin().as_raw_fd();
+            let mut buffer = [0u8; 1024];
+            let mut parser = InputParser::default();
+
+            while !stop.load(Ordering::Relaxed) {
+                let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+
+                // Wait with a timeout to notice when we should stop
+                let ready = unsafe { libc::poll(&mut pollfd, 1, INPUT_POLL_TIMEOUT) };
+
+                if ready < 0 {
+                    // Signals like SIGWINCH interrupt waiting
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
                     }
+
+                    return;
                 }
-                thread::sleep(Duration::from_millis(10));
-            }
-        });
-    }
 
-    fn start_stdin_listening(tx: UnboundedSender<Event>) {
-        let mut tx = tx;
-        thread::spawn(move || {
-            for event_res in io::stdin().events() {
-                match event_res {
-                    Ok(event) => {
-                        let _ = tx.start_send(event).unwrap();
-                        let _ = tx.poll_complete().unwrap();
+                // Input which stays incomplete was meant as it is, like a
+                // single Esc key press
+                let events = if ready == 0 {
+                    parser.flush()
+                } else {
+                    // Read directly from the file descriptor, a buffered
+                    // reader could hold back input poll does not know about
+                    let count = unsafe {
+                        libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len())
+                    };
+
+                    if count <= 0 {
+                        return;
+                    }
+
+                    parser.parse(&buffer[..count as usize])
+                };
+
+                for event in events {
+                    if tx.unbounded_send(event).is_err() {
+                        return;
                     }
-                    Err(e) => panic!("{}", e),
                 }
             }
-        });
+        })
     }
+}
 
-    pub fn stdout(&mut self) -> &mut RenderTarget {
-        &mut self.stdout
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(input_thread) = self.input_thread.take() {
+            let _ = input_thread.join();
+        }
     }
 }
 
-pub enum TerminalEvent {
-    Resize((u16, u16)),
-    Input(Event),
+impl Backend for Terminal {
+    fn output(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
 }
 
 impl Stream for Terminal {
@@ -120,3 +194,108 @@ impl Stream for Terminal {
         Ok(Async::NotReady)
     }
 }
+
+/// Turns bytes read from stdin into events. Sequences cut off at the end of
+/// a read are kept until the next one completes them.
+#[derive(Default)]
+struct InputParser {
+    pending: Vec<u8>,
+}
+
+impl InputParser {
+    fn parse(&mut self, bytes: &[u8]) -> Vec<Event> {
+        self.pending.extend_from_slice(bytes);
+        self.take_events(false)
+    }
+
+    // Returns the events of everything held back, taking incomplete
+    // sequences as they are
+    fn flush(&mut self) -> Vec<Event> {
+        self.take_events(true)
+    }
+
+    fn take_events(&mut self, is_complete: bool) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut start = 0;
+
+        while start < self.pending.len() {
+            let mut bytes = InputBytes { bytes: &self.pending, position: start + 1, ran_out: false };
+            let result = parse_event(self.pending[start], &mut bytes);
+
+            if bytes.ran_out && !is_complete && self.pending.len() - start < MAX_SEQUENCE_LENGTH {
+                break;
+            }
+
+            // Escape without a complete sequence after it was a key press
+            if bytes.ran_out && self.pending[start] == ESCAPE {
+                events.push(Event::Key(Key::Esc));
+                start += 1;
+                continue;
+            }
+
+            // Unknown escape sequences are ignored
+            if let Ok(event) = result {
+                events.push(event);
+            }
+
+            start = bytes.position;
+        }
+
+        self.pending.drain(..start);
+        events
+    }
+}
+
+// Bytes of one read, remembering whether parsing needed more of them
+struct InputBytes<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    ran_out: bool,
+}
+
+impl Iterator for InputBytes<'_> {
+    type Item = Result<u8, io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.bytes.get(self.position) {
+            Some(byte) => {
+                self.position += 1;
+                Some(Ok(*byte))
+            }
+            None => {
+                self.ran_out = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod terminal {
+    use super::*;
+
+    #[test]
+    fn completes_split_sequences() {
+        let mut parser = InputParser::default();
+
+        assert_eq!(parser.parse(b"a\x1b["), vec![Event::Key(Key::Char('a'))]);
+        assert_eq!(parser.parse(b"Ab"), vec![Event::Key(Key::Up), Event::Key(Key::Char('b'))]);
+
+        // Characters can be split as well
+        assert_eq!(parser.parse(&"ü".as_bytes()[..1]), vec![]);
+        assert_eq!(parser.parse(&"ü".as_bytes()[1..]), vec![Event::Key(Key::Char('ü'))]);
+    }
+
+    #[test]
+    fn flushes_single_escape() {
+        let mut parser = InputParser::default();
+
+        assert_eq!(parser.parse(b"\x1b"), vec![]);
+        assert_eq!(parser.flush(), vec![Event::Key(Key::Esc)]);
+        assert_eq!(parser.flush(), vec![]);
+
+        // Sequences which never complete are taken literally
+        assert_eq!(parser.parse(b"\x1b["), vec![]);
+        assert_eq!(parser.flush(), vec![Event::Key(Key::Esc), Event::Key(Key::Char('['))]);
+    }
+}
@@ -0,0 +1,175 @@
+use std::env;
+
+use termion::color::{self, Fg};
+use termion::style::{Bold, Invert, Reset};
+
+pub const THEME_NAMES: &[&str] = &["dark", "light", "mono"];
+
+/// Text style made of termion escape codes, empty styles print plain text.
+#[derive(Clone, Default)]
+pub struct Style {
+    codes: String,
+}
+
+impl Style {
+    fn new<C: color::Color>(color: C) -> Self {
+        Self {
+            codes: Fg(color).to_string(),
+        }
+    }
+
+    fn bold(mut self) -> Self {
+        self.codes.push_str(Bold.as_ref());
+        self
+    }
+
+    fn inverted(mut self) -> Self {
+        self.codes.push_str(Invert.as_ref());
+        self
+    }
+
+    /// Wraps text in the escape codes of this style.
+    pub fn paint(&self, text: &str) -> String {
+        if self.codes.is_empty() || text.is_empty() {
+            return text.to_string();
+        }
+
+        format!("{}{}{}", self.codes, text, Reset)
+    }
+}
+
+/// Colours used by the interface. The default theme is monochrome.
+#[derive(Clone, Default)]
+pub struct Theme {
+    pub timestamp: Style,
+    pub info: Style,
+    pub own: Style,
+    pub mention: Style,
+    pub prompt: Style,
+    pub status: Style,
+    pub warning: Style,
+
+    // Colours to pick from for other authors
+    authors: Vec<Style>,
+}
+
+impl Theme {
+    pub fn monochrome() -> Self {
+        Self::default()
+    }
+
+    pub fn dark() -> Self {
+        Self {
+            timestamp: Style::new(color::LightBlack),
+            info: Style::new(color::LightBlack),
+            own: Style::new(color::LightWhite).bold(),
+            mention: Style::new(color::LightYellow).bold(),
+            prompt: Style::new(color::LightGreen),
+            status: Style::new(color::LightBlack).inverted(),
+            warning: Style::new(color::LightRed).inverted().bold(),
+            authors: vec![
+                Style::new(color::LightRed),
+                Style::new(color::LightGreen),
+                Style::new(color::LightBlue),
+                Style::new(color::LightMagenta),
+                Style::new(color::LightCyan),
+                Style::new(color::Red),
+                Style::new(color::Green),
+                Style::new(color::Magenta),
+                Style::new(color::Cyan),
+            ],
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            timestamp: Style::new(color::LightBlack),
+            info: Style::new(color::LightBlack),
+            own: Style::new(color::Black).bold(),
+            mention: Style::new(color::Red).bold(),
+            prompt: Style::new(color::Blue),
+            status: Style::new(color::LightBlack).inverted(),
+            warning: Style::new(color::Red).inverted().bold(),
+            authors: vec![
+                Style::new(color::Red),
+                Style::new(color::Green),
+                Style::new(color::Blue),
+                Style::new(color::Magenta),
+                Style::new(color::Cyan),
+                Style::new(color::Yellow),
+            ],
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<Self> {
+        match name {
+            "dark" => Some(Self::dark()),
+            "light" => Some(Self::light()),
+            "mono" => Some(Self::monochrome()),
+            _ => None,
+        }
+    }
+
+    /// Returns the theme with the given name (dark by default), falls back
+    /// to monochrome when NO_COLOR is set or the terminal has no colours.
+    pub fn from_env(name: Option<&str>) -> Result<Self, String> {
+        let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        Self::detect(name, no_color, env::var("TERM").ok().as_deref())
+    }
+
+    fn detect(name: Option<&str>, no_color: bool, term: Option<&str>) -> Result<Self, String> {
+        let name = name.unwrap_or("dark");
+
+        let theme = Self::by_name(name).ok_or_else(|| {
+            format!("Unknown theme {}, available are {}", name, THEME_NAMES.join(", "))
+        })?;
+
+        match term {
+            _ if no_color => Ok(Self::monochrome()),
+            None | Some("") | Some("dumb") => Ok(Self::monochrome()),
+            Some(_) => Ok(theme),
+        }
+    }
+
+    /// Returns the colour of an author, always the same for the same key.
+    pub fn author(&self, public_key: &[u8]) -> Style {
+        if self.authors.is_empty() {
+            return Style::default();
+        }
+
+        let index = public_key
+            .iter()
+            .fold(0usize, |sum, byte| sum.wrapping_mul(31).wrapping_add(*byte as usize));
+
+        self.authors[index % self.authors.len()].clone()
+    }
+}
+
+#[cfg(test)]
+mod theme {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_monochrome() {
+        let theme = Theme::detect(None, false, Some("xterm-256color")).unwrap();
+        assert_ne!(theme.own.paint("Test"), "Test");
+
+        let theme = Theme::detect(Some("light"), true, Some("xterm-256color")).unwrap();
+        assert_eq!(theme.own.paint("Test"), "Test");
+
+        let theme = Theme::detect(None, false, Some("dumb")).unwrap();
+        assert_eq!(theme.own.paint("Test"), "Test");
+
+        assert!(Theme::detect(Some("pink"), false, None).is_err());
+    }
+
+    #[test]
+    fn author_colours_are_deterministic() {
+        let theme = Theme::dark();
+        let key = [1, 2, 3, 4];
+
+        assert_eq!(theme.author(&key).paint("adz"), theme.author(&key).paint("adz"));
+        assert_ne!(theme.author(&key).paint("adz"), theme.author(&[4, 3, 2, 1]).paint("adz"));
+        assert_eq!(Theme::monochrome().author(&key).paint("adz"), "adz");
+    }
+}
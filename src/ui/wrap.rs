@@ -0,0 +1,136 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Splits text into lines which are at most `width` terminal columns wide.
+///
+/// Lines are broken at whitespace when possible, words longer than a line are
+/// split between grapheme clusters. Explicit line breaks are kept.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+
+    for paragraph in text.split('\n') {
+        let mut line = Line::default();
+
+        for word in paragraph.trim_end_matches('\r').split_word_bounds() {
+            let word_width = word.width();
+
+            if word.trim().is_empty() {
+                // Whitespace at the beginning of a wrapped line is dropped
+                if line.width + word_width <= width {
+                    if !(line.is_wrapped && line.text.is_empty()) {
+                        line.push(word, word_width);
+                    }
+                } else {
+                    line.wrap(&mut lines);
+                }
+            } else if line.width + word_width <= width {
+                line.push(word, word_width);
+            } else if word_width <= width {
+                line.wrap(&mut lines);
+                line.push(word, word_width);
+            } else {
+                // Word does not fit into any line, split it where needed
+                for grapheme in word.graphemes(true) {
+                    let grapheme_width = grapheme.width();
+
+                    if line.width + grapheme_width > width && !line.text.is_empty() {
+                        line.wrap(&mut lines);
+                    }
+
+                    line.push(grapheme, grapheme_width);
+                }
+            }
+        }
+
+        lines.push(line.text.trim_end().to_string());
+    }
+
+    lines
+}
+
+#[derive(Default)]
+struct Line {
+    text: String,
+    width: usize,
+    is_wrapped: bool,
+}
+
+impl Line {
+    fn push(&mut self, text: &str, width: usize) {
+        self.text.push_str(text);
+        self.width += width;
+    }
+
+    fn wrap(&mut self, lines: &mut Vec<String>) {
+        lines.push(self.text.trim_end().to_string());
+
+        self.text.clear();
+        self.width = 0;
+        self.is_wrapped = true;
+    }
+}
+
+/// Cuts the text after the last grapheme fitting into the columns.
+pub fn truncate(text: &str, columns: usize) -> String {
+    let mut width = 0;
+
+    text.graphemes(true)
+        .take_while(|grapheme| {
+            width += grapheme.width();
+            width <= columns
+        })
+        .collect()
+}
+
+/// Removes control characters except line breaks, which would otherwise
+/// reach the terminal as escape sequences. Tabs become spaces.
+pub fn strip_control(text: &str) -> String {
+    text.chars()
+        .filter_map(|chr| match chr {
+            '\n' => Some(chr),
+            '\t' => Some(' '),
+            _ if chr.is_control() => None,
+            _ => Some(chr),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod wrap {
+    use super::*;
+
+    #[test]
+    fn breaks_at_whitespace() {
+        assert_eq!(wrap("Hello, Test! 1, 2, 3", 12), vec!["Hello, Test!", "1, 2, 3"]);
+        assert_eq!(wrap("Hello", 12), vec!["Hello"]);
+        assert_eq!(wrap("", 12), vec![""]);
+    }
+
+    #[test]
+    fn keeps_line_breaks() {
+        assert_eq!(wrap("One\ntwo\r\n\nthree", 10), vec!["One", "two", "", "three"]);
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(strip_control("\x1b[2JHello\tTest\r\n\x07"), "[2JHello Test\n");
+    }
+
+    #[test]
+    fn splits_long_words() {
+        assert_eq!(wrap("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn respects_display_width() {
+        // Wide characters take two columns each
+        assert_eq!(wrap("日本語のテキスト", 6), vec!["日本語", "のテキ", "スト"]);
+
+        // Combined characters are never split
+        assert_eq!(wrap("e\u{301}e\u{301}e\u{301}", 2), vec!["e\u{301}e\u{301}", "e\u{301}"]);
+
+        // Non-ASCII text does not panic at char boundaries
+        assert_eq!(wrap("Grüße aus Köln", 6), vec!["Grüße", "aus", "Köln"]);
+    }
+}
@@ -0,0 +1,53 @@
+//! Variable-length encoding of unsigned integers, 7 bits per byte
+
+/// Appends the value, small numbers take a single byte.
+pub fn write(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+
+    bytes.push(value as u8);
+}
+
+/// Reads a value from the front of the bytes and advances them, returns
+/// None when the bytes end within the value.
+pub fn read(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+
+    for (index, byte) in bytes.iter().enumerate() {
+        if index >= 10 {
+            break;
+        }
+
+        value |= u64::from(byte & 0x7f) << (7 * index);
+
+        if byte & 0x80 == 0 {
+            *bytes = &bytes[index + 1..];
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod varint {
+    #[test]
+    fn encode_decode() {
+        let mut bytes = Vec::new();
+        super::write(&mut bytes, 5);
+        super::write(&mut bytes, 300);
+        super::write(&mut bytes, u64::MAX);
+
+        assert_eq!(bytes.len(), 1 + 2 + 10);
+
+        let mut slice = &bytes[..];
+        assert_eq!(super::read(&mut slice), Some(5));
+        assert_eq!(super::read(&mut slice), Some(300));
+        assert_eq!(super::read(&mut slice), Some(u64::MAX));
+        assert!(slice.is_empty());
+
+        assert_eq!(super::read(&mut &[0x80, 0x80][..]), None);
+    }
+}